@@ -17,6 +17,16 @@ pub struct Info {
     pub port_mapping_list: Vec<(bool, SocketAddr, String)>,
     pub in_ips: Vec<(u32, u32, Ipv4Addr)>,
     pub out_ips: Vec<(u32, u32)>,
+    pub tcp_proxy: Option<TcpProxyItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TcpProxyItem {
+    pub active_connections: u64,
+    pub accepted: u64,
+    pub closed: u64,
+    pub up: u64,
+    pub down: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -1,6 +1,8 @@
 use std::io;
 use vnt::core::Vnt;
 
+#[cfg(feature = "ip_proxy")]
+use crate::command::entity::TcpProxyItem;
 use crate::command::entity::{DeviceItem, Info, RouteItem};
 use crate::console_out;
 
@@ -191,6 +193,16 @@ pub fn command_info(vnt: &Vnt) -> Info {
     let port_mapping_list = vec![];
     let in_ips = vnt.config().in_ips.clone();
     let out_ips = vnt.config().out_ips.clone();
+    #[cfg(feature = "ip_proxy")]
+    let tcp_proxy = vnt.proxy_stats().map(|stats| TcpProxyItem {
+        active_connections: stats.active_connections,
+        accepted: stats.accepted,
+        closed: stats.closed,
+        up: stats.upload_bytes,
+        down: stats.download_bytes,
+    });
+    #[cfg(not(feature = "ip_proxy"))]
+    let tcp_proxy = None;
     Info {
         name,
         virtual_ip,
@@ -207,5 +219,6 @@ pub fn command_info(vnt: &Vnt) -> Info {
         port_mapping_list,
         in_ips,
         out_ips,
+        tcp_proxy,
     }
 }
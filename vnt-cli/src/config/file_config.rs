@@ -1,15 +1,40 @@
 use anyhow::anyhow;
 use std::net::Ipv4Addr;
+#[cfg(feature = "ip_proxy")]
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+#[cfg(feature = "ip_proxy")]
+use std::path::PathBuf;
 use std::str::FromStr;
+#[cfg(feature = "ip_proxy")]
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
-use crate::config::get_device_id;
+use crate::config::{
+    env_config, resolve_device_id, validate_config, ConfigFormat, ConfigReport, DeviceIdSource,
+    DeviceIdStrategy,
+};
 use vnt::channel::punch::PunchModel;
 use vnt::channel::UseChannelType;
 use vnt::cipher::CipherModel;
-use vnt::compression::Compressor;
-use vnt::core::Config;
+use vnt::compression::{Compressor, DEFAULT_COMPRESS_THRESHOLD};
+use vnt::core::{Config, DEFAULT_SERVER_ADDRESS, DEFAULT_STUN_SERVERS};
+#[cfg(feature = "ip_proxy")]
+use vnt::ip_proxy::dns_proxy;
+#[cfg(feature = "ip_proxy")]
+use vnt::ip_proxy::nat64::Nat64;
+#[cfg(feature = "ip_proxy")]
+use vnt::ip_proxy::policy::{PolicyRule, ProxyPolicy};
+#[cfg(feature = "ip_proxy")]
+use vnt::ip_proxy::port_filter::PortFilter;
+#[cfg(feature = "ip_proxy")]
+use vnt::ip_proxy::proxy_protocol::ProxyProtocolRule;
+#[cfg(feature = "ip_proxy")]
+use vnt::ip_proxy::socks5::{Socks5Listen, UpstreamProxy};
+#[cfg(feature = "tls")]
+use vnt::ip_proxy::tls::{TlsOrigin, TlsRule};
+#[cfg(feature = "ip_proxy")]
+use vnt::ip_proxy::ProxyConfig;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(default)]
@@ -18,6 +43,8 @@ pub struct FileConfig {
     pub tap: bool,
     pub token: String,
     pub device_id: String,
+    pub device_id_dir: Option<String>,
+    pub device_id_seed: Option<String>,
     pub name: String,
     pub server_address: String,
     pub stun_server: Vec<String>,
@@ -31,6 +58,90 @@ pub struct FileConfig {
     pub use_channel: String,
     #[cfg(feature = "ip_proxy")]
     pub no_proxy: bool,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_bind_addr: Option<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_port_filter: Option<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_policy: Vec<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_worker_threads: usize,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_listen_backlog: u32,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_reuse_addr: bool,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_buf_len: usize,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_connect_timeout: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_mss: u16,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_require_src_port: bool,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_nat64: Vec<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_tos_passthrough: bool,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_unix_targets: Vec<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_protocol: Vec<String>,
+    #[cfg(feature = "tls")]
+    pub proxy_tls: Vec<String>,
+    #[cfg(feature = "tls")]
+    pub proxy_tls_ca: Option<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_access_log: Option<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_access_log_max_size: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_access_log_max_files: usize,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_egress_bind: Option<Ipv4Addr>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_egress_device: Option<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_fwmark: Option<u32>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_nodelay: bool,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_nodelay_ports: Vec<u16>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_keepalive_idle: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_keepalive_interval: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_keepalive_count: u32,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_idle_timeout: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_write_timeout: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_nat_ttl: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_nat_max: usize,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_drain_timeout: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_max_connections: usize,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_max_connections_per_dest: usize,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_accept_rate: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_upstream: Option<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_rate_limit: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_rate_limit_per_conn: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_socks5: Option<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_udp_idle_timeout: u64,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_dns_upstream: Option<String>,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_dns_domains: Vec<String>,
     pub server_encrypt: bool,
     pub parallel: usize,
     pub cipher_model: Option<String>,
@@ -45,6 +156,8 @@ pub struct FileConfig {
     #[cfg(feature = "port_mapping")]
     pub mapping: Vec<String>,
     pub compressor: Option<String>,
+    pub compress_threshold: usize,
+    pub fallback_servers: Vec<String>,
 }
 
 impl Default for FileConfig {
@@ -53,14 +166,12 @@ impl Default for FileConfig {
             #[cfg(target_os = "windows")]
             tap: false,
             token: "".to_string(),
-            device_id: get_device_id(),
+            device_id: "".to_string(),
+            device_id_dir: None,
+            device_id_seed: None,
             name: os_info::get().to_string(),
-            server_address: "nat1.wherewego.top:29872".to_string(),
-            stun_server: vec![
-                "stun1.l.google.com:19302".to_string(),
-                "stun2.l.google.com:19302".to_string(),
-                "stun.miwifi.com:3478".to_string(),
-            ],
+            server_address: DEFAULT_SERVER_ADDRESS.to_string(),
+            stun_server: DEFAULT_STUN_SERVERS.iter().map(|s| s.to_string()).collect(),
             dns: vec![],
             in_ips: vec![],
             out_ips: vec![],
@@ -71,6 +182,90 @@ impl Default for FileConfig {
             use_channel: "all".to_string(),
             #[cfg(feature = "ip_proxy")]
             no_proxy: false,
+            #[cfg(feature = "ip_proxy")]
+            proxy_bind_addr: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_port_filter: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_policy: vec![],
+            #[cfg(feature = "ip_proxy")]
+            proxy_worker_threads: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_listen_backlog: vnt::ip_proxy::tcp_proxy::DEFAULT_LISTEN_BACKLOG,
+            #[cfg(feature = "ip_proxy")]
+            proxy_reuse_addr: cfg!(not(target_os = "windows")),
+            #[cfg(feature = "ip_proxy")]
+            proxy_buf_len: vnt::ip_proxy::tcp_proxy::DEFAULT_BUF_LEN,
+            #[cfg(feature = "ip_proxy")]
+            proxy_connect_timeout: 5,
+            #[cfg(feature = "ip_proxy")]
+            proxy_mss: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_require_src_port: false,
+            #[cfg(feature = "ip_proxy")]
+            proxy_nat64: vec![],
+            #[cfg(feature = "ip_proxy")]
+            proxy_tos_passthrough: false,
+            #[cfg(feature = "ip_proxy")]
+            proxy_unix_targets: vec![],
+            #[cfg(feature = "ip_proxy")]
+            proxy_protocol: vec![],
+            #[cfg(feature = "tls")]
+            proxy_tls: vec![],
+            #[cfg(feature = "tls")]
+            proxy_tls_ca: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_access_log: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_access_log_max_size: vnt::ip_proxy::access_log::DEFAULT_MAX_SIZE,
+            #[cfg(feature = "ip_proxy")]
+            proxy_access_log_max_files: vnt::ip_proxy::access_log::DEFAULT_MAX_FILES,
+            #[cfg(feature = "ip_proxy")]
+            proxy_egress_bind: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_egress_device: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_fwmark: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_nodelay: false,
+            #[cfg(feature = "ip_proxy")]
+            proxy_nodelay_ports: vec![],
+            #[cfg(feature = "ip_proxy")]
+            proxy_keepalive_idle: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_keepalive_interval: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_keepalive_count: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_idle_timeout: 300,
+            #[cfg(feature = "ip_proxy")]
+            proxy_write_timeout: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_nat_ttl: 300,
+            #[cfg(feature = "ip_proxy")]
+            proxy_nat_max: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_drain_timeout: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_max_connections: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_max_connections_per_dest: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_accept_rate: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_upstream: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_rate_limit: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_rate_limit_per_conn: 0,
+            #[cfg(feature = "ip_proxy")]
+            proxy_socks5: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_udp_idle_timeout: 600,
+            #[cfg(feature = "ip_proxy")]
+            proxy_dns_upstream: None,
+            #[cfg(feature = "ip_proxy")]
+            proxy_dns_domains: vec![],
             server_encrypt: false,
             parallel: 1,
             cipher_model: None,
@@ -85,19 +280,243 @@ impl Default for FileConfig {
             #[cfg(feature = "port_mapping")]
             mapping: vec![],
             compressor: None,
+            compress_threshold: DEFAULT_COMPRESS_THRESHOLD,
+            fallback_servers: vec![],
         }
     }
 }
 
-pub fn read_config(file_path: &str) -> anyhow::Result<(Config, bool)> {
+/// 配置文件里有不认识的字段时报错，并给出最接近的字段名
+fn check_unknown_keys(conf: &str, format: ConfigFormat) -> anyhow::Result<()> {
+    // 格式错误交给后面的反序列化报告
+    let (keys, separator) = match format {
+        ConfigFormat::Yaml => match serde_yaml::from_str::<serde_yaml::Value>(conf) {
+            Ok(serde_yaml::Value::Mapping(mapping)) => {
+                let mut keys = Vec::with_capacity(mapping.len());
+                for key in mapping.keys() {
+                    match key.as_str() {
+                        Some(key) => keys.push(key.to_string()),
+                        None => return Err(anyhow!("key {:?} is not a string", key)),
+                    }
+                }
+                (keys, ':')
+            }
+            _ => return Ok(()),
+        },
+        ConfigFormat::Toml => match toml::from_str::<toml::Table>(conf) {
+            Ok(table) => (table.keys().cloned().collect(), '='),
+            Err(_) => return Ok(()),
+        },
+    };
+    let known_keys = known_keys();
+    for key in &keys {
+        let key = key.as_str();
+        if known_keys.iter().any(|k| k == key) {
+            continue;
+        }
+        let line = conf
+            .lines()
+            .position(|line| {
+                line.trim_start()
+                    .strip_prefix(key)
+                    .is_some_and(|v| v.trim_start().starts_with(separator))
+            })
+            .map(|i| format!(" at line {}", i + 1))
+            .unwrap_or_default();
+        let suggestion = known_keys
+            .iter()
+            .map(|k| (edit_distance(key, k), k))
+            .filter(|(distance, k)| *distance <= 2.max(k.len() / 3))
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, k)| format!(", did you mean '{}'?", k))
+            .unwrap_or_default();
+        return Err(anyhow!(
+            "unknown key '{}'{}{}\naccepted keys: {}",
+            key,
+            line,
+            suggestion,
+            known_keys.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// 当前编译选项下FileConfig支持的所有字段
+fn known_keys() -> Vec<String> {
+    match serde_yaml::to_value(FileConfig::default()) {
+        Ok(serde_yaml::Value::Mapping(mapping)) => mapping
+            .keys()
+            .filter_map(|k| k.as_str().map(|k| k.to_string()))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// format为None时按扩展名判断格式
+pub fn read_config(
+    file_path: &str,
+    format: Option<ConfigFormat>,
+) -> anyhow::Result<(Config, bool)> {
     let conf = std::fs::read_to_string(file_path)?;
-    let file_conf = match serde_yaml::from_str::<FileConfig>(&conf) {
-        Ok(val) => val,
+    let format = format.unwrap_or_else(|| ConfigFormat::from_path(file_path));
+    let (config, cmd, _) = parse_config(&conf, format)?;
+    Ok((config, cmd))
+}
+
+/// 只解析和校验配置文件，不启动也不获取自动生成的设备id(不会创建device-id文件)，
+/// 用于在CI中检查配置。读取文件失败时返回错误，配置的问题都放在报告里
+pub fn check_config(file_path: &str, format: Option<ConfigFormat>) -> anyhow::Result<ConfigReport> {
+    let conf = std::fs::read_to_string(file_path)?;
+    let format = format.unwrap_or_else(|| ConfigFormat::from_path(file_path));
+    Ok(check_config0(&conf, format))
+}
+
+fn check_config0(conf: &str, format: ConfigFormat) -> ConfigReport {
+    let mut report = ConfigReport::default();
+    let (file_conf, env_device_id) = match parse_file_config(conf, format) {
+        Ok(conf) => conf,
         Err(e) => {
-            log::error!("{:?}", e);
-            return Err(anyhow!("{}", e));
+            report.errors.push(e.to_string());
+            return report;
         }
     };
+    report.warnings = file_config_warnings(&file_conf, env_device_id.is_some());
+    let config = to_config(file_conf, |file_conf| {
+        // 和resolve_device_id的优先级一致，环境变量 > 配置文件
+        let device_id = env_device_id
+            .as_deref()
+            .unwrap_or(file_conf.device_id.trim());
+        if device_id.is_empty() {
+            Ok("<auto>".to_string())
+        } else {
+            Ok(device_id.to_string())
+        }
+    });
+    match config {
+        Ok(config) => {
+            if let Err(errors) = validate_config(&config) {
+                report.errors.extend(errors.iter().map(|e| e.to_string()));
+            }
+            report.config = Some(config);
+        }
+        Err(e) => report.errors.push(e.to_string()),
+    }
+    report
+}
+
+/// 不影响启动，但是可能和预期不一样的配置，env_device_id表示设置了环境变量VNT_DEVICE_ID
+fn file_config_warnings(file_conf: &FileConfig, env_device_id: bool) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let id_options_set = file_conf.device_id_seed.is_some() || file_conf.device_id_dir.is_some();
+    if !env_device_id && file_conf.device_id.trim().is_empty() {
+        warnings.push("device_id: not set, resolved at startup".to_string());
+    } else if id_options_set {
+        warnings.push("device_id_seed/device_id_dir: ignored because device_id is set".to_string());
+    }
+    if file_conf.device_id_seed.is_some() && file_conf.device_id_dir.is_some() {
+        warnings.push("device_id_dir: ignored because device_id_seed is set".to_string());
+    }
+    if file_conf.compressor.is_none() && file_conf.compress_threshold != DEFAULT_COMPRESS_THRESHOLD
+    {
+        warnings.push("compress_threshold: ignored without compressor".to_string());
+    }
+    warnings
+}
+
+/// 不同格式只是反序列化不同，后面的处理和校验都是一样的，同时返回设备id的来源
+fn parse_config(
+    conf: &str,
+    format: ConfigFormat,
+) -> anyhow::Result<(Config, bool, DeviceIdSource)> {
+    let (file_conf, env_device_id) = parse_file_config(conf, format)?;
+    let cmd = file_conf.cmd;
+    let mut device_id_source = DeviceIdSource::Configured;
+    let config = to_config(file_conf, |file_conf| {
+        let device_id_strategy = DeviceIdStrategy::new(
+            file_conf.device_id_seed.clone(),
+            file_conf.device_id_dir.clone(),
+        );
+        let device_id = resolve_device_id(
+            None,
+            env_device_id.as_deref(),
+            Some(&file_conf.device_id),
+            &device_id_strategy,
+        )?;
+        device_id_source = device_id.source;
+        Ok(device_id.value)
+    })?;
+    if let Err(errors) = validate_config(&config) {
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        return Err(anyhow!("\n{}", errors.join("\n")));
+    }
+    Ok((config, cmd, device_id_source))
+}
+
+/// 反序列化并用环境变量覆盖文件里的值，
+/// VNT_DEVICE_ID单独返回，由resolve_device_id按优先级处理并记录来源
+fn parse_file_config(
+    conf: &str,
+    format: ConfigFormat,
+) -> anyhow::Result<(FileConfig, Option<String>)> {
+    check_unknown_keys(conf, format)?;
+    let file_conf = match format {
+        ConfigFormat::Yaml => serde_yaml::from_str::<FileConfig>(conf).map_err(|e| {
+            log::error!("{:?}", e);
+            anyhow!("{}", e)
+        }),
+        ConfigFormat::Toml => toml::from_str::<FileConfig>(conf).map_err(|e| {
+            log::error!("{:?}", e);
+            anyhow!("{}", e)
+        }),
+    };
+    let mut file_conf = file_conf?;
+    let env = env_config()?;
+    if let Some(token) = env.token {
+        file_conf.token = token;
+    }
+    if let Some(server_address) = env.server_address {
+        file_conf.server_address = server_address;
+    }
+    if env.device_id_dir.is_some() {
+        file_conf.device_id_dir = env.device_id_dir;
+    }
+    if env.device_id_seed.is_some() {
+        file_conf.device_id_seed = env.device_id_seed;
+    }
+    if let Some(name) = env.name {
+        file_conf.name = name;
+    }
+    if env.password.is_some() {
+        file_conf.password = env.password;
+    }
+    if let Some(ip) = env.ip {
+        file_conf.ip = Some(ip.to_string());
+    }
+    if env.mtu.is_some() {
+        file_conf.mtu = env.mtu;
+    }
+    Ok((file_conf, env.device_id))
+}
+
+/// device_id在其他字段都解析成功后才获取，配置有错误时不会生成device-id文件
+fn to_config(
+    file_conf: FileConfig,
+    device_id: impl FnOnce(&FileConfig) -> anyhow::Result<String>,
+) -> anyhow::Result<Config> {
     if file_conf.token.is_empty() {
         return Err(anyhow!("token is_empty"));
     }
@@ -118,28 +537,175 @@ pub fn read_config(file_path: &str) -> anyhow::Result<(Config, bool)> {
         None => None,
         Some(r) => Some(r.map_err(|e| anyhow!("ip {:?} error:{}", &file_conf.ip, e))?),
     };
-    let cipher_model = {
-        #[cfg(not(any(feature = "aes_gcm", feature = "server_encrypt")))]
-        if file_conf.password.is_some() && file_conf.cipher_model.is_none() {
-            Err(anyhow!("cipher_model undefined"))?
+    let cipher_model = match file_conf.cipher_model.as_ref() {
+        Some(model) => {
+            CipherModel::from_str(model).map_err(|e| anyhow!("cipher_model error:{}", e))?
+        }
+        None => {
+            #[cfg(not(any(feature = "aes_gcm", feature = "server_encrypt")))]
+            {
+                if file_conf.password.is_some() {
+                    Err(anyhow!("cipher_model undefined"))?
+                }
+                CipherModel::None
+            }
+            #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+            CipherModel::AesGcm
         }
-        #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
-        CipherModel::AesGcm
     };
 
-    let punch_model = PunchModel::from_str(&file_conf.punch_model).map_err(|e| anyhow!("{}", e))?;
-    let use_channel_type =
-        UseChannelType::from_str(&file_conf.use_channel).map_err(|e| anyhow!("{}", e))?;
+    let punch_model = PunchModel::from_str(&file_conf.punch_model)
+        .map_err(|e| anyhow!("punch_model error:{}", e))?;
+    let use_channel_type = UseChannelType::from_str(&file_conf.use_channel)
+        .map_err(|e| anyhow!("use_channel error:{}", e))?;
     let compressor = if let Some(compressor) = file_conf.compressor.as_ref() {
-        Compressor::from_str(compressor).map_err(|e| anyhow!("{}", e))?
+        Compressor::from_str(compressor).map_err(|e| anyhow!("compressor error:{}", e))?
     } else {
         Compressor::None
     };
-    let config = Config::new(
+    #[cfg(feature = "ip_proxy")]
+    let tcp_bind_addr = match file_conf.proxy_bind_addr.as_ref() {
+        None => ProxyConfig::default().tcp_bind_addr,
+        Some(addr) => {
+            IpAddr::from_str(addr).map_err(|e| anyhow!("proxy_bind_addr {:?} error:{}", addr, e))?
+        }
+    };
+    #[cfg(feature = "ip_proxy")]
+    let tcp_port_filter = if let Some(filter) = file_conf.proxy_port_filter.as_ref() {
+        PortFilter::from_str(filter).map_err(|e| anyhow!("proxy_port_filter error:{}", e))?
+    } else {
+        PortFilter::All
+    };
+    #[cfg(feature = "ip_proxy")]
+    let tcp_policy = ProxyPolicy::new(
+        file_conf
+            .proxy_policy
+            .iter()
+            .map(|rule| PolicyRule::from_str(rule))
+            .collect::<Result<_, _>>()
+            .map_err(|e| anyhow!("proxy_policy error:{}", e))?,
+    );
+    #[cfg(feature = "ip_proxy")]
+    let tcp_nat64 = file_conf
+        .proxy_nat64
+        .iter()
+        .map(|rule| Nat64::from_str(rule))
+        .collect::<Result<_, _>>()
+        .map_err(|e| anyhow!("proxy_nat64 error:{}", e))?;
+    // 格式：10.26.0.3:80->/run/app.sock
+    #[cfg(feature = "ip_proxy")]
+    let tcp_unix_targets = file_conf
+        .proxy_unix_targets
+        .iter()
+        .map(|target| {
+            let (addr, path) = target
+                .split_once("->")
+                .ok_or_else(|| anyhow!("proxy_unix_targets {:?} error", target))?;
+            let addr = SocketAddrV4::from_str(addr.trim())
+                .map_err(|e| anyhow!("proxy_unix_targets {:?} error:{}", target, e))?;
+            Ok((addr, PathBuf::from(path.trim())))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    #[cfg(feature = "ip_proxy")]
+    let tcp_proxy_protocol = file_conf
+        .proxy_protocol
+        .iter()
+        .map(|rule| ProxyProtocolRule::from_str(rule))
+        .collect::<Result<_, _>>()
+        .map_err(|e| anyhow!("proxy_protocol error:{}", e))?;
+    // 没有规则时不加载根证书
+    #[cfg(feature = "tls")]
+    let tcp_tls = if file_conf.proxy_tls.is_empty() {
+        None
+    } else {
+        let rules = file_conf
+            .proxy_tls
+            .iter()
+            .map(|rule| TlsRule::from_str(rule))
+            .collect::<Result<_, _>>()
+            .map_err(|e| anyhow!("proxy_tls error:{}", e))?;
+        let ca_file = file_conf.proxy_tls_ca.as_deref().map(std::path::Path::new);
+        Some(TlsOrigin::new(rules, ca_file).map_err(|e| anyhow!("proxy_tls_ca error:{}", e))?)
+    };
+    #[cfg(feature = "ip_proxy")]
+    let tcp_upstream = if let Some(upstream) = file_conf.proxy_upstream.as_ref() {
+        UpstreamProxy::from_str(upstream).map_err(|e| anyhow!("proxy_upstream error:{}", e))?
+    } else {
+        UpstreamProxy::Direct
+    };
+    #[cfg(feature = "ip_proxy")]
+    let socks5 = match file_conf.proxy_socks5.as_ref() {
+        Some(socks5) => {
+            Some(Socks5Listen::from_str(socks5).map_err(|e| anyhow!("proxy_socks5 error:{}", e))?)
+        }
+        None => None,
+    };
+    // 只写ip时使用53端口
+    #[cfg(feature = "ip_proxy")]
+    let dns_upstream = match file_conf.proxy_dns_upstream.as_ref() {
+        Some(addr) => Some(
+            SocketAddr::from_str(addr)
+                .or_else(|_| {
+                    IpAddr::from_str(addr).map(|ip| SocketAddr::new(ip, dns_proxy::DNS_PORT))
+                })
+                .map_err(|e| anyhow!("proxy_dns_upstream {:?} error:{}", addr, e))?,
+        ),
+        None => None,
+    };
+    #[cfg(feature = "ip_proxy")]
+    let proxy_config = ProxyConfig {
+        tcp_bind_addr,
+        tcp_port_filter,
+        tcp_policy,
+        tcp_listen_backlog: file_conf.proxy_listen_backlog,
+        tcp_reuse_addr: file_conf.proxy_reuse_addr,
+        tcp_buf_len: file_conf.proxy_buf_len,
+        tcp_connect_timeout: Duration::from_secs(file_conf.proxy_connect_timeout),
+        tcp_mss: file_conf.proxy_mss,
+        tcp_require_src_port: file_conf.proxy_require_src_port,
+        tcp_nat64,
+        tcp_tos_passthrough: file_conf.proxy_tos_passthrough,
+        tcp_unix_targets,
+        tcp_proxy_protocol,
+        #[cfg(feature = "tls")]
+        tcp_tls,
+        tcp_egress_bind: file_conf.proxy_egress_bind,
+        tcp_egress_device: file_conf.proxy_egress_device.clone(),
+        tcp_fwmark: file_conf.proxy_fwmark,
+        tcp_nodelay: file_conf.proxy_nodelay,
+        tcp_nodelay_ports: file_conf.proxy_nodelay_ports.clone(),
+        tcp_keepalive_idle: Duration::from_secs(file_conf.proxy_keepalive_idle),
+        tcp_keepalive_interval: Duration::from_secs(file_conf.proxy_keepalive_interval),
+        tcp_keepalive_count: file_conf.proxy_keepalive_count,
+        tcp_idle_timeout: Duration::from_secs(file_conf.proxy_idle_timeout),
+        tcp_write_timeout: Duration::from_secs(file_conf.proxy_write_timeout),
+        tcp_nat_ttl: Duration::from_secs(file_conf.proxy_nat_ttl),
+        tcp_nat_max: file_conf.proxy_nat_max,
+        tcp_drain_timeout: Duration::from_secs(file_conf.proxy_drain_timeout),
+        tcp_max_connections: file_conf.proxy_max_connections,
+        tcp_max_connections_per_dest: file_conf.proxy_max_connections_per_dest,
+        tcp_accept_rate: file_conf.proxy_accept_rate,
+        tcp_upstream,
+        tcp_rate_limit: file_conf.proxy_rate_limit,
+        tcp_rate_limit_per_conn: file_conf.proxy_rate_limit_per_conn,
+        socks5,
+        tcp_observer: None,
+        tcp_access_log: file_conf.proxy_access_log.as_ref().map(PathBuf::from),
+        tcp_access_log_max_size: file_conf.proxy_access_log_max_size,
+        tcp_access_log_max_files: file_conf.proxy_access_log_max_files,
+        udp_idle_timeout: Duration::from_secs(file_conf.proxy_udp_idle_timeout),
+        dns_upstream,
+        dns_domains: file_conf.proxy_dns_domains.clone(),
+        handlers: Default::default(),
+        packet_observer: None,
+        worker_threads: file_conf.proxy_worker_threads,
+    };
+    let device_id = device_id(&file_conf)?;
+    let mut config = Config::new(
         #[cfg(target_os = "windows")]
         file_conf.tap,
         file_conf.token,
-        file_conf.device_id,
+        device_id,
         file_conf.name,
         file_conf.server_address,
         file_conf.dns,
@@ -152,6 +718,8 @@ pub fn read_config(file_path: &str) -> anyhow::Result<(Config, bool)> {
         virtual_ip,
         #[cfg(feature = "ip_proxy")]
         file_conf.no_proxy,
+        #[cfg(feature = "ip_proxy")]
+        proxy_config,
         file_conf.server_encrypt,
         file_conf.parallel,
         cipher_model,
@@ -167,5 +735,192 @@ pub fn read_config(file_path: &str) -> anyhow::Result<(Config, bool)> {
         file_conf.mapping,
         compressor,
     )?;
-    Ok((config, file_conf.cmd))
+    config.compress_threshold = file_conf.compress_threshold;
+    config.fallback_servers = file_conf.fallback_servers;
+    Ok(config)
+}
+
+#[test]
+fn test_unknown_keys() {
+    assert!(check_unknown_keys("token: abc\nmtu: 1400\n", ConfigFormat::Yaml).is_ok());
+    let e = check_unknown_keys(
+        "token: abc\nsever_address: 1.1.1.1:29872\n",
+        ConfigFormat::Yaml,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(e.starts_with("unknown key 'sever_address' at line 2, did you mean 'server_address'?"));
+    assert!(e.contains("accepted keys: "));
+    let e = check_unknown_keys("abcdefg: 1\n", ConfigFormat::Yaml)
+        .unwrap_err()
+        .to_string();
+    assert!(!e.contains("did you mean"));
+    let e = check_unknown_keys("token = \"abc\"\nmut = 1400\n", ConfigFormat::Toml)
+        .unwrap_err()
+        .to_string();
+    assert!(e.starts_with("unknown key 'mut' at line 2, did you mean 'mtu'?"));
+}
+
+#[test]
+fn test_toml_same_as_yaml() {
+    let yaml = r#"
+token: abc
+device_id: device
+name: test
+server_address: 127.0.0.1:29872
+stun_server:
+  - 127.0.0.1:3478
+in_ips:
+  - 192.168.0.0/24,10.26.0.3
+mtu: 1400
+ip: 10.26.0.2
+ports: [0, 0]
+"#;
+    let toml = r#"
+token = "abc"
+device_id = "device"
+name = "test"
+server_address = "127.0.0.1:29872"
+stun_server = ["127.0.0.1:3478"]
+in_ips = ["192.168.0.0/24,10.26.0.3"]
+mtu = 1400
+ip = "10.26.0.2"
+ports = [0, 0]
+"#;
+    let (yaml_config, yaml_cmd, _) = parse_config(yaml, ConfigFormat::Yaml).unwrap();
+    let (toml_config, toml_cmd, _) = parse_config(toml, ConfigFormat::Toml).unwrap();
+    assert_eq!(format!("{:?}", yaml_config), format!("{:?}", toml_config));
+    assert_eq!(yaml_cmd, toml_cmd);
+    assert_eq!(ConfigFormat::from_path("conf.TOML"), ConfigFormat::Toml);
+    assert_eq!(ConfigFormat::from_path("conf.yaml"), ConfigFormat::Yaml);
+}
+
+#[test]
+fn test_builder_same_as_file() {
+    // 服务器地址使用ip，避免测试时解析域名
+    let yaml = "token: abc\ndevice_id: device\nserver_address: 127.0.0.1:29872\n";
+    let (file_config, _, _) = parse_config(yaml, ConfigFormat::Yaml).unwrap();
+    let builder_config = Config::builder()
+        .token("abc")
+        .device_id("device")
+        .server_address("127.0.0.1:29872")
+        .build()
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", file_config),
+        format!("{:?}", builder_config)
+    );
+}
+
+#[test]
+fn test_check_config() {
+    let yaml = "token: abc\ndevice_id: device\nserver_address: 127.0.0.1:29872\n";
+    let report = check_config0(yaml, ConfigFormat::Yaml);
+    assert!(report.is_ok());
+    assert!(report.warnings.is_empty());
+    assert_eq!(report.config.unwrap().device_id, "device");
+    // 没有设置device_id时不获取自动生成的id，配置错误一次全部列出
+    let yaml = r#"
+token: abc
+device_id_seed: seed
+device_id_dir: /tmp
+server_address: 127.0.0.1:29872
+mtu: 100
+parallel: 0
+compress_threshold: 10
+"#;
+    let report = check_config0(yaml, ConfigFormat::Yaml);
+    assert!(!report.is_ok());
+    assert_eq!(
+        report.warnings,
+        vec![
+            "device_id: not set, resolved at startup",
+            "device_id_dir: ignored because device_id_seed is set",
+            "compress_threshold: ignored without compressor",
+        ]
+    );
+    assert_eq!(report.errors.len(), 2);
+    assert!(report.errors[0].starts_with("mtu: 100 invalid"));
+    assert!(report.errors[1].starts_with("parallel: 0 invalid"));
+    let text = report.to_string();
+    assert!(text.contains("error: mtu: 100 invalid"));
+    assert!(text.ends_with("config has 2 error(s)"));
+    // 无法解析时没有config
+    let report = check_config0("token: abc\nmut: 1400\n", ConfigFormat::Yaml);
+    assert!(report.config.is_none());
+    assert!(report.errors[0].starts_with("unknown key 'mut'"));
+}
+
+#[cfg(feature = "ip_proxy")]
+#[test]
+fn test_proxy_keepalive() {
+    let yaml = "token: abc\ndevice_id: device\nserver_address: 127.0.0.1:29872\n";
+    let (config, _, _) = parse_config(yaml, ConfigFormat::Yaml).unwrap();
+    // 默认不开启
+    assert!(config.proxy_config.tcp_keepalive_idle.is_zero());
+    let yaml = r#"
+token: abc
+device_id: device
+server_address: 127.0.0.1:29872
+proxy_keepalive_idle: 30
+proxy_keepalive_interval: 5
+proxy_keepalive_count: 3
+"#;
+    let (config, _, _) = parse_config(yaml, ConfigFormat::Yaml).unwrap();
+    assert_eq!(
+        config.proxy_config.tcp_keepalive_idle,
+        Duration::from_secs(30)
+    );
+    assert_eq!(
+        config.proxy_config.tcp_keepalive_interval,
+        Duration::from_secs(5)
+    );
+    assert_eq!(config.proxy_config.tcp_keepalive_count, 3);
+}
+
+#[cfg(all(feature = "aes_gcm", feature = "chacha20_poly1305"))]
+#[test]
+fn test_cipher_model() {
+    let yaml =
+        "token: abc\ndevice_id: device\nserver_address: 127.0.0.1:29872\npassword: '123456'\n";
+    let (config, _, _) = parse_config(yaml, ConfigFormat::Yaml).unwrap();
+    assert_eq!(config.cipher_model, CipherModel::AesGcm);
+    let (config, _, _) = parse_config(
+        &format!("{}cipher_model: chacha20_poly1305\n", yaml),
+        ConfigFormat::Yaml,
+    )
+    .unwrap();
+    assert_eq!(config.cipher_model, CipherModel::Chacha20Poly1305);
+    assert!(parse_config(&format!("{}cipher_model: abc\n", yaml), ConfigFormat::Yaml).is_err());
+}
+
+#[test]
+fn test_device_id_env() {
+    // 环境变量是整个进程共享的，在子进程里设置，不影响其他并行执行的测试
+    if std::env::var_os("VNT_TEST_DEVICE_ID_ENV").is_none() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "config::file_config::test_device_id_env"])
+            .env("VNT_TEST_DEVICE_ID_ENV", "1")
+            .env(super::DEVICE_ID_ENV, " env-device ")
+            .env_remove("VNT_DEVICE_ID_SEED")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+    // 环境变量 > 配置文件
+    let yaml = "token: abc\ndevice_id: device\nserver_address: 127.0.0.1:29872\n";
+    let (config, _, source) = parse_config(yaml, ConfigFormat::Yaml).unwrap();
+    assert_eq!(config.device_id, "env-device");
+    assert_eq!(source, DeviceIdSource::Env);
+    let yaml = "token: abc\nserver_address: 127.0.0.1:29872\ndevice_id_seed: seed\n";
+    let (config, _, source) = parse_config(yaml, ConfigFormat::Yaml).unwrap();
+    assert_eq!(config.device_id, "env-device");
+    assert_eq!(source, DeviceIdSource::Env);
+    let report = check_config0(yaml, ConfigFormat::Yaml);
+    assert_eq!(report.config.unwrap().device_id, "env-device");
+    assert_eq!(
+        report.warnings,
+        ["device_id_seed/device_id_dir: ignored because device_id is set"]
+    );
 }
@@ -1,31 +1,569 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use vnt::core::Config;
+#[cfg(feature = "ip_proxy")]
+use vnt::ip_proxy::socks5::UpstreamProxy;
+
+/// ipv4要求的最小mtu
+const MIN_MTU: u32 = 576;
+
 #[cfg(feature = "file_config")]
 mod file_config;
+mod watcher;
 
 #[cfg(feature = "file_config")]
-pub use file_config::read_config;
+pub use file_config::{check_config, read_config};
+pub use watcher::ConfigWatcher;
 
+/// -f总是注册的，没有file_config时返回错误，不能panic
 #[cfg(not(feature = "file_config"))]
-pub fn read_config(_file_path: &str) -> anyhow::Result<(vnt::core::Config, bool)> {
-    unimplemented!()
+pub fn read_config(
+    _file_path: &str,
+    _format: Option<ConfigFormat>,
+) -> anyhow::Result<(vnt::core::Config, bool)> {
+    Err(anyhow::anyhow!("file_config feature is not enabled"))
 }
 
-pub fn get_device_id() -> String {
-    if let Some(id) = common::identifier::get_unique_identifier() {
-        id
-    } else {
-        let path_buf = match crate::app_home() {
-            Ok(path_buf) => path_buf.join("device-id"),
-            Err(e) => {
-                log::warn!("{:?}", e);
-                return String::new();
+/// --check总是注册的，没有file_config时返回错误，不能panic
+#[cfg(not(feature = "file_config"))]
+pub fn check_config(
+    _file_path: &str,
+    _format: Option<ConfigFormat>,
+) -> anyhow::Result<ConfigReport> {
+    Err(anyhow::anyhow!("file_config feature is not enabled"))
+}
+
+/// 配置文件格式
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+
+#[cfg(feature = "file_config")]
+impl ConfigFormat {
+    /// 按扩展名判断，.toml以外的都当作yaml
+    pub fn from_path(file_path: &str) -> Self {
+        let is_toml = std::path::Path::new(file_path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+        if is_toml {
+            ConfigFormat::Toml
+        } else {
+            ConfigFormat::Yaml
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "toml" => Ok(ConfigFormat::Toml),
+            _ => Err(format!("not match '{}', enum: yaml/toml", s)),
+        }
+    }
+}
+
+/// 配置项错误，key是配置文件里的字段名
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: &'static str,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: &'static str, message: String) -> Self {
+        Self { key, message }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// 检查配置文件的结果
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    /// 解析后的配置，无法解析时为None
+    pub config: Option<Config>,
+    /// 不影响启动的问题，例如被忽略的字段
+    pub warnings: Vec<String>,
+    /// 启动时会失败的错误
+    pub errors: Vec<String>,
+}
+
+impl ConfigReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(config) = &self.config {
+            let mut config = config.clone();
+            // 不输出密码
+            if config.password.is_some() {
+                config.password = Some("******".to_string());
             }
-        };
-        if let Ok(id) = std::fs::read_to_string(path_buf.as_path()) {
-            id
+            writeln!(f, "{:#?}", config)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "warning: {}", warning)?;
+        }
+        for error in &self.errors {
+            writeln!(f, "error: {}", error)?;
+        }
+        if self.is_ok() {
+            write!(f, "config ok")
         } else {
-            let id = uuid::Uuid::new_v4().to_string();
-            let _ = std::fs::write(path_buf, &id);
-            id
+            write!(f, "config has {} error(s)", self.errors.len())
+        }
+    }
+}
+
+/// 检查解析后的配置，一次返回所有错误
+pub fn validate_config(config: &Config) -> Result<(), Vec<ConfigError>> {
+    let mut errors = Vec::new();
+    for (dest, mask, ip) in &config.in_ips {
+        if !is_valid_mask(*mask) {
+            errors.push(ConfigError::new(
+                "in_ips",
+                format!(
+                    "mask {} of {} invalid, exp: 192.168.0.0/24,10.26.0.3",
+                    Ipv4Addr::from(*mask),
+                    Ipv4Addr::from(*dest)
+                ),
+            ));
+        }
+        if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+            errors.push(ConfigError::new(
+                "in_ips",
+                format!("gateway {} invalid, exp: 192.168.0.0/24,10.26.0.3", ip),
+            ));
+        }
+    }
+    for (dest, mask) in &config.out_ips {
+        if !is_valid_mask(*mask) {
+            errors.push(ConfigError::new(
+                "out_ips",
+                format!(
+                    "mask {} of {} invalid, exp: 0.0.0.0/0",
+                    Ipv4Addr::from(*mask),
+                    Ipv4Addr::from(*dest)
+                ),
+            ));
+        }
+    }
+    if let Some(mtu) = config.mtu {
+        if !(MIN_MTU..=u16::MAX as u32).contains(&mtu) {
+            errors.push(ConfigError::new(
+                "mtu",
+                format!("{} invalid, exp: {}-{}", mtu, MIN_MTU, u16::MAX),
+            ));
+        }
+    }
+    if let Some(ip) = config.ip {
+        if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+            errors.push(ConfigError::new(
+                "ip",
+                format!("{} invalid, exp: 10.26.0.2", ip),
+            ));
+        }
+    }
+    if config.parallel == 0 {
+        errors.push(ConfigError::new(
+            "parallel",
+            "0 invalid, exp: positive integer".to_string(),
+        ));
+    }
+    if let Some(ports) = &config.ports {
+        // 0表示随机端口，可以重复
+        let mut used = HashSet::new();
+        if ports.is_empty() {
+            errors.push(ConfigError::new(
+                "ports",
+                "empty, exp: [0,0] or remove it".to_string(),
+            ));
+        }
+        for port in ports.iter().filter(|port| **port != 0) {
+            if !used.insert(*port) {
+                errors.push(ConfigError::new("ports", format!("{} duplicate", port)));
+            }
         }
     }
+    if let Some(packet_loss) = config.packet_loss_rate {
+        if !(0.0..=1.0).contains(&packet_loss) {
+            errors.push(ConfigError::new(
+                "packet_loss",
+                format!("{} invalid, exp: 0-1", packet_loss),
+            ));
+        }
+    }
+    #[cfg(feature = "ip_proxy")]
+    validate_proxy_config(config, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(feature = "ip_proxy")]
+fn validate_proxy_config(config: &Config, errors: &mut Vec<ConfigError>) {
+    let proxy_config = &config.proxy_config;
+    if proxy_config.tcp_buf_len < vnt::ip_proxy::tcp_proxy::MIN_BUF_LEN {
+        errors.push(ConfigError::new(
+            "proxy_buf_len",
+            format!(
+                "{} invalid, exp: >= {}",
+                proxy_config.tcp_buf_len,
+                vnt::ip_proxy::tcp_proxy::MIN_BUF_LEN
+            ),
+        ));
+    }
+    if let std::net::IpAddr::V6(ip) = proxy_config.tcp_bind_addr {
+        if !ip.is_unspecified() {
+            errors.push(ConfigError::new(
+                "proxy_bind_addr",
+                format!("{} invalid, exp: ipv4 address", ip),
+            ));
+        }
+    }
+    if config.no_proxy && proxy_config.tcp_upstream != UpstreamProxy::Direct {
+        errors.push(ConfigError::new(
+            "proxy_upstream",
+            "can not be used with no_proxy".to_string(),
+        ));
+    }
+    if config.no_proxy && proxy_config.socks5.is_some() {
+        errors.push(ConfigError::new(
+            "proxy_socks5",
+            "can not be used with no_proxy".to_string(),
+        ));
+    }
+    if let Some(Err(e)) = proxy_config.socks5.as_ref().map(|socks5| socks5.check()) {
+        errors.push(ConfigError::new("proxy_socks5", e));
+    }
+}
+
+/// 掩码必须是连续的1
+fn is_valid_mask(mask: u32) -> bool {
+    mask.leading_ones() + mask.trailing_zeros() >= 32
+}
+
+/// 可以用环境变量覆盖的配置项，优先级：默认值 < 配置文件 < 环境变量 < 命令行参数
+#[derive(Debug, Default)]
+pub struct EnvConfig {
+    /// VNT_TOKEN
+    pub token: Option<String>,
+    /// VNT_SERVER
+    pub server_address: Option<String>,
+    /// VNT_DEVICE_ID
+    pub device_id: Option<String>,
+    /// VNT_DEVICE_ID_DIR
+    pub device_id_dir: Option<String>,
+    /// VNT_DEVICE_ID_SEED
+    pub device_id_seed: Option<String>,
+    /// VNT_NAME
+    pub name: Option<String>,
+    /// VNT_PASSWORD
+    pub password: Option<String>,
+    /// VNT_IP
+    pub ip: Option<Ipv4Addr>,
+    /// VNT_MTU
+    pub mtu: Option<u32>,
+}
+
+/// 读取VNT_*环境变量，值的类型不对时报错
+pub fn env_config() -> anyhow::Result<EnvConfig> {
+    Ok(EnvConfig {
+        token: env_var("VNT_TOKEN"),
+        server_address: env_var("VNT_SERVER"),
+        device_id: env_var(DEVICE_ID_ENV),
+        device_id_dir: env_var("VNT_DEVICE_ID_DIR"),
+        device_id_seed: env_var("VNT_DEVICE_ID_SEED"),
+        name: env_var("VNT_NAME"),
+        password: env_var("VNT_PASSWORD"),
+        ip: env_parse("VNT_IP")?,
+        mtu: env_parse("VNT_MTU")?,
+    })
+}
+
+/// 没设置或者是空字符串都当作没有
+fn env_var(key: &str) -> Option<String> {
+    std::env::var(key)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+fn env_parse<T>(key: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env_var(key) {
+        None => Ok(None),
+        Some(v) => T::from_str(&v)
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("env {}={:?} error:{}", key, v, e)),
+    }
+}
+
+/// 设置了这个环境变量时用它作为设备id，容器重建后也能保持不变，
+/// 不合并到配置文件的device_id里，获取设备id时单独处理才能知道来源
+pub const DEVICE_ID_ENV: &str = "VNT_DEVICE_ID";
+
+/// 没有指定设备id时自动获取的方式
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceIdStrategy {
+    /// 机器唯一标识 > 保存的device-id文件 > 新生成uuid，
+    /// device-id文件默认在app_home下，只读的根文件系统可以用dir指定可写的目录
+    Persistent { dir: Option<String> },
+    /// 由seed计算出固定的uuid，不依赖机器标识和文件，容器重建后也不变
+    Seed(String),
+}
+
+impl Default for DeviceIdStrategy {
+    fn default() -> Self {
+        DeviceIdStrategy::Persistent { dir: None }
+    }
+}
+
+impl DeviceIdStrategy {
+    /// 设置了seed就用Seed，否则是默认的Persistent
+    pub fn new(seed: Option<String>, dir: Option<String>) -> Self {
+        match seed {
+            Some(seed) => DeviceIdStrategy::Seed(seed),
+            None => DeviceIdStrategy::Persistent { dir },
+        }
+    }
+}
+
+/// 设备id是从哪里得到的，排查多个客户端使用相同设备id时有用
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceIdSource {
+    /// 配置文件或者命令行参数指定
+    Configured,
+    /// 环境变量VNT_DEVICE_ID
+    Env,
+    /// 由device_id_seed计算
+    Seed,
+    /// 机器唯一标识
+    Hardware,
+    /// 之前保存的device-id文件
+    PersistedFile,
+    /// 新生成的uuid
+    Generated,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceId {
+    pub value: String,
+    pub source: DeviceIdSource,
+}
+
+impl DeviceId {
+    fn new(value: String, source: DeviceIdSource) -> Self {
+        Self { value, source }
+    }
+}
+
+/// 和其他配置项的优先级一致：命令行参数 > 环境变量VNT_DEVICE_ID > 配置文件，
+/// 都没有指定(或者是空字符串)时按strategy获取。
+/// 获取失败时返回错误，不能用空的设备id连接服务器，否则不同的机器会相互冲突
+pub fn resolve_device_id(
+    arg: Option<&str>,
+    env: Option<&str>,
+    file: Option<&str>,
+    strategy: &DeviceIdStrategy,
+) -> anyhow::Result<DeviceId> {
+    let specified = [
+        (arg, DeviceIdSource::Configured),
+        (env, DeviceIdSource::Env),
+        (file, DeviceIdSource::Configured),
+    ]
+    .into_iter()
+    .find_map(|(id, source)| {
+        let id = id?.trim();
+        (!id.is_empty()).then(|| DeviceId::new(id.to_string(), source))
+    });
+    let device_id = match specified {
+        Some(device_id) => device_id,
+        None => get_device_id(strategy)?,
+    };
+    log::info!("设备id:{},来源:{:?}", device_id.value, device_id.source);
+    Ok(device_id)
+}
+
+pub fn get_device_id(strategy: &DeviceIdStrategy) -> anyhow::Result<DeviceId> {
+    match strategy {
+        DeviceIdStrategy::Persistent { dir } => {
+            let dir = match dir {
+                Some(dir) => {
+                    if let Err(e) = std::fs::create_dir_all(dir) {
+                        log::warn!("创建device-id目录{}失败:{:?}", dir, e);
+                    }
+                    Ok(PathBuf::from(dir))
+                }
+                None => crate::app_home(),
+            };
+            device_id_from(
+                common::identifier::get_unique_identifier,
+                dir.map(|path_buf| path_buf.join("device-id")),
+            )
+        }
+        DeviceIdStrategy::Seed(seed) => {
+            Ok(DeviceId::new(seed_device_id(seed), DeviceIdSource::Seed))
+        }
+    }
+}
+
+/// 相同的seed总是得到相同的uuid(v5)
+fn seed_device_id(seed: &str) -> String {
+    uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, seed.as_bytes()).to_string()
+}
+
+fn device_id_from(
+    unique_identifier: impl FnOnce() -> Option<String>,
+    path: io::Result<PathBuf>,
+) -> anyhow::Result<DeviceId> {
+    if let Some(id) = unique_identifier() {
+        Ok(DeviceId::new(id, DeviceIdSource::Hardware))
+    } else {
+        let path_buf = path.map_err(|e| {
+            anyhow::anyhow!(
+                "无法获取设备id,找不到保存device-id的目录:{},请指定设备id或者{}",
+                e,
+                DEVICE_ID_ENV
+            )
+        })?;
+        if let Ok(id) = std::fs::read_to_string(path_buf.as_path()) {
+            let id = id.trim();
+            if !id.is_empty() {
+                return Ok(DeviceId::new(id.to_string(), DeviceIdSource::PersistedFile));
+            }
+            log::warn!("{:?} 内容为空，重新生成设备id", path_buf);
+        }
+        let id = uuid::Uuid::new_v4().to_string();
+        // 保存失败时这次仍然可以用，但是重启后设备id会变
+        if let Err(e) = std::fs::write(path_buf.as_path(), &id) {
+            log::warn!("保存设备id到{:?}失败:{:?}", path_buf, e);
+        }
+        Ok(DeviceId::new(id, DeviceIdSource::Generated))
+    }
+}
+
+#[test]
+fn test_device_id_precedence() {
+    let dir = std::env::temp_dir().join(format!("vnt-device-id-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("device-id");
+    let _ = std::fs::remove_file(&path);
+    let unique = || Some("unique".to_string());
+    let device_id = |value: &str, source| DeviceId::new(value.to_string(), source);
+    let strategy = DeviceIdStrategy::Seed("seed".to_string());
+
+    // 命令行参数 > 环境变量 > 配置文件，空字符串忽略
+    assert_eq!(
+        resolve_device_id(Some(" arg "), Some("env"), Some("file"), &strategy).unwrap(),
+        device_id("arg", DeviceIdSource::Configured)
+    );
+    assert_eq!(
+        resolve_device_id(Some(" "), Some("env"), Some("file"), &strategy).unwrap(),
+        device_id("env", DeviceIdSource::Env)
+    );
+    assert_eq!(
+        resolve_device_id(None, Some(""), Some("file"), &strategy).unwrap(),
+        device_id("file", DeviceIdSource::Configured)
+    );
+    // 都没有指定时按strategy获取
+    assert_eq!(
+        resolve_device_id(None, None, Some(" "), &strategy)
+            .unwrap()
+            .source,
+        DeviceIdSource::Seed
+    );
+    // 机器唯一标识 > 保存的device-id文件
+    assert_eq!(
+        device_id_from(unique, Ok(path.clone())).unwrap(),
+        device_id("unique", DeviceIdSource::Hardware)
+    );
+    // 没有唯一标识时新生成并保存，之后读取保存的值
+    let id = device_id_from(|| None, Ok(path.clone())).unwrap();
+    assert_eq!(id.source, DeviceIdSource::Generated);
+    assert!(uuid::Uuid::parse_str(&id.value).is_ok());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), id.value);
+    assert_eq!(
+        device_id_from(|| None, Ok(path.clone())).unwrap(),
+        device_id(&id.value, DeviceIdSource::PersistedFile)
+    );
+    assert_eq!(
+        device_id_from(unique, Ok(path.clone())).unwrap(),
+        device_id("unique", DeviceIdSource::Hardware)
+    );
+    let err = Err(io::Error::new(io::ErrorKind::NotFound, "app_home"));
+    // 找不到保存的目录时不能返回空的设备id
+    assert!(device_id_from(|| None, err).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_device_id_file_error() {
+    let dir = std::env::temp_dir().join(format!("vnt-device-id-file-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // 空文件不能当作设备id，重新生成并覆盖
+    let path = dir.join("device-id");
+    std::fs::write(&path, " \n").unwrap();
+    let id = device_id_from(|| None, Ok(path.clone())).unwrap();
+    assert_eq!(id.source, DeviceIdSource::Generated);
+    assert!(uuid::Uuid::parse_str(&id.value).is_ok());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), id.value);
+    // 目录不可写时仍然返回新生成的设备id
+    let not_dir = dir.join("file");
+    std::fs::write(&not_dir, "").unwrap();
+    let id = device_id_from(|| None, Ok(not_dir.join("device-id"))).unwrap();
+    assert_eq!(id.source, DeviceIdSource::Generated);
+    assert!(uuid::Uuid::parse_str(&id.value).is_ok());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_env_parse() {
+    std::env::set_var("VNT_TEST_ENV_MTU", " 1400 ");
+    assert_eq!(env_parse::<u32>("VNT_TEST_ENV_MTU").unwrap(), Some(1400));
+    std::env::set_var("VNT_TEST_ENV_MTU", "abc");
+    let e = env_parse::<u32>("VNT_TEST_ENV_MTU")
+        .unwrap_err()
+        .to_string();
+    assert!(e.starts_with("env VNT_TEST_ENV_MTU=\"abc\" error:"));
+    std::env::set_var("VNT_TEST_ENV_MTU", "");
+    assert_eq!(env_parse::<u32>("VNT_TEST_ENV_MTU").unwrap(), None);
+    std::env::remove_var("VNT_TEST_ENV_MTU");
+}
+
+#[test]
+fn test_seed_device_id() {
+    let strategy = DeviceIdStrategy::new(Some("host-1:salt".to_string()), None);
+    let id = get_device_id(&strategy).unwrap();
+    assert_eq!(id.source, DeviceIdSource::Seed);
+    assert_eq!(id, get_device_id(&strategy).unwrap());
+    let id = id.value;
+    assert_eq!(uuid::Uuid::parse_str(&id).unwrap().get_version_num(), 5);
+    assert_ne!(id, seed_device_id("host-2:salt"));
+    assert_eq!(
+        DeviceIdStrategy::new(None, None),
+        DeviceIdStrategy::default()
+    );
 }
@@ -0,0 +1,379 @@
+use std::fmt::Debug;
+
+use vnt::core::Config;
+
+use crate::config::{read_config, ConfigFormat};
+
+/// 不重启就能生效的字段，其他字段变化需要重启。
+/// out_ips从空变成非空(或者相反)时需要启动或停止代理，仍然需要重启
+pub const HOT_RELOAD_KEYS: &[&str] = &["out_ips", "proxy_port_filter", "proxy_rate_limit"];
+
+/// 两次读取配置之间变化的字段，用配置文件里的字段名表示
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    /// 可以直接生效的变化
+    pub hot: Vec<&'static str>,
+    /// 需要重启才能生效的变化
+    pub restart: Vec<&'static str>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.hot.is_empty() && self.restart.is_empty()
+    }
+}
+
+/// 重新读取配置文件并和当前生效的配置比较
+pub struct ConfigWatcher {
+    file_path: String,
+    format: Option<ConfigFormat>,
+    /// 当前生效的配置，reload只会合并可以直接生效的字段
+    config: Config,
+}
+
+impl ConfigWatcher {
+    pub fn new(file_path: String, format: Option<ConfigFormat>, config: Config) -> Self {
+        Self {
+            file_path,
+            format,
+            config,
+        }
+    }
+    /// 返回文件里的新配置和变化的字段，读取失败时当前配置不变。
+    /// 需要重启的字段不会合并到当前配置，重启前每次reload都会报告
+    pub fn reload(&mut self) -> anyhow::Result<(Config, ConfigDiff)> {
+        let (config, _) = read_config(&self.file_path, self.format)?;
+        let diff = diff_config(&self.config, &config);
+        if diff.hot.contains(&"out_ips") {
+            self.config.out_ips = config.out_ips.clone();
+        }
+        #[cfg(feature = "ip_proxy")]
+        {
+            if diff.hot.contains(&"proxy_port_filter") {
+                self.config.proxy_config.tcp_port_filter =
+                    config.proxy_config.tcp_port_filter.clone();
+            }
+            if diff.hot.contains(&"proxy_rate_limit") {
+                self.config.proxy_config.tcp_rate_limit = config.proxy_config.tcp_rate_limit;
+            }
+        }
+        Ok((config, diff))
+    }
+}
+
+fn diff_config(old: &Config, new: &Config) -> ConfigDiff {
+    let mut diff = ConfigDiff::default();
+    let mut check = |key: &'static str, old: &dyn Debug, new: &dyn Debug| {
+        if format!("{:?}", old) != format!("{:?}", new) {
+            if HOT_RELOAD_KEYS.contains(&key) {
+                diff.hot.push(key);
+            } else {
+                diff.restart.push(key);
+            }
+        }
+    };
+    #[cfg(target_os = "windows")]
+    check("tap", &old.tap, &new.tap);
+    check("token", &old.token, &new.token);
+    check("device_id", &old.device_id, &new.device_id);
+    check("name", &old.name, &new.name);
+    check(
+        "server_address",
+        &old.server_address_str,
+        &new.server_address_str,
+    );
+    check(
+        "fallback_servers",
+        &old.fallback_servers,
+        &new.fallback_servers,
+    );
+    check("dns", &old.name_servers, &new.name_servers);
+    check("stun_server", &old.stun_server, &new.stun_server);
+    check("in_ips", &old.in_ips, &new.in_ips);
+    check("out_ips", &old.out_ips, &new.out_ips);
+    check("password", &old.password, &new.password);
+    check("mtu", &old.mtu, &new.mtu);
+    check("tcp", &old.tcp, &new.tcp);
+    check("ip", &old.ip, &new.ip);
+    #[cfg(feature = "ip_proxy")]
+    {
+        let (old_proxy, new_proxy) = (&old.proxy_config, &new.proxy_config);
+        check("no_proxy", &old.no_proxy, &new.no_proxy);
+        check(
+            "proxy_bind_addr",
+            &old_proxy.tcp_bind_addr,
+            &new_proxy.tcp_bind_addr,
+        );
+        check(
+            "proxy_port_filter",
+            &old_proxy.tcp_port_filter,
+            &new_proxy.tcp_port_filter,
+        );
+        check("proxy_policy", &old_proxy.tcp_policy, &new_proxy.tcp_policy);
+        check(
+            "proxy_worker_threads",
+            &old_proxy.worker_threads,
+            &new_proxy.worker_threads,
+        );
+        check(
+            "proxy_listen_backlog",
+            &old_proxy.tcp_listen_backlog,
+            &new_proxy.tcp_listen_backlog,
+        );
+        check(
+            "proxy_reuse_addr",
+            &old_proxy.tcp_reuse_addr,
+            &new_proxy.tcp_reuse_addr,
+        );
+        check(
+            "proxy_buf_len",
+            &old_proxy.tcp_buf_len,
+            &new_proxy.tcp_buf_len,
+        );
+        check(
+            "proxy_connect_timeout",
+            &old_proxy.tcp_connect_timeout,
+            &new_proxy.tcp_connect_timeout,
+        );
+        check("proxy_mss", &old_proxy.tcp_mss, &new_proxy.tcp_mss);
+        check(
+            "proxy_require_src_port",
+            &old_proxy.tcp_require_src_port,
+            &new_proxy.tcp_require_src_port,
+        );
+        check("proxy_nat64", &old_proxy.tcp_nat64, &new_proxy.tcp_nat64);
+        check(
+            "proxy_tos_passthrough",
+            &old_proxy.tcp_tos_passthrough,
+            &new_proxy.tcp_tos_passthrough,
+        );
+        check(
+            "proxy_unix_targets",
+            &old_proxy.tcp_unix_targets,
+            &new_proxy.tcp_unix_targets,
+        );
+        check(
+            "proxy_protocol",
+            &old_proxy.tcp_proxy_protocol,
+            &new_proxy.tcp_proxy_protocol,
+        );
+        #[cfg(feature = "tls")]
+        check("proxy_tls", &old_proxy.tcp_tls, &new_proxy.tcp_tls);
+        check(
+            "proxy_access_log",
+            &old_proxy.tcp_access_log,
+            &new_proxy.tcp_access_log,
+        );
+        check(
+            "proxy_access_log_max_size",
+            &old_proxy.tcp_access_log_max_size,
+            &new_proxy.tcp_access_log_max_size,
+        );
+        check(
+            "proxy_access_log_max_files",
+            &old_proxy.tcp_access_log_max_files,
+            &new_proxy.tcp_access_log_max_files,
+        );
+        check(
+            "proxy_egress_bind",
+            &old_proxy.tcp_egress_bind,
+            &new_proxy.tcp_egress_bind,
+        );
+        check(
+            "proxy_egress_device",
+            &old_proxy.tcp_egress_device,
+            &new_proxy.tcp_egress_device,
+        );
+        check("proxy_fwmark", &old_proxy.tcp_fwmark, &new_proxy.tcp_fwmark);
+        check(
+            "proxy_nodelay",
+            &old_proxy.tcp_nodelay,
+            &new_proxy.tcp_nodelay,
+        );
+        check(
+            "proxy_nodelay_ports",
+            &old_proxy.tcp_nodelay_ports,
+            &new_proxy.tcp_nodelay_ports,
+        );
+        check(
+            "proxy_keepalive_idle",
+            &old_proxy.tcp_keepalive_idle,
+            &new_proxy.tcp_keepalive_idle,
+        );
+        check(
+            "proxy_keepalive_interval",
+            &old_proxy.tcp_keepalive_interval,
+            &new_proxy.tcp_keepalive_interval,
+        );
+        check(
+            "proxy_keepalive_count",
+            &old_proxy.tcp_keepalive_count,
+            &new_proxy.tcp_keepalive_count,
+        );
+        check(
+            "proxy_idle_timeout",
+            &old_proxy.tcp_idle_timeout,
+            &new_proxy.tcp_idle_timeout,
+        );
+        check(
+            "proxy_write_timeout",
+            &old_proxy.tcp_write_timeout,
+            &new_proxy.tcp_write_timeout,
+        );
+        check(
+            "proxy_nat_ttl",
+            &old_proxy.tcp_nat_ttl,
+            &new_proxy.tcp_nat_ttl,
+        );
+        check(
+            "proxy_nat_max",
+            &old_proxy.tcp_nat_max,
+            &new_proxy.tcp_nat_max,
+        );
+        check(
+            "proxy_drain_timeout",
+            &old_proxy.tcp_drain_timeout,
+            &new_proxy.tcp_drain_timeout,
+        );
+        check(
+            "proxy_max_connections",
+            &old_proxy.tcp_max_connections,
+            &new_proxy.tcp_max_connections,
+        );
+        check(
+            "proxy_max_connections_per_dest",
+            &old_proxy.tcp_max_connections_per_dest,
+            &new_proxy.tcp_max_connections_per_dest,
+        );
+        check(
+            "proxy_accept_rate",
+            &old_proxy.tcp_accept_rate,
+            &new_proxy.tcp_accept_rate,
+        );
+        check(
+            "proxy_upstream",
+            &old_proxy.tcp_upstream,
+            &new_proxy.tcp_upstream,
+        );
+        check(
+            "proxy_rate_limit",
+            &old_proxy.tcp_rate_limit,
+            &new_proxy.tcp_rate_limit,
+        );
+        check(
+            "proxy_rate_limit_per_conn",
+            &old_proxy.tcp_rate_limit_per_conn,
+            &new_proxy.tcp_rate_limit_per_conn,
+        );
+        check("proxy_socks5", &old_proxy.socks5, &new_proxy.socks5);
+        check(
+            "proxy_udp_idle_timeout",
+            &old_proxy.udp_idle_timeout,
+            &new_proxy.udp_idle_timeout,
+        );
+        check(
+            "proxy_dns_upstream",
+            &old_proxy.dns_upstream,
+            &new_proxy.dns_upstream,
+        );
+        check(
+            "proxy_dns_domains",
+            &old_proxy.dns_domains,
+            &new_proxy.dns_domains,
+        );
+    }
+    check("server_encrypt", &old.server_encrypt, &new.server_encrypt);
+    check("parallel", &old.parallel, &new.parallel);
+    check("cipher_model", &old.cipher_model, &new.cipher_model);
+    check("finger", &old.finger, &new.finger);
+    check("punch_model", &old.punch_model, &new.punch_model);
+    check("ports", &old.ports, &new.ports);
+    check("first_latency", &old.first_latency, &new.first_latency);
+    #[cfg(not(target_os = "android"))]
+    check("device_name", &old.device_name, &new.device_name);
+    check("use_channel", &old.use_channel_type, &new.use_channel_type);
+    check("packet_loss", &old.packet_loss_rate, &new.packet_loss_rate);
+    check("packet_delay", &old.packet_delay, &new.packet_delay);
+    #[cfg(feature = "port_mapping")]
+    check("mapping", &old.port_mapping_list, &new.port_mapping_list);
+    check("compressor", &old.compressor, &new.compressor);
+    check(
+        "compress_threshold",
+        &old.compress_threshold,
+        &new.compress_threshold,
+    );
+    // 代理只在启动时out_ips不为空(或者开启了socks5)才会启动
+    #[cfg(feature = "ip_proxy")]
+    if !old.no_proxy
+        && old.proxy_config.socks5.is_none()
+        && old.out_ips.is_empty() != new.out_ips.is_empty()
+    {
+        if let Some(index) = diff.hot.iter().position(|key| *key == "out_ips") {
+            diff.hot.remove(index);
+            diff.restart.push("out_ips");
+        }
+    }
+    diff
+}
+
+#[cfg(feature = "ip_proxy")]
+#[test]
+fn test_reload() {
+    let dir = std::env::temp_dir().join(format!("vnt-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.yaml");
+    let conf = "token: abc\ndevice_id: device\nname: test\nserver_address: 127.0.0.1:29872\n";
+    std::fs::write(&path, conf).unwrap();
+    let file_path = path.to_str().unwrap().to_string();
+    let (config, _) = read_config(&file_path, None).unwrap();
+    let mut watcher = ConfigWatcher::new(file_path, None, config);
+
+    let (_, diff) = watcher.reload().unwrap();
+    assert!(diff.is_empty());
+
+    std::fs::write(
+        &path,
+        format!(
+            "{}proxy_port_filter: deny:22\ntoken: abcd\n",
+            conf.replace("token: abc\n", "")
+        ),
+    )
+    .unwrap();
+    let (config, diff) = watcher.reload().unwrap();
+    assert_eq!(diff.hot, vec!["proxy_port_filter"]);
+    assert_eq!(diff.restart, vec!["token"]);
+    assert_eq!(config.token, "abcd");
+    // 可以直接生效的字段合并到当前配置，需要重启的仍然保持旧值
+    assert_eq!(
+        watcher.config.proxy_config.tcp_port_filter,
+        config.proxy_config.tcp_port_filter
+    );
+    assert_eq!(watcher.config.token, "abc");
+    let (_, diff) = watcher.reload().unwrap();
+    assert!(diff.hot.is_empty());
+    assert_eq!(diff.restart, vec!["token"]);
+
+    // out_ips和代理限速直接生效，从无到有需要启动代理
+    let conf = format!("{}proxy_port_filter: deny:22\n", conf);
+    std::fs::write(
+        &path,
+        format!("{}out_ips:\n  - 0.0.0.0/0\nproxy_rate_limit: 1024\n", conf),
+    )
+    .unwrap();
+    let (_, diff) = watcher.reload().unwrap();
+    assert_eq!(diff.hot, vec!["proxy_rate_limit"]);
+    assert_eq!(diff.restart, vec!["out_ips"]);
+    assert_eq!(watcher.config.proxy_config.tcp_rate_limit, 1024);
+    assert!(watcher.config.out_ips.is_empty());
+    let mut old = watcher.config.clone();
+    old.out_ips = vec![(0, 0)];
+    let mut new = old.clone();
+    new.out_ips = vec![(0xc0a80100, 0xffffff00)];
+    assert_eq!(diff_config(&old, &new).hot, vec!["out_ips"]);
+
+    // 读取失败时当前配置不变
+    std::fs::write(&path, "token: [").unwrap();
+    assert!(watcher.reload().is_err());
+    assert_eq!(watcher.config.token, "abc");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
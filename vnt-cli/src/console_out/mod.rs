@@ -58,6 +58,17 @@ pub fn console_info(status: Info) {
             println!("  {}/{}", Ipv4Addr::from(dest), mask.count_ones())
         }
     }
+    if let Some(tcp_proxy) = status.tcp_proxy {
+        println!("------------------------------------------");
+        println!(
+            "TCP proxy connections: {} (accepted {}, closed {})",
+            style(tcp_proxy.active_connections).green(),
+            tcp_proxy.accepted,
+            tcp_proxy.closed
+        );
+        println!("  Up: {}", style(convert(tcp_proxy.up)).green());
+        println!("  Down: {}", style(convert(tcp_proxy.down)).green());
+    }
 }
 
 fn convert(num: u64) -> String {
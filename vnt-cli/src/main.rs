@@ -1,8 +1,10 @@
 use anyhow::anyhow;
+use std::ffi::OsString;
 use std::io;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::OnceLock;
 
 use console::style;
 use getopts::Options;
@@ -12,7 +14,7 @@ use vnt::channel::punch::PunchModel;
 use vnt::channel::UseChannelType;
 use vnt::cipher::CipherModel;
 use vnt::compression::Compressor;
-use vnt::core::{Config, Vnt};
+use vnt::core::{Config, Vnt, DEFAULT_SERVER_ADDRESS, DEFAULT_STUN_SERVERS};
 
 #[cfg(feature = "command")]
 mod command;
@@ -22,8 +24,48 @@ mod console_out;
 mod generated_serial_number;
 mod root_check;
 
+/// 设置数据目录的环境变量
+const HOME_ENV: &str = "VNT_HOME";
+/// 命令行参数--home指定的数据目录
+static HOME_FLAG: OnceLock<PathBuf> = OnceLock::new();
+
+/// 数据目录，device-id、command-port、log4rs.yaml等状态都放在这里，优先级：
+/// 1. 命令行参数--home
+/// 2. 环境变量VNT_HOME，值为空时忽略
+/// 3. 程序所在目录下的env目录
+///
+/// 目录不存在时创建，创建失败返回带目录路径的错误
 pub fn app_home() -> io::Result<PathBuf> {
-    let root_path = match std::env::current_exe() {
+    let path = resolve_app_home(HOME_FLAG.get().cloned(), std::env::var_os(HOME_ENV));
+    if !path.exists() {
+        std::fs::create_dir_all(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("create app home {:?} failed: {}", path, e),
+            )
+        })?;
+    }
+    Ok(path)
+}
+
+/// 是否通过--home或者VNT_HOME指定了数据目录
+#[cfg(feature = "log")]
+fn app_home_overridden() -> bool {
+    HOME_FLAG.get().is_some() || std::env::var_os(HOME_ENV).is_some_and(|v| !v.is_empty())
+}
+
+fn resolve_app_home(flag: Option<PathBuf>, env: Option<OsString>) -> PathBuf {
+    if let Some(path) = flag {
+        return path;
+    }
+    if let Some(path) = env.filter(|v| !v.is_empty()) {
+        return PathBuf::from(path);
+    }
+    exe_dir().join("env")
+}
+
+fn exe_dir() -> PathBuf {
+    match std::env::current_exe() {
         Ok(path) => {
             if let Some(v) = path.as_path().parent() {
                 v.to_path_buf()
@@ -36,17 +78,27 @@ pub fn app_home() -> io::Result<PathBuf> {
             log::warn!("current_exe err:{:?}", e);
             PathBuf::new()
         }
-    };
-    let path = root_path.join("env");
-    if !path.exists() {
-        std::fs::create_dir_all(&path)?;
     }
-    Ok(path)
+}
+
+/// 指定了数据目录并且目录下有log4rs.yaml时使用它，否则使用当前目录下的log4rs.yaml
+#[cfg(feature = "log")]
+fn log_config() -> PathBuf {
+    if app_home_overridden() {
+        match app_home() {
+            Ok(home) => {
+                let path = home.join("log4rs.yaml");
+                if path.exists() {
+                    return path;
+                }
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+    PathBuf::from("log4rs.yaml")
 }
 
 fn main() {
-    #[cfg(feature = "log")]
-    let _ = log4rs::init_file("log4rs.yaml", Default::default());
     let args: Vec<String> = std::env::args().collect();
     let program = args[0].clone();
     let mut opts = Options::new();
@@ -80,7 +132,10 @@ fn main() {
     opts.optmulti("", "dns", "dns", "<dns>");
     opts.optmulti("", "mapping", "mapping", "<mapping>");
     opts.optopt("f", "", "配置文件", "<conf>");
+    opts.optopt("", "config-format", "配置文件格式yaml/toml", "<toml>");
+    opts.optflag("", "check", "只检查配置文件");
     opts.optopt("", "compressor", "压缩算法", "<lz4>");
+    opts.optopt("", "home", "数据目录", "<dir>");
     //"后台运行时,查看其他设备列表"
     opts.optflag("", "list", "后台运行时,查看其他设备列表");
     opts.optflag("", "all", "后台运行时,查看其他设备完整信息");
@@ -96,10 +151,42 @@ fn main() {
             return;
         }
     };
+    if let Some(home) = matches.opt_str("home") {
+        let _ = HOME_FLAG.set(PathBuf::from(home));
+    }
+    #[cfg(feature = "log")]
+    let _ = log4rs::init_file(log_config(), Default::default());
     if matches.opt_present("h") || args.len() == 1 {
         print_usage(&program, opts);
         return;
     }
+    // 检查配置不需要管理员权限
+    if matches.opt_present("check") {
+        let Some(conf) = matches.opt_str("f") else {
+            println!("'--check' requires '-f <conf>'");
+            std::process::exit(1);
+        };
+        let format = match matches.opt_get::<config::ConfigFormat>("config-format") {
+            Ok(format) => format,
+            Err(e) => {
+                println!("'--config-format' invalid,{}", e);
+                std::process::exit(1);
+            }
+        };
+        match config::check_config(&conf, format) {
+            Ok(report) => {
+                println!("{}", report);
+                if !report.is_ok() {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                println!("conf err {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
     if !root_check::is_app_elevated() {
         println!("Please run it with administrator or root privileges");
         #[cfg(any(target_os = "linux", target_os = "macos"))]
@@ -124,16 +211,33 @@ fn main() {
         return;
     }
     let conf = matches.opt_str("f");
-    let (config, cmd) = if conf.is_some() {
-        match config::read_config(&conf.unwrap()) {
-            Ok(c) => c,
+    let (config, cmd, watcher) = if let Some(conf) = conf {
+        let format = match matches.opt_get::<config::ConfigFormat>("config-format") {
+            Ok(format) => format,
+            Err(e) => {
+                println!("'--config-format' invalid,{}", e);
+                return;
+            }
+        };
+        match config::read_config(&conf, format) {
+            Ok((config, cmd)) => {
+                let watcher = config::ConfigWatcher::new(conf, format, config.clone());
+                (config, cmd, Some(watcher))
+            }
             Err(e) => {
                 println!("conf err {}", e);
                 return;
             }
         }
     } else {
-        if !matches.opt_present("k") {
+        let env = match config::env_config() {
+            Ok(env) => env,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        if !matches.opt_present("k") && env.token.is_none() {
             print_usage(&program, opts);
             println!("parameter -k not found .");
             return;
@@ -141,30 +245,34 @@ fn main() {
         #[cfg(target_os = "windows")]
         let tap = matches.opt_present("a");
         let device_name = matches.opt_str("nic");
-        let token: String = matches.opt_get("k").unwrap().unwrap();
-        let device_id = matches.opt_get_default("d", String::new()).unwrap();
-        let device_id = if device_id.is_empty() {
-            config::get_device_id()
-        } else {
-            device_id
+        let token: String = matches.opt_str("k").or(env.token).unwrap();
+        let device_id_strategy =
+            config::DeviceIdStrategy::new(env.device_id_seed, env.device_id_dir);
+        let device_id = match config::resolve_device_id(
+            matches.opt_str("d").as_deref(),
+            env.device_id.as_deref(),
+            None,
+            &device_id_strategy,
+        ) {
+            Ok(device_id) => device_id.value,
+            Err(e) => {
+                print_usage(&program, opts);
+                println!("parameter -d not found ,{}", e);
+                return;
+            }
         };
-        if device_id.is_empty() {
-            print_usage(&program, opts);
-            println!("parameter -d not found .");
-            return;
-        }
         let name = matches
-            .opt_get_default("n", os_info::get().to_string())
-            .unwrap();
+            .opt_str("n")
+            .or(env.name)
+            .unwrap_or_else(|| os_info::get().to_string());
         let server_address_str = matches
-            .opt_get_default("s", "nat1.wherewego.top:29872".to_string())
-            .unwrap();
+            .opt_str("s")
+            .or(env.server_address)
+            .unwrap_or_else(|| DEFAULT_SERVER_ADDRESS.to_string());
 
         let mut stun_server = matches.opt_strs("e");
         if stun_server.is_empty() {
-            stun_server.push("stun1.l.google.com:19302".to_string());
-            stun_server.push("stun2.l.google.com:19302".to_string());
-            stun_server.push("stun.miwifi.com:3478".to_string());
+            stun_server = DEFAULT_STUN_SERVERS.iter().map(|s| s.to_string()).collect();
         }
         let dns = matches.opt_strs("dns");
         let in_ip = matches.opt_strs("i");
@@ -189,7 +297,7 @@ fn main() {
                 return;
             }
         };
-        let password: Option<String> = matches.opt_get("w").unwrap();
+        let password: Option<String> = matches.opt_str("w").or(env.password);
         let server_encrypt = matches.opt_present("W");
         #[cfg(not(feature = "server_encrypt"))]
         {
@@ -210,11 +318,12 @@ fn main() {
                 }
             }
         } else {
-            None
+            env.mtu
         };
         let virtual_ip: Option<String> = matches.opt_get("ip").unwrap();
-        let virtual_ip =
-            virtual_ip.map(|v| Ipv4Addr::from_str(&v).expect(&format!("'--ip {}' error", v)));
+        let virtual_ip = virtual_ip
+            .map(|v| Ipv4Addr::from_str(&v).expect(&format!("'--ip {}' error", v)))
+            .or(env.ip);
         if let Some(virtual_ip) = virtual_ip {
             if virtual_ip.is_unspecified() || virtual_ip.is_broadcast() || virtual_ip.is_multicast()
             {
@@ -308,6 +417,8 @@ fn main() {
             virtual_ip,
             #[cfg(feature = "ip_proxy")]
             no_proxy,
+            #[cfg(feature = "ip_proxy")]
+            vnt::ip_proxy::ProxyConfig::default(),
             server_encrypt,
             parallel,
             cipher_model,
@@ -329,7 +440,13 @@ fn main() {
                 return;
             }
         };
-        (config, cmd)
+        if let Err(errors) = config::validate_config(&config) {
+            for e in errors {
+                println!("config error {}", e);
+            }
+            return;
+        }
+        (config, cmd, None)
     };
     println!("version {}", vnt::VNT_VERSION);
     println!("Serial:{}", generated_serial_number::SERIAL_NUMBER);
@@ -338,13 +455,50 @@ fn main() {
         vnt::VNT_VERSION,
         generated_serial_number::SERIAL_NUMBER
     );
-    main0(config, cmd);
+    main0(config, cmd, watcher);
     std::process::exit(0);
 }
 
 mod callback;
 
-fn main0(config: Config, _show_cmd: bool) {
+/// 重新读取配置文件，应用可以直接生效的字段，需要重启的只提示
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn reload_config(watcher: &mut config::ConfigWatcher, vnt: &Vnt) {
+    let (config, diff) = match watcher.reload() {
+        Ok(rs) => rs,
+        Err(e) => {
+            println!("reload config error {}", e);
+            log::warn!("重新加载配置失败:{:?}", e);
+            return;
+        }
+    };
+    if diff.is_empty() {
+        log::info!("重新加载配置，没有变化");
+        return;
+    }
+    if diff.hot.contains(&"out_ips") {
+        vnt.set_out_ips(config.out_ips.clone());
+    }
+    #[cfg(feature = "ip_proxy")]
+    {
+        if diff.hot.contains(&"proxy_port_filter") {
+            vnt.set_proxy_port_filter(config.proxy_config.tcp_port_filter.clone());
+        }
+        if diff.hot.contains(&"proxy_rate_limit") {
+            vnt.set_proxy_rate_limit(config.proxy_config.tcp_rate_limit);
+        }
+    }
+    if !diff.hot.is_empty() {
+        println!("reload config {:?}", diff.hot);
+        log::info!("重新加载配置，已生效:{:?}", diff.hot);
+    }
+    if !diff.restart.is_empty() {
+        println!("restart required {:?}", diff.restart);
+        log::warn!("以下配置需要重启才能生效:{:?}", diff.restart);
+    }
+}
+
+fn main0(config: Config, _show_cmd: bool, _watcher: Option<config::ConfigWatcher>) {
     #[cfg(feature = "port_mapping")]
     for (is_tcp, addr, dest) in config.port_mapping_list.iter() {
         if *is_tcp {
@@ -357,11 +511,13 @@ fn main0(config: Config, _show_cmd: bool) {
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     {
         let vnt_c = vnt_util.clone();
-        let mut signals = signal_hook::iterator::Signals::new(&[
-            signal_hook::consts::SIGINT,
-            signal_hook::consts::SIGTERM,
-        ])
-        .unwrap();
+        let mut watcher = _watcher;
+        let mut sigs = vec![signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM];
+        // 使用配置文件时SIGHUP重新加载配置，否则保持默认行为
+        if watcher.is_some() {
+            sigs.push(signal_hook::consts::SIGHUP);
+        }
+        let mut signals = signal_hook::iterator::Signals::new(&sigs).unwrap();
         let handle = signals.handle();
         std::thread::spawn(move || {
             for sig in signals.forever() {
@@ -372,6 +528,11 @@ fn main0(config: Config, _show_cmd: bool) {
                         handle.close();
                         break;
                     }
+                    signal_hook::consts::SIGHUP => {
+                        if let Some(watcher) = watcher.as_mut() {
+                            reload_config(watcher, &vnt_c);
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -470,6 +631,8 @@ fn print_usage(program: &str, _opts: Options) {
     println!("  -u <mtu>            自定义mtu(不加密默认为1450，加密默认为1410)");
     #[cfg(feature = "file_config")]
     println!("  -f <conf_file>      读取配置文件中的配置");
+    #[cfg(feature = "file_config")]
+    println!("  --check             和-f一起使用,只检查配置文件,有错误时退出码为1");
 
     println!("  --tcp               和服务端使用tcp通信,默认使用udp,遇到udp qos时可指定使用tcp");
     println!("  --ip <ip>           指定虚拟ip,指定的ip不能和其他设备重复,必须有效并且在服务端所属网段下,默认情况由服务端分配");
@@ -506,6 +669,7 @@ fn print_usage(program: &str, _opts: Options) {
     #[cfg(feature = "ip_proxy")]
     println!("  --no-proxy          关闭内置代理,如需点对网则需要配置网卡NAT转发");
     println!("  --first-latency     优先低延迟的通道,默认情况优先使用p2p通道");
+    println!("  --home <dir>        数据目录,保存device-id等状态,也可以用环境变量VNT_HOME指定,默认为程序所在目录下的env");
     println!("  --use-channel <p2p> 使用通道 relay/p2p/all,默认两者都使用");
     println!("  --nic <tun0>        指定虚拟网卡名称");
     println!("  --packet-loss <0>   模拟丢包,取值0~1之间的小数,程序会按设定的概率主动丢包,可用于模拟弱网");
@@ -558,3 +722,29 @@ fn green(str: String) -> impl std::fmt::Display {
 fn yellow(str: String) -> impl std::fmt::Display {
     style(str).yellow()
 }
+
+#[test]
+fn test_app_home() {
+    let exe_home = exe_dir().join("env");
+    assert_eq!(resolve_app_home(None, None), exe_home);
+    assert_eq!(resolve_app_home(None, Some(OsString::new())), exe_home);
+    assert_eq!(
+        resolve_app_home(Some(PathBuf::from("/a")), Some(OsString::from("/b"))),
+        PathBuf::from("/a")
+    );
+    let dir = std::env::temp_dir().join(format!("vnt-home-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let home = dir.join("home");
+    std::env::set_var(HOME_ENV, &home);
+    let rs = app_home();
+    std::env::remove_var(HOME_ENV);
+    assert_eq!(rs.unwrap(), home);
+    assert!(home.is_dir());
+    // 父路径是文件时无法创建，错误信息里带上目录
+    std::fs::write(dir.join("file"), b"").unwrap();
+    std::env::set_var(HOME_ENV, dir.join("file").join("home"));
+    let rs = app_home();
+    std::env::remove_var(HOME_ENV);
+    assert!(rs.unwrap_err().to_string().contains("create app home"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -106,6 +106,7 @@ pub fn new_config(env: &mut JNIEnv, config: JObject) -> Result<Config, Error> {
         tcp,
         ip,
         false,
+        vnt::ip_proxy::ProxyConfig::default(),
         server_encrypt,
         1,
         cipher_model,
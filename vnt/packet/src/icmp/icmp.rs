@@ -39,6 +39,10 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> IcmpPacket<B> {
     pub fn set_kind(&mut self, kind: Kind) {
         self.buffer.as_mut()[0] = kind.into();
     }
+    /// 设置Identifier，只对带Identifier的类型有意义，之后需要更新校验和
+    pub fn set_identifier(&mut self, id: u16) {
+        self.buffer.as_mut()[4..6].copy_from_slice(&id.to_be_bytes());
+    }
     pub fn update_checksum(&mut self) {
         self.buffer.as_mut()[2..4].copy_from_slice(&[0, 0]);
         let checksum = cal_checksum(self.buffer.as_ref());
@@ -100,7 +104,7 @@ impl<B: AsRef<[u8]>> IcmpPacket<B> {
                 Ok(d) => Description::Ip(d),
                 Err(_) => Description::Other(self.payload()),
             },
-            Kind::TimestampRequest | Kind::TimestampReply => {
+            Kind::TimestampRequest | Kind::TimestampReply if self.payload().len() >= 12 => {
                 let mut buffer = Cursor::new(self.payload());
 
                 Description::Timestamp(
@@ -1,8 +1,5 @@
 use std::net::Ipv4Addr;
 
-use byteorder::BigEndian;
-use byteorder::ReadBytesExt;
-
 pub mod arp;
 pub mod ethernet;
 pub mod icmp;
@@ -69,17 +66,7 @@ unsigned short getChecksum(unsigned short * iphead, int count)
 }
  */
 pub fn cal_checksum(buffer: &[u8]) -> u16 {
-    use std::io::Cursor;
-    let mut sum = 0;
-    let length = buffer.len();
-    let mut buffer = Cursor::new(buffer);
-    while let Ok(value) = buffer.read_u16::<BigEndian>() {
-        sum += u32::from(value);
-    }
-    if length & 1 == 1 {
-        //奇数,说明还有一位,不足的补0
-        sum += u32c(buffer.read_u8().unwrap(), 0);
-    }
+    let mut sum = sum_u16(buffer);
     while sum >> 16 != 0 {
         sum = (sum & 0xffff) + (sum >> 16);
     }
@@ -104,7 +91,6 @@ pub fn ipv4_cal_checksum(
     dest_ip: &Ipv4Addr,
     protocol: u8,
 ) -> u16 {
-    use std::io::Cursor;
     let length = buffer.len();
     let mut sum = 0;
     let src_ip = src_ip.octets();
@@ -115,20 +101,26 @@ pub fn ipv4_cal_checksum(
     sum += u32c(dest_ip[2], dest_ip[3]);
     sum += u32c(0, protocol);
     sum += length as u32;
-    let mut buffer = Cursor::new(buffer);
-    while let Ok(value) = buffer.read_u16::<BigEndian>() {
-        sum += u32::from(value);
-    }
-    if length & 1 == 1 {
-        //奇数,说明还有一位
-        sum += u32c(buffer.read_u8().unwrap(), 0);
-    }
+    sum += sum_u16(buffer);
     while sum >> 16 != 0 {
         sum = (sum & 0xffff) + (sum >> 16);
     }
     !sum as u16
 }
 
+/// 按16位累加，长度是奇数时最后一个字节后面补0
+fn sum_u16(buffer: &[u8]) -> u32 {
+    let mut chunks = buffer.chunks_exact(2);
+    let mut sum = 0;
+    for chunk in &mut chunks {
+        sum += u32c(chunk[0], chunk[1]);
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32c(*last, 0);
+    }
+    sum
+}
+
 #[inline]
 fn u32c(x: u8, y: u8) -> u32 {
     ((x as u32) << 8) | y as u32
@@ -143,4 +135,10 @@ mod tests {
         let sum = cal_checksum(&[255, 255]);
         println!("{:?}", sum);
     }
+
+    #[test]
+    fn odd_length() {
+        assert_eq!(cal_checksum(&[0x12, 0x34, 0x56]), !0x6834);
+        assert_eq!(cal_checksum(&[0x12, 0x34, 0x56, 0x00]), !0x6834);
+    }
 }
@@ -11,6 +11,13 @@ pub const PSH: u8 = 0b0000_1000;
 pub const ACK: u8 = 0b0001_0000;
 pub const URG: u8 = 0b0010_0000;
 
+impl Flags {
+    /// 是否设置了flag，例如 flags.contains(SYN)
+    pub fn contains(&self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+}
+
 impl fmt::Debug for Flags {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut str = String::with_capacity(22);
@@ -69,7 +69,10 @@ impl<B: AsRef<[u8]>> TcpPacket<B> {
             Err(io::Error::from(io::ErrorKind::InvalidData))?;
         }
 
-        if packet.buffer.as_ref().len() < packet.data_offset() as usize * 4 {
+        // 数据偏移包含选项，最小是5(20字节)
+        if packet.data_offset() < 5
+            || packet.buffer.as_ref().len() < packet.data_offset() as usize * 4
+        {
             Err(io::Error::from(io::ErrorKind::InvalidData))?;
         }
 
@@ -94,6 +97,16 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> TcpPacket<B> {
     pub fn set_destination_port(&mut self, value: u16) {
         self.buffer.as_mut()[2..4].copy_from_slice(&value.to_be_bytes())
     }
+    /// MSS选项大于max时改成max，返回是否修改，修改后需要更新校验和
+    pub fn clamp_mss(&mut self, max: u16) -> bool {
+        match self.mss_offset() {
+            Some(offset) if self.mss() > Some(max) => {
+                self.buffer.as_mut()[offset..offset + 2].copy_from_slice(&max.to_be_bytes());
+                true
+            }
+            _ => false,
+        }
+    }
     /// 更新校验和
     pub fn update_checksum(&mut self) {
         //先将校验和置0
@@ -156,6 +169,36 @@ impl<B: AsRef<[u8]>> TcpPacket<B> {
     pub fn payload(&self) -> &[u8] {
         &self.buffer.as_ref()[(self.data_offset() as usize * 4)..]
     }
+    /// MSS选项的值，只有SYN包会带
+    pub fn mss(&self) -> Option<u16> {
+        let offset = self.mss_offset()?;
+        let buf = self.buffer.as_ref();
+        Some(u16::from_be_bytes([buf[offset], buf[offset + 1]]))
+    }
+    /// MSS选项的值在buffer中的位置
+    fn mss_offset(&self) -> Option<usize> {
+        let options = self.options();
+        let mut i = 0;
+        while i < options.len() {
+            match options[i] {
+                // End of Option List
+                0 => return None,
+                // No-Operation
+                1 => i += 1,
+                kind => {
+                    let len = *options.get(i + 1)? as usize;
+                    if len < 2 || i + len > options.len() {
+                        return None;
+                    }
+                    if kind == 2 && len == 4 {
+                        return Some(20 + i + 2);
+                    }
+                    i += len;
+                }
+            }
+        }
+        None
+    }
 }
 
 impl<B: AsRef<[u8]>> fmt::Debug for TcpPacket<B> {
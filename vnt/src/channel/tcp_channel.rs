@@ -10,6 +10,7 @@ use std::os::windows::io::FromRawSocket;
 #[cfg(windows)]
 use std::os::windows::io::IntoRawSocket;
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
 use mio::net::{TcpListener, TcpStream};
@@ -20,10 +21,29 @@ use crate::channel::handler::RecvChannelHandler;
 use crate::channel::notify::{AcceptNotify, WritableNotify};
 use crate::channel::sender::{AcceptSocketSender, PacketSender};
 use crate::channel::{RouteKey, BUFFER_SIZE};
-use crate::util::StopManager;
+use crate::util::{is_fd_exhausted, StopManager};
 
 const SERVER: Token = Token(0);
 const NOTIFY: Token = Token(1);
+/// 文件描述符用完时暂停accept的时间
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// 分配tcp连接的Token，和fd无关，不会和SERVER、NOTIFY冲突。
+/// 读写两个线程共用同一个Token，一端关闭时另一端可能还没有处理完，所以Token不回收复用
+struct TokenAllocator {
+    next: usize,
+}
+
+impl TokenAllocator {
+    fn new() -> Self {
+        Self { next: NOTIFY.0 + 1 }
+    }
+    fn alloc(&mut self) -> Token {
+        let token = Token(self.next);
+        self.next += 1;
+        token
+    }
+}
 
 /// 监听tcp端口，等待客户端连接
 pub fn tcp_listen<H>(
@@ -88,17 +108,28 @@ where
     let mut read_map: HashMap<Token, (RouteKey, TcpStream, Box<[u8; BUFFER_SIZE]>, usize)> =
         HashMap::with_capacity(32);
     let mut extend = [0; BUFFER_SIZE];
+    let mut tokens = TokenAllocator::new();
+    // 暂停accept时恢复的时间
+    let mut accept_resume: Option<Instant> = None;
     loop {
-        poll.poll(&mut events, None)?;
+        let timeout = accept_resume.map(|resume| resume.saturating_duration_since(Instant::now()));
+        poll.poll(&mut events, timeout)?;
+        if let Some(resume) = accept_resume {
+            if Instant::now() >= resume {
+                accept_resume = None;
+                // 注册时backlog里还有连接会立即产生可读事件
+                poll.registry()
+                    .register(&mut tcp_server, SERVER, Interest::READABLE)?;
+            }
+        }
         for event in events.iter() {
             match event.token() {
                 SERVER => loop {
                     match tcp_server.accept() {
                         Ok((stream, addr)) => {
                             accept_handle(
-                                stream,
-                                addr,
-                                None,
+                                (stream, addr, None),
+                                tokens.alloc(),
                                 &write_waker,
                                 &mut read_map,
                                 &tcp_sender,
@@ -109,6 +140,15 @@ where
                             if e.kind() == io::ErrorKind::WouldBlock {
                                 break;
                             }
+                            if is_fd_exhausted(&e) {
+                                // 描述符用完时不能退出，等其他连接关闭后还要继续服务。
+                                // 也不能只是break：边缘触发下不会再收到可读事件，backlog里的连接一直得不到处理；
+                                // 所以先注销监听socket，过ACCEPT_BACKOFF后重新注册再accept，期间新连接留在backlog里
+                                log::warn!("tcp accept err={:?},{:?}后重试", e, ACCEPT_BACKOFF);
+                                poll.registry().deregister(&mut tcp_server)?;
+                                accept_resume = Some(Instant::now() + ACCEPT_BACKOFF);
+                                break;
+                            }
                             return Err(e)?;
                         }
                     }
@@ -118,11 +158,10 @@ where
                         return Ok(());
                     }
                     if accept_notify.is_add_socket() {
-                        while let Ok((stream, addr, init_buf)) = accept_tcp_receiver.try_recv() {
+                        while let Ok(socket) = accept_tcp_receiver.try_recv() {
                             accept_handle(
-                                stream,
-                                addr,
-                                init_buf,
+                                socket,
+                                tokens.alloc(),
                                 &write_waker,
                                 &mut read_map,
                                 &tcp_sender,
@@ -242,6 +281,8 @@ fn tcp_writable_listen(
                                     .register(&mut stream, token, Interest::WRITABLE)
                             {
                                 log::warn!("registry err={:?}", e);
+                                // 读的一端已经注册，关闭连接让读线程收到事件后清理
+                                let _ = stream.shutdown(Shutdown::Both);
                                 continue;
                             }
                             let (sender, receiver) = sync_channel(128);
@@ -271,66 +312,59 @@ fn tcp_writable_listen(
     }
 }
 
+/// socket是(连接,对端地址,连接后先发送的数据)，和AcceptSocketSender发送的一致
 fn accept_handle(
-    stream: TcpStream,
-    addr: SocketAddr,
-    init_buf: Option<Vec<u8>>,
+    (stream, addr, init_buf): (TcpStream, SocketAddr, Option<Vec<u8>>),
+    token: Token,
     write_waker: &WritableNotify,
     read_map: &mut HashMap<Token, (RouteKey, TcpStream, Box<[u8; BUFFER_SIZE]>, usize)>,
     tcp_sender: &SyncSender<(TcpStream, Token, SocketAddr, Option<Vec<u8>>)>,
     registry: &Registry,
 ) -> io::Result<()> {
+    // 转成std的TcpStream，用来复制出写的一端
     #[cfg(windows)]
-    let (tcp_stream, index) = unsafe {
-        let fd = stream.into_raw_socket();
-        (std::net::TcpStream::from_raw_socket(fd), fd as usize)
-    };
+    let tcp_stream = unsafe { std::net::TcpStream::from_raw_socket(stream.into_raw_socket()) };
     #[cfg(any(unix))]
-    let (tcp_stream, index) = unsafe {
-        let fd = stream.into_raw_fd();
-        (std::net::TcpStream::from_raw_fd(fd), fd as usize)
-    };
-    if index == 0 || index == 1 {
-        log::error!("index err={:?}", addr);
-        return Ok(());
-    }
-    let token = Token(index);
-    match tcp_stream.try_clone() {
-        Ok(tcp_writer) => {
-            match tcp_sender.try_send((TcpStream::from_std(tcp_writer), token, addr, init_buf)) {
-                Ok(_) => {
-                    if let Err(e) = write_waker.add_socket() {
-                        log::error!("write_waker,err={:?},addr={:?}", e, addr);
-                        return Ok(());
-                    }
-                }
-                Err(e) => {
-                    return match e {
-                        TrySendError::Full(_) => {
-                            log::error!("Full,addr={:?}", addr);
-                            Ok(())
-                        }
-                        TrySendError::Disconnected(_) => {
-                            Err(io::Error::new(io::ErrorKind::Other, "write thread exit"))
-                        }
-                    };
-                }
-            }
-        }
+    let tcp_stream = unsafe { std::net::TcpStream::from_raw_fd(stream.into_raw_fd()) };
+    let tcp_writer = match tcp_stream.try_clone() {
+        Ok(tcp_writer) => tcp_writer,
         Err(e) => {
             log::error!("try_clone err={:?},addr={:?}", e, addr);
             return Ok(());
         }
-    }
+    };
+    // 先注册读的一端，失败时写的一端还没有交给写线程，两端直接drop关闭
     let mut stream = TcpStream::from_std(tcp_stream);
     if let Err(e) = registry.register(&mut stream, token, Interest::READABLE) {
         log::error!("registry err={:?},addr={:?}", e, addr);
         return Ok(());
     }
+    match tcp_sender.try_send((TcpStream::from_std(tcp_writer), token, addr, init_buf)) {
+        Ok(_) => {
+            if let Err(e) = write_waker.add_socket() {
+                log::error!("write_waker,err={:?},addr={:?}", e, addr);
+                let _ = registry.deregister(&mut stream);
+                return Ok(());
+            }
+        }
+        Err(e) => {
+            // 写的一端没有发出去，读的一端也注销后关闭
+            let _ = registry.deregister(&mut stream);
+            return match e {
+                TrySendError::Full(_) => {
+                    log::error!("Full,addr={:?}", addr);
+                    Ok(())
+                }
+                TrySendError::Disconnected(_) => {
+                    Err(io::Error::new(io::ErrorKind::Other, "write thread exit"))
+                }
+            };
+        }
+    }
     read_map.insert(
         token,
         (
-            RouteKey::new(true, index, addr),
+            RouteKey::new(true, token.0, addr),
             stream,
             Box::new([0; BUFFER_SIZE]),
             0,
@@ -460,3 +494,75 @@ fn closed_handle_w(
         let _ = tcp.shutdown(Shutdown::Both);
     }
 }
+
+#[test]
+fn test_accept_handle_token() {
+    let poll = Poll::new().unwrap();
+    let write_poll = Poll::new().unwrap();
+    let write_waker = WritableNotify::new(Waker::new(write_poll.registry(), NOTIFY).unwrap());
+    let (tcp_sender, tcp_receiver) = sync_channel(4);
+    let mut read_map = HashMap::new();
+    let mut tokens = TokenAllocator::new();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut accepted = Vec::new();
+    for _ in 0..3 {
+        let client = std::net::TcpStream::connect(addr).unwrap();
+        let (stream, peer_addr) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let token = tokens.alloc();
+        // 和fd无关，不会用到保留的Token，连接也不会被丢弃
+        assert!(token != SERVER && token != NOTIFY);
+        accept_handle(
+            (TcpStream::from_std(stream), peer_addr, None),
+            token,
+            &write_waker,
+            &mut read_map,
+            &tcp_sender,
+            poll.registry(),
+        )
+        .unwrap();
+        assert!(read_map.contains_key(&token));
+        let (_, write_token, write_addr, _) = tcp_receiver.try_recv().unwrap();
+        assert_eq!((write_token, write_addr), (token, peer_addr));
+        accepted.push((client, token));
+    }
+    let tokens: std::collections::HashSet<_> = accepted.iter().map(|(_, t)| *t).collect();
+    assert_eq!(tokens.len(), 3);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_accept_handle_register_failed() {
+    let poll = Poll::new().unwrap();
+    let write_poll = Poll::new().unwrap();
+    let write_waker = WritableNotify::new(Waker::new(write_poll.registry(), NOTIFY).unwrap());
+    let (tcp_sender, tcp_receiver) = sync_channel(4);
+    let mut read_map = HashMap::new();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, peer_addr) = listener.accept().unwrap();
+    stream.set_nonblocking(true).unwrap();
+    let mut stream = TcpStream::from_std(stream);
+    // 已经注册过的socket再注册会失败
+    poll.registry()
+        .register(&mut stream, Token(100), Interest::READABLE)
+        .unwrap();
+    let token = TokenAllocator::new().alloc();
+    accept_handle(
+        (stream, peer_addr, None),
+        token,
+        &write_waker,
+        &mut read_map,
+        &tcp_sender,
+        poll.registry(),
+    )
+    .unwrap();
+    // 两端都没有留下，连接已经关闭
+    assert!(read_map.is_empty());
+    assert!(tcp_receiver.try_recv().is_err());
+    client
+        .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+        .unwrap();
+    assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+}
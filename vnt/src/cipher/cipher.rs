@@ -329,3 +329,35 @@ impl Cipher {
         }
     }
 }
+
+#[cfg(all(feature = "aes_gcm", feature = "chacha20_poly1305"))]
+#[test]
+fn test_cipher_model_round_trip() {
+    let packet = || {
+        let mut p =
+            NetPacket::new_encrypt([0; 100 + crate::protocol::body::ENCRYPTION_RESERVED]).unwrap();
+        p.set_payload_len(100).unwrap();
+        p.payload_mut().fill(7);
+        p
+    };
+    let new_cipher =
+        |model| Cipher::new_password(model, Some("password".into()), Some("token".into()));
+    for model in [CipherModel::AesGcm, CipherModel::Chacha20Poly1305] {
+        // 发送端和接收端分别创建，同样的密码和算法可以互通
+        let (sender, receiver) = (new_cipher(model), new_cipher(model));
+        let mut p = packet();
+        let src = p.payload().to_vec();
+        sender.encrypt_ipv4(&mut p).unwrap();
+        assert_ne!(p.payload(), &src[..]);
+        receiver.decrypt_ipv4(&mut p).unwrap();
+        assert_eq!(p.payload(), &src[..]);
+    }
+    // 算法不同时解密失败
+    let mut p = packet();
+    new_cipher(CipherModel::Chacha20Poly1305)
+        .encrypt_ipv4(&mut p)
+        .unwrap();
+    assert!(new_cipher(CipherModel::AesGcm)
+        .decrypt_ipv4(&mut p)
+        .is_err());
+}
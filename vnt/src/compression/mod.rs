@@ -17,6 +17,9 @@ mod lz4_compress;
 #[cfg(feature = "zstd_compress")]
 mod zstd_compress;
 
+/// 默认的压缩阈值，数据长度小于这个值时不压缩
+pub const DEFAULT_COMPRESS_THRESHOLD: usize = 128;
+
 #[derive(Clone, Copy, Debug)]
 pub enum Compressor {
     #[cfg(feature = "lz4_compress")]
@@ -65,6 +68,7 @@ impl FromStr for Compressor {
 impl Compressor {
     pub fn compress<I: AsRef<[u8]>, O: AsRef<[u8]> + AsMut<[u8]>>(
         &self,
+        _threshold: usize,
         _in_net_packet: &NetPacket<I>,
         _out: &mut NetPacket<O>,
     ) -> anyhow::Result<bool> {
@@ -81,36 +85,38 @@ impl Compressor {
 
 #[cfg(any(feature = "lz4_compress", feature = "zstd_compress"))]
 impl Compressor {
+    /// 数据长度不小于threshold时压缩，压缩后没有变小则返回false，使用原始数据
     pub fn compress<I: AsRef<[u8]>, O: AsRef<[u8]> + AsMut<[u8]>>(
         &self,
+        threshold: usize,
         in_net_packet: &NetPacket<I>,
         out: &mut NetPacket<O>,
     ) -> anyhow::Result<bool> {
         match self {
             #[cfg(feature = "lz4_compress")]
             Compressor::Lz4 => {
-                if in_net_packet.data_len() < 128 {
+                if in_net_packet.data_len() < threshold {
                     return Ok(false);
                 }
                 Lz4Compressor::compress(in_net_packet, out)?;
                 let mut compression_extension_tail = out.append_compression_extension_tail()?;
                 compression_extension_tail.set_algorithm(CompressionAlgorithm::Lz4);
                 //压缩没效果，则放弃压缩
-                if out.data_len() >= in_net_packet.data_len() - 16 {
+                if out.data_len() + 16 >= in_net_packet.data_len() {
                     return Ok(false);
                 }
                 return Ok(true);
             }
             #[cfg(feature = "zstd_compress")]
             Compressor::Zstd(level) => {
-                if in_net_packet.data_len() < 128 {
+                if in_net_packet.data_len() < threshold {
                     return Ok(false);
                 }
                 ZstdCompressor::compress(*level, in_net_packet, out)?;
                 let mut compression_extension_tail = out.append_compression_extension_tail()?;
                 compression_extension_tail.set_algorithm(CompressionAlgorithm::Zstd);
                 //压缩没效果，则放弃压缩
-                if out.data_len() >= in_net_packet.data_len() - 16 {
+                if out.data_len() + 16 >= in_net_packet.data_len() {
                     return Ok(false);
                 }
                 return Ok(true);
@@ -154,7 +160,8 @@ fn test_lz4() {
     .unwrap();
     let mut out_packet = NetPacket::new([0; 1000]).unwrap();
     let mut src_out_packet = NetPacket::new([0; 1000]).unwrap();
-    lz4.compress(&in_packet, &mut out_packet).unwrap();
+    lz4.compress(DEFAULT_COMPRESS_THRESHOLD, &in_packet, &mut out_packet)
+        .unwrap();
     let tail = out_packet.split_tail_packet().unwrap();
     match tail {
         ExtensionTailPacket::Compression(c) => match c.algorithm() {
@@ -193,7 +200,8 @@ fn test_zstd() {
     .unwrap();
     let mut out_packet = NetPacket::new([0; 1000]).unwrap();
     let mut src_out_packet = NetPacket::new([0; 1000]).unwrap();
-    zstd.compress(&in_packet, &mut out_packet).unwrap();
+    zstd.compress(DEFAULT_COMPRESS_THRESHOLD, &in_packet, &mut out_packet)
+        .unwrap();
     let tail = out_packet.split_tail_packet().unwrap();
     match tail {
         ExtensionTailPacket::Compression(c) => match c.algorithm() {
@@ -216,3 +224,29 @@ fn test_zstd() {
     assert!(!out_packet.is_extension());
     assert_eq!(in_packet.payload(), src_out_packet.payload())
 }
+#[test]
+fn test_incompressible() {
+    // 伪随机数据压缩后不会变小，保持不压缩
+    let mut buf = [0u8; 1012];
+    let mut seed = 0x2545_f491u32;
+    for v in buf[12..].iter_mut() {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        *v = (seed >> 16) as u8;
+    }
+    let in_packet = NetPacket::new(buf).unwrap();
+    for compressor in [Compressor::Lz4, Compressor::Zstd(9)] {
+        let mut out_packet = NetPacket::new([0; 2000]).unwrap();
+        assert!(!compressor
+            .compress(DEFAULT_COMPRESS_THRESHOLD, &in_packet, &mut out_packet)
+            .unwrap());
+    }
+    // 小于阈值的数据即使可以压缩也不压缩
+    let in_packet = NetPacket::new([0u8; 512]).unwrap();
+    let mut out_packet = NetPacket::new([0; 1000]).unwrap();
+    assert!(Compressor::Lz4
+        .compress(DEFAULT_COMPRESS_THRESHOLD, &in_packet, &mut out_packet)
+        .unwrap());
+    assert!(!Compressor::Lz4
+        .compress(1024, &in_packet, &mut out_packet)
+        .unwrap());
+}
@@ -0,0 +1,329 @@
+use std::net::Ipv4Addr;
+
+use crate::channel::punch::PunchModel;
+use crate::channel::UseChannelType;
+use crate::cipher::CipherModel;
+use crate::compression::{Compressor, DEFAULT_COMPRESS_THRESHOLD};
+use crate::core::{Config, DEFAULT_SERVER_ADDRESS, DEFAULT_STUN_SERVERS};
+#[cfg(feature = "ip_proxy")]
+use crate::ip_proxy::ProxyConfig;
+
+/// Config的构造器，没有设置的字段使用和vnt-cli配置文件相同的默认值，
+/// 至少需要设置token和device_id
+#[derive(Clone, Debug)]
+pub struct ConfigBuilder {
+    #[cfg(target_os = "windows")]
+    tap: bool,
+    token: String,
+    device_id: String,
+    name: String,
+    server_address: String,
+    name_servers: Vec<String>,
+    stun_server: Vec<String>,
+    in_ips: Vec<(u32, u32, Ipv4Addr)>,
+    out_ips: Vec<(u32, u32)>,
+    password: Option<String>,
+    mtu: Option<u32>,
+    tcp: bool,
+    ip: Option<Ipv4Addr>,
+    #[cfg(feature = "ip_proxy")]
+    no_proxy: bool,
+    #[cfg(feature = "ip_proxy")]
+    proxy_config: ProxyConfig,
+    server_encrypt: bool,
+    parallel: usize,
+    cipher_model: CipherModel,
+    finger: bool,
+    punch_model: PunchModel,
+    ports: Option<Vec<u16>>,
+    first_latency: bool,
+    #[cfg(not(target_os = "android"))]
+    device_name: Option<String>,
+    use_channel_type: UseChannelType,
+    packet_loss_rate: Option<f64>,
+    packet_delay: u32,
+    #[cfg(feature = "port_mapping")]
+    port_mapping_list: Vec<String>,
+    compressor: Compressor,
+    compress_threshold: usize,
+    fallback_servers: Vec<String>,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self {
+            #[cfg(target_os = "windows")]
+            tap: false,
+            token: String::new(),
+            device_id: String::new(),
+            name: os_info::get().to_string(),
+            server_address: DEFAULT_SERVER_ADDRESS.to_string(),
+            name_servers: vec![],
+            stun_server: DEFAULT_STUN_SERVERS.iter().map(|s| s.to_string()).collect(),
+            in_ips: vec![],
+            out_ips: vec![],
+            password: None,
+            mtu: None,
+            tcp: false,
+            ip: None,
+            #[cfg(feature = "ip_proxy")]
+            no_proxy: false,
+            #[cfg(feature = "ip_proxy")]
+            proxy_config: ProxyConfig::default(),
+            server_encrypt: false,
+            parallel: 1,
+            #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+            cipher_model: CipherModel::AesGcm,
+            #[cfg(not(any(feature = "aes_gcm", feature = "server_encrypt")))]
+            cipher_model: CipherModel::None,
+            finger: false,
+            punch_model: PunchModel::All,
+            ports: None,
+            first_latency: false,
+            #[cfg(not(target_os = "android"))]
+            device_name: None,
+            use_channel_type: UseChannelType::All,
+            packet_loss_rate: None,
+            packet_delay: 0,
+            #[cfg(feature = "port_mapping")]
+            port_mapping_list: vec![],
+            compressor: Compressor::None,
+            compress_threshold: DEFAULT_COMPRESS_THRESHOLD,
+            fallback_servers: vec![],
+        }
+    }
+}
+
+impl ConfigBuilder {
+    #[cfg(target_os = "windows")]
+    pub fn tap(mut self, tap: bool) -> Self {
+        self.tap = tap;
+        self
+    }
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = token.into();
+        self
+    }
+    pub fn device_id(mut self, device_id: impl Into<String>) -> Self {
+        self.device_id = device_id.into();
+        self
+    }
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+    /// 服务器地址，可以是域名，build时解析
+    pub fn server_address(mut self, server_address: impl Into<String>) -> Self {
+        self.server_address = server_address.into();
+        self
+    }
+    pub fn name_servers(mut self, name_servers: Vec<String>) -> Self {
+        self.name_servers = name_servers;
+        self
+    }
+    pub fn stun_server(mut self, stun_server: Vec<String>) -> Self {
+        self.stun_server = stun_server;
+        self
+    }
+    /// (目标网段,掩码,下一跳)
+    pub fn in_ips(mut self, in_ips: Vec<(u32, u32, Ipv4Addr)>) -> Self {
+        self.in_ips = in_ips;
+        self
+    }
+    /// (网段,掩码)
+    pub fn out_ips(mut self, out_ips: Vec<(u32, u32)>) -> Self {
+        self.out_ips = out_ips;
+        self
+    }
+    pub fn password(mut self, password: Option<String>) -> Self {
+        self.password = password;
+        self
+    }
+    pub fn mtu(mut self, mtu: Option<u32>) -> Self {
+        self.mtu = mtu;
+        self
+    }
+    pub fn tcp(mut self, tcp: bool) -> Self {
+        self.tcp = tcp;
+        self
+    }
+    pub fn ip(mut self, ip: Option<Ipv4Addr>) -> Self {
+        self.ip = ip;
+        self
+    }
+    #[cfg(feature = "ip_proxy")]
+    pub fn no_proxy(mut self, no_proxy: bool) -> Self {
+        self.no_proxy = no_proxy;
+        self
+    }
+    #[cfg(feature = "ip_proxy")]
+    pub fn proxy_config(mut self, proxy_config: ProxyConfig) -> Self {
+        self.proxy_config = proxy_config;
+        self
+    }
+    pub fn server_encrypt(mut self, server_encrypt: bool) -> Self {
+        self.server_encrypt = server_encrypt;
+        self
+    }
+    pub fn parallel(mut self, parallel: usize) -> Self {
+        self.parallel = parallel;
+        self
+    }
+    pub fn cipher_model(mut self, cipher_model: CipherModel) -> Self {
+        self.cipher_model = cipher_model;
+        self
+    }
+    pub fn finger(mut self, finger: bool) -> Self {
+        self.finger = finger;
+        self
+    }
+    pub fn punch_model(mut self, punch_model: PunchModel) -> Self {
+        self.punch_model = punch_model;
+        self
+    }
+    pub fn ports(mut self, ports: Option<Vec<u16>>) -> Self {
+        self.ports = ports;
+        self
+    }
+    pub fn first_latency(mut self, first_latency: bool) -> Self {
+        self.first_latency = first_latency;
+        self
+    }
+    #[cfg(not(target_os = "android"))]
+    pub fn device_name(mut self, device_name: Option<String>) -> Self {
+        self.device_name = device_name;
+        self
+    }
+    pub fn use_channel_type(mut self, use_channel_type: UseChannelType) -> Self {
+        self.use_channel_type = use_channel_type;
+        self
+    }
+    pub fn packet_loss_rate(mut self, packet_loss_rate: Option<f64>) -> Self {
+        self.packet_loss_rate = packet_loss_rate;
+        self
+    }
+    pub fn packet_delay(mut self, packet_delay: u32) -> Self {
+        self.packet_delay = packet_delay;
+        self
+    }
+    /// 例如 udp:127.0.0.1:80->10.26.0.10:8080
+    #[cfg(feature = "port_mapping")]
+    pub fn port_mapping_list(mut self, port_mapping_list: Vec<String>) -> Self {
+        self.port_mapping_list = port_mapping_list;
+        self
+    }
+    pub fn compressor(mut self, compressor: Compressor) -> Self {
+        self.compressor = compressor;
+        self
+    }
+    /// 数据长度不小于这个值时才压缩
+    pub fn compress_threshold(mut self, compress_threshold: usize) -> Self {
+        self.compress_threshold = compress_threshold;
+        self
+    }
+    /// 备用服务器地址，server_address连接不上时按顺序尝试，连接时才解析
+    pub fn fallback_servers(mut self, fallback_servers: Vec<String>) -> Self {
+        self.fallback_servers = fallback_servers;
+        self
+    }
+    /// 和Config::new一样补全默认端口、校验参数并解析服务器地址
+    pub fn build(self) -> anyhow::Result<Config> {
+        let mut config = Config::new(
+            #[cfg(target_os = "windows")]
+            self.tap,
+            self.token,
+            self.device_id,
+            self.name,
+            self.server_address,
+            self.name_servers,
+            self.stun_server,
+            self.in_ips,
+            self.out_ips,
+            self.password,
+            self.mtu,
+            self.tcp,
+            self.ip,
+            #[cfg(feature = "ip_proxy")]
+            self.no_proxy,
+            #[cfg(feature = "ip_proxy")]
+            self.proxy_config,
+            self.server_encrypt,
+            self.parallel,
+            self.cipher_model,
+            self.finger,
+            self.punch_model,
+            self.ports,
+            self.first_latency,
+            #[cfg(not(target_os = "android"))]
+            self.device_name,
+            self.use_channel_type,
+            self.packet_loss_rate,
+            self.packet_delay,
+            #[cfg(feature = "port_mapping")]
+            self.port_mapping_list,
+            self.compressor,
+        )?;
+        config.compress_threshold = self.compress_threshold;
+        config.fallback_servers = self.fallback_servers;
+        Ok(config)
+    }
+}
+
+#[test]
+fn test_builder() {
+    let config = Config::builder()
+        .token("abc")
+        .device_id("device")
+        .server_address("127.0.0.1:29872")
+        .stun_server(vec!["127.0.0.1".to_string()])
+        .build()
+        .unwrap();
+    assert_eq!(
+        config.server_address,
+        "127.0.0.1:29872".parse::<std::net::SocketAddr>().unwrap()
+    );
+    // 和Config::new一样补全默认端口
+    assert_eq!(config.stun_server, vec!["127.0.0.1:3478".to_string()]);
+    assert_eq!(config.parallel, 1);
+    // 缺少device_id时校验失败
+    assert!(Config::builder()
+        .token("abc")
+        .server_address("127.0.0.1:29872")
+        .build()
+        .is_err());
+}
+
+#[cfg(feature = "ip_proxy")]
+#[test]
+fn test_proxy_enabled() {
+    let builder = Config::builder()
+        .token("abc")
+        .device_id("device")
+        .server_address("127.0.0.1:29872");
+    // 没有out_ips时不需要代理
+    assert!(!builder.clone().build().unwrap().proxy_enabled());
+    let builder = builder.out_ips(vec![(0, 0)]);
+    assert!(builder.clone().build().unwrap().proxy_enabled());
+    assert!(!builder.no_proxy(true).build().unwrap().proxy_enabled());
+}
+
+#[cfg(all(feature = "aes_gcm", feature = "chacha20_poly1305"))]
+#[test]
+fn test_cipher_model() {
+    let builder = Config::builder()
+        .token("abc")
+        .device_id("device")
+        .server_address("127.0.0.1:29872")
+        .password(Some("password".to_string()));
+    // 密码哈希包含算法，算法不同的设备哈希不同，设备列表里能看出不能互通
+    let aes = builder.clone().build().unwrap();
+    let chacha = builder
+        .clone()
+        .cipher_model(CipherModel::Chacha20Poly1305)
+        .build()
+        .unwrap();
+    assert_eq!(aes.cipher_model, CipherModel::AesGcm);
+    assert_ne!(aes.password_hash(), chacha.password_hash());
+    // 有密码时必须加密
+    assert!(builder.cipher_model(CipherModel::None).build().is_err());
+}
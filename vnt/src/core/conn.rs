@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
+#[cfg(feature = "ip_proxy")]
+use std::net::SocketAddrV4;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -20,8 +22,16 @@ use crate::core::Config;
 use crate::external_route::{AllowExternalRoute, ExternalRoute};
 use crate::handle::handshaker::Handshake;
 use crate::handle::maintain::PunchReceiver;
+use crate::handle::peer::{self, PeerSnapshot};
 use crate::handle::recv_data::RecvDataHandler;
+use crate::handle::server_list::ServerList;
 use crate::handle::{maintain, BaseConfigInfo, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo};
+#[cfg(feature = "ip_proxy")]
+use crate::ip_proxy::port_filter::PortFilter;
+#[cfg(feature = "ip_proxy")]
+use crate::ip_proxy::tcp_proxy::{ConnSnapshot, ProxyStatsSnapshot};
+#[cfg(feature = "ip_proxy")]
+use crate::ip_proxy::IpProxyMap;
 use crate::nat::NatTest;
 use crate::tun_tap_device::tun_create_helper::{DeviceAdapter, TunDeviceHelper};
 use crate::util::{
@@ -43,6 +53,10 @@ pub struct Vnt {
     down_count_watcher: WatchU64Adder,
     up_count_watcher: WatchSingleU64Adder,
     client_secret_hash: Option<[u8; 16]>,
+    out_external_route: AllowExternalRoute,
+    #[cfg(feature = "ip_proxy")]
+    proxy_map: Option<IpProxyMap>,
+    servers: Arc<Mutex<ServerList>>,
 }
 
 impl Vnt {
@@ -85,6 +99,12 @@ impl Vnt {
             config.server_address_str.clone(),
             config.name_servers.clone(),
         );
+        //主服务器和备用服务器
+        let servers = {
+            let mut list = vec![config.server_address_str.clone()];
+            list.extend(config.fallback_servers.iter().cloned());
+            Arc::new(Mutex::new(ServerList::new(list)))
+        };
         // 服务停止管理器
         let stop_manager = {
             let callback = callback.clone();
@@ -146,12 +166,19 @@ impl Vnt {
         let out_external_route = AllowExternalRoute::new(config.out_ips.clone());
 
         #[cfg(feature = "ip_proxy")]
-        let proxy_map = if !config.out_ips.is_empty() && !config.no_proxy {
+        // 不启动代理时proxy_map为None，收发数据不经过代理
+        let proxy_map = if config.proxy_enabled() {
+            let mut proxy_config = config.proxy_config.clone();
+            if proxy_config.tcp_mss == 0 {
+                // 减去ip头和tcp头
+                proxy_config.tcp_mss = config.device_mtu().saturating_sub(40) as u16;
+            }
             Some(crate::ip_proxy::init_proxy(
                 context.clone(),
                 stop_manager.clone(),
                 current_device.clone(),
                 client_cipher.clone(),
+                proxy_config,
             )?)
         } else {
             None
@@ -181,6 +208,7 @@ impl Vnt {
             up_counter,
             device_list.clone(),
             config.compressor,
+            config.compress_threshold,
         );
         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
         let device_adapter = DeviceAdapter::new(device.clone());
@@ -201,7 +229,7 @@ impl Vnt {
             punch_sender,
             peer_nat_info_map.clone(),
             external_route.clone(),
-            out_external_route,
+            out_external_route.clone(),
             #[cfg(feature = "ip_proxy")]
             proxy_map.clone(),
             down_counter,
@@ -229,10 +257,13 @@ impl Vnt {
             context.clone(),
             current_device.clone(),
             config_info.clone(),
-            tcp_socket_sender.clone(),
+            maintain::ServerConnector {
+                servers: servers.clone(),
+                tcp_socket_sender: tcp_socket_sender.clone(),
+                handshake,
+            },
             callback.clone(),
             0,
-            handshake,
         );
         {
             let context = context.clone();
@@ -282,6 +313,10 @@ impl Vnt {
             down_count_watcher,
             up_count_watcher,
             client_secret_hash: config_info.client_secret_hash,
+            out_external_route,
+            #[cfg(feature = "ip_proxy")]
+            proxy_map,
+            servers,
         })
     }
 }
@@ -378,6 +413,10 @@ impl Vnt {
     pub fn current_device(&self) -> CurrentDeviceInfo {
         self.current_device.load()
     }
+    /// 当前使用的服务器，配置了备用服务器时会在连接失败后切换
+    pub fn current_server(&self) -> String {
+        self.servers.lock().current().to_string()
+    }
     pub fn peer_nat_info(&self, ip: &Ipv4Addr) -> Option<NatInfo> {
         self.peer_nat_info_map.read().get(ip).cloned()
     }
@@ -393,6 +432,21 @@ impl Vnt {
         drop(device_list_lock);
         device_list
     }
+    /// 所有对端设备的连接方式和延迟，只读取内存中的设备列表和路由表，可以频繁调用
+    pub fn peers(&self) -> Vec<PeerSnapshot> {
+        let current_device = self.current_device.load();
+        let device_list = self.device_list();
+        let context = self.context.lock();
+        match context.as_ref() {
+            Some(context) => peer::snapshot(
+                &current_device,
+                device_list,
+                |ip| context.route_table.route_one(ip),
+                |route_key| context.route_table.route_to_id(route_key),
+            ),
+            None => peer::snapshot(&current_device, device_list, |_| None, |_| None),
+        }
+    }
     pub fn route(&self, ip: &Ipv4Addr) -> Option<Route> {
         self.context.lock().as_ref()?.route_table.route_one(ip)
     }
@@ -419,6 +473,46 @@ impl Vnt {
     pub fn down_stream(&self) -> u64 {
         self.down_count_watcher.get()
     }
+    /// tcp代理的统计，没有启用代理时为None
+    #[cfg(feature = "ip_proxy")]
+    pub fn proxy_stats(&self) -> Option<ProxyStatsSnapshot> {
+        self.proxy_map.as_ref().map(|v| v.tcp_stats())
+    }
+    /// tcp代理当前的映射(来源地址,真实目标地址)，没有启用代理时为空
+    #[cfg(feature = "ip_proxy")]
+    pub fn proxy_mappings(&self) -> Vec<(SocketAddrV4, SocketAddrV4)> {
+        self.proxy_map
+            .as_ref()
+            .map(|v| v.tcp_mappings())
+            .unwrap_or_default()
+    }
+    /// tcp代理正在转发的连接和各自的字节数，没有启用代理时为空
+    #[cfg(feature = "ip_proxy")]
+    pub fn proxy_connections(&self) -> Vec<ConnSnapshot> {
+        self.proxy_map
+            .as_ref()
+            .map(|v| v.tcp_connections())
+            .unwrap_or_default()
+    }
+    /// 不重启修改tcp代理的端口过滤规则，没有启用代理时忽略
+    #[cfg(feature = "ip_proxy")]
+    pub fn set_proxy_port_filter(&self, port_filter: PortFilter) {
+        if let Some(proxy_map) = self.proxy_map.as_ref() {
+            proxy_map.set_tcp_port_filter(port_filter);
+        }
+    }
+    /// 不重启修改tcp代理的限速，为0则不限制，没有启用代理时忽略
+    #[cfg(feature = "ip_proxy")]
+    pub fn set_proxy_rate_limit(&self, rate: u64) {
+        if let Some(proxy_map) = self.proxy_map.as_ref() {
+            proxy_map.set_tcp_rate_limit(rate);
+        }
+    }
+    /// 不重启修改允许转发的网段(out_ips)。
+    /// 代理是否启动由启动时的out_ips决定，修改后不会启动或者停止代理
+    pub fn set_out_ips(&self, out_ips: Vec<(u32, u32)>) {
+        self.out_external_route.update(out_ips);
+    }
     pub fn stop(&self) {
         //退出协助回收资源
         let _ = self.context.lock().take();
@@ -430,6 +524,11 @@ impl Vnt {
     pub fn wait_timeout(&self, dur: Duration) -> bool {
         self.stop_manager.wait_timeout(dur)
     }
+    /// 停止并等待所有任务退出，最多等待timeout，返回超时后还没有退出的任务名称
+    pub fn stop_and_wait(&self, timeout: Duration) -> Vec<String> {
+        let _ = self.context.lock().take();
+        self.stop_manager.stop_and_wait(timeout)
+    }
     pub fn config(&self) -> &Config {
         &self.config
     }
@@ -2,16 +2,29 @@ use anyhow::anyhow;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 
+pub use builder::ConfigBuilder;
 pub use conn::Vnt;
 
 use crate::channel::punch::PunchModel;
 use crate::channel::UseChannelType;
 use crate::cipher::CipherModel;
-use crate::compression::Compressor;
+use crate::compression::{Compressor, DEFAULT_COMPRESS_THRESHOLD};
+#[cfg(feature = "ip_proxy")]
+use crate::ip_proxy::ProxyConfig;
 use crate::util::{address_choose, dns_query_all};
 
+mod builder;
 mod conn;
 
+/// 默认的服务器地址
+pub const DEFAULT_SERVER_ADDRESS: &str = "nat1.wherewego.top:29872";
+/// 默认的stun服务器
+pub const DEFAULT_STUN_SERVERS: [&str; 3] = [
+    "stun1.l.google.com:19302",
+    "stun2.l.google.com:19302",
+    "stun.miwifi.com:3478",
+];
+
 #[derive(Clone, Debug)]
 pub struct Config {
     #[cfg(target_os = "windows")]
@@ -29,8 +42,11 @@ pub struct Config {
     pub mtu: Option<u32>,
     pub tcp: bool,
     pub ip: Option<Ipv4Addr>,
+    /// 关闭内置代理，不监听端口也不启动代理线程，发往out_ips的包原样写入网卡
     #[cfg(feature = "ip_proxy")]
     pub no_proxy: bool,
+    #[cfg(feature = "ip_proxy")]
+    pub proxy_config: ProxyConfig,
     pub server_encrypt: bool,
     pub parallel: usize,
     pub cipher_model: CipherModel,
@@ -48,9 +64,17 @@ pub struct Config {
     #[cfg(feature = "port_mapping")]
     pub port_mapping_list: Vec<(bool, SocketAddr, String)>,
     pub compressor: Compressor,
+    /// 数据长度不小于这个值时才压缩
+    pub compress_threshold: usize,
+    /// 备用服务器地址，server_address连接不上时按顺序尝试
+    pub fallback_servers: Vec<String>,
 }
 
 impl Config {
+    /// 用构造器创建，没有设置的字段使用默认值
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
     pub fn new(
         #[cfg(target_os = "windows")] tap: bool,
         token: String,
@@ -66,6 +90,7 @@ impl Config {
         tcp: bool,
         ip: Option<Ipv4Addr>,
         #[cfg(feature = "ip_proxy")] no_proxy: bool,
+        #[cfg(feature = "ip_proxy")] proxy_config: ProxyConfig,
         server_encrypt: bool,
         parallel: usize,
         cipher_model: CipherModel,
@@ -102,6 +127,10 @@ impl Config {
         if name.is_empty() || name.len() > 128 {
             return Err(anyhow!("name too long"));
         }
+        // 设置了密码却不加密时对端以为数据是加密的，直接报错
+        if password.is_some() && cipher_model == CipherModel::None {
+            return Err(anyhow!("password is set but cipher_model is none"));
+        }
         let server_address =
             address_choose(dns_query_all(&server_address_str, name_servers.clone())?)?;
         #[cfg(feature = "port_mapping")]
@@ -129,6 +158,8 @@ impl Config {
             ip,
             #[cfg(feature = "ip_proxy")]
             no_proxy,
+            #[cfg(feature = "ip_proxy")]
+            proxy_config,
             server_encrypt,
             parallel,
             cipher_model,
@@ -144,11 +175,23 @@ impl Config {
             #[cfg(feature = "port_mapping")]
             port_mapping_list,
             compressor,
+            compress_threshold: DEFAULT_COMPRESS_THRESHOLD,
+            fallback_servers: vec![],
         })
     }
 }
 
 impl Config {
+    /// 是否启动内置代理，开启了socks5服务端时没有out_ips也需要启动
+    #[cfg(feature = "ip_proxy")]
+    pub fn proxy_enabled(&self) -> bool {
+        !self.no_proxy && (!self.out_ips.is_empty() || self.proxy_config.socks5.is_some())
+    }
+    /// 虚拟网卡的mtu，没有设置时根据是否加密选择默认值
+    pub fn device_mtu(&self) -> u32 {
+        self.mtu
+            .unwrap_or(if self.password.is_none() { 1450 } else { 1410 })
+    }
     pub fn password_hash(&self) -> Option<[u8; 16]> {
         if let Some(p) = self.password.as_ref() {
             match self.cipher_model {
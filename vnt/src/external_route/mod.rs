@@ -1,6 +1,8 @@
 use std::net::Ipv4Addr;
 use std::sync::Arc;
 
+use parking_lot::RwLock;
+
 // 目标网段，子网掩码，网关
 #[derive(Clone)]
 pub struct ExternalRoute {
@@ -38,25 +40,34 @@ impl ExternalRoute {
 // 目标网段，子网掩码
 #[derive(Clone)]
 pub struct AllowExternalRoute {
-    route_table: Arc<Vec<(u32, u32)>>,
+    /// 可以在运行时替换，见update
+    route_table: Arc<RwLock<Vec<(u32, u32)>>>,
 }
 
 impl AllowExternalRoute {
-    pub fn new(mut route_table: Vec<(u32, u32)>) -> Self {
+    pub fn new(route_table: Vec<(u32, u32)>) -> Self {
+        Self {
+            route_table: Arc::new(RwLock::new(Self::normalize(route_table))),
+        }
+    }
+    fn normalize(mut route_table: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
         for (dest, mask) in &mut route_table {
             *dest = *mask & *dest;
         }
         route_table.sort_by(|(dest1, _), (dest2, _)| dest2.cmp(dest1));
-        Self {
-            route_table: Arc::new(route_table),
-        }
+        route_table
+    }
+    /// 替换允许转发的网段，所有clone共享同一份规则
+    pub fn update(&self, route_table: Vec<(u32, u32)>) {
+        *self.route_table.write() = Self::normalize(route_table);
     }
     pub fn allow(&self, ip: &Ipv4Addr) -> bool {
-        if self.route_table.is_empty() {
+        let route_table = self.route_table.read();
+        if route_table.is_empty() {
             return false;
         }
         let ip = u32::from_be_bytes(ip.octets());
-        for (dest, mask) in self.route_table.iter() {
+        for (dest, mask) in route_table.iter() {
             if *mask & ip == *mask & *dest {
                 return true;
             }
@@ -64,3 +75,15 @@ impl AllowExternalRoute {
         false
     }
 }
+
+#[test]
+fn test_allow_update() {
+    let allow = AllowExternalRoute::new(vec![(u32::from(Ipv4Addr::new(192, 168, 1, 0)), !0 << 8)]);
+    let shared = allow.clone();
+    assert!(shared.allow(&Ipv4Addr::new(192, 168, 1, 2)));
+    allow.update(vec![(u32::from(Ipv4Addr::new(192, 168, 2, 0)), !0 << 8)]);
+    assert!(!shared.allow(&Ipv4Addr::new(192, 168, 1, 2)));
+    assert!(shared.allow(&Ipv4Addr::new(192, 168, 2, 2)));
+    allow.update(vec![]);
+    assert!(!shared.allow(&Ipv4Addr::new(192, 168, 2, 2)));
+}
@@ -1,16 +1,19 @@
 use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
 use crossbeam_utils::atomic::AtomicCell;
 use mio::net::TcpStream;
+use parking_lot::Mutex;
 
 use crate::channel::context::ChannelContext;
 use crate::channel::idle::{Idle, IdleType};
 use crate::channel::sender::AcceptSocketSender;
 use crate::handle::callback::{ConnectInfo, ErrorType};
 use crate::handle::handshaker::Handshake;
+use crate::handle::server_list::ServerList;
 use crate::handle::{BaseConfigInfo, ConnectStatus, CurrentDeviceInfo};
 use crate::util::{address_choose, dns_query_all, Scheduler};
 use crate::{ErrorInfo, VntCallback};
@@ -31,24 +34,29 @@ pub fn idle_route<Call: VntCallback>(
     }
 }
 
+/// 重连服务器用到的状态：主服务器和备用服务器列表、tcp重连的socket发送端、握手
+pub struct ServerConnector {
+    pub servers: Arc<Mutex<ServerList>>,
+    pub tcp_socket_sender: AcceptSocketSender<(TcpStream, SocketAddr, Option<Vec<u8>>)>,
+    pub handshake: Handshake,
+}
+
 pub fn idle_gateway<Call: VntCallback>(
     scheduler: &Scheduler,
     context: ChannelContext,
     current_device_info: Arc<AtomicCell<CurrentDeviceInfo>>,
     config: BaseConfigInfo,
-    tcp_socket_sender: AcceptSocketSender<(TcpStream, SocketAddr, Option<Vec<u8>>)>,
+    connector: ServerConnector,
     call: Call,
     mut connect_count: usize,
-    handshake: Handshake,
 ) {
     idle_gateway0(
         &context,
         &current_device_info,
         &config,
-        &tcp_socket_sender,
+        &connector,
         &call,
         &mut connect_count,
-        &handshake,
     );
     let rs = scheduler.timeout(Duration::from_secs(5), move |s| {
         idle_gateway(
@@ -56,10 +64,9 @@ pub fn idle_gateway<Call: VntCallback>(
             context,
             current_device_info,
             config,
-            tcp_socket_sender,
+            connector,
             call,
             connect_count,
-            handshake,
         )
     });
     if !rs {
@@ -71,19 +78,17 @@ fn idle_gateway0<Call: VntCallback>(
     context: &ChannelContext,
     current_device: &AtomicCell<CurrentDeviceInfo>,
     config: &BaseConfigInfo,
-    tcp_socket_sender: &AcceptSocketSender<(TcpStream, SocketAddr, Option<Vec<u8>>)>,
+    connector: &ServerConnector,
     call: &Call,
     connect_count: &mut usize,
-    handshake: &Handshake,
 ) {
     if let Err(e) = check_gateway_channel(
         context,
         current_device,
         config,
-        tcp_socket_sender,
+        connector,
         call,
         connect_count,
-        handshake,
     ) {
         let cur = current_device.load();
         call.error(ErrorInfo::new_msg(
@@ -120,16 +125,42 @@ fn check_gateway_channel<Call: VntCallback>(
     context: &ChannelContext,
     current_device_info: &AtomicCell<CurrentDeviceInfo>,
     config: &BaseConfigInfo,
-    tcp_socket_sender: &AcceptSocketSender<(TcpStream, SocketAddr, Option<Vec<u8>>)>,
+    connector: &ServerConnector,
     call: &Call,
     count: &mut usize,
-    handshake: &Handshake,
 ) -> io::Result<()> {
+    let ServerConnector {
+        servers,
+        tcp_socket_sender,
+        handshake,
+    } = connector;
     let mut current_device = current_device_info.load();
     if current_device.status.offline() {
+        // 上一次没有连上时切换服务器，都在退避时间内则这次不连接
+        let server_addr = match servers.lock().next_attempt(Instant::now()) {
+            Some(server_addr) => server_addr.to_string(),
+            None => return Ok(()),
+        };
         *count += 1;
-        // 探测服务器地址
-        current_device = domain_request0(current_device_info, config);
+        // 探测服务器地址，解析成功后才切换服务器，避免握手发给旧地址而当前服务器显示的是新的
+        current_device =
+            match domain_request0(current_device_info, &server_addr, &config.name_servers) {
+                Ok(current_device) => {
+                    servers.lock().resolved();
+                    current_device
+                }
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    if !servers.lock().resolve_failed(Instant::now()) {
+                        return Err(io::Error::other(format!(
+                            "resolve server {} failed",
+                            server_addr
+                        )));
+                    }
+                    // 还是原来的服务器，使用上次解析的地址
+                    current_device_info.load()
+                }
+            };
         //需要重连
         call.connect(ConnectInfo::new(*count, current_device.connect_server));
         log::info!("发送握手请求,{:?}", config);
@@ -153,51 +184,131 @@ fn check_gateway_channel<Call: VntCallback>(
                 }
             }
         }
+    } else {
+        servers.lock().connected();
     }
     Ok(())
 }
 
+/// 解析服务器地址，地址变化时更新connect_server，解析失败时不修改
 pub fn domain_request0(
     current_device: &AtomicCell<CurrentDeviceInfo>,
-    config: &BaseConfigInfo,
-) -> CurrentDeviceInfo {
+    server_addr: &str,
+    name_servers: &[String],
+) -> anyhow::Result<CurrentDeviceInfo> {
     let mut current_dev = current_device.load();
 
     // 探测服务端地址变化
-    match dns_query_all(&config.server_addr, config.name_servers.clone()) {
-        Ok(addrs) => {
-            log::info!(
-                "domain {} dns {:?} addr {:?}",
-                config.server_addr,
-                config.name_servers,
-                addrs
-            );
-
-            match address_choose(addrs) {
-                Ok(addr) => {
-                    if addr != current_dev.connect_server {
-                        let mut tmp = current_dev.clone();
-                        tmp.connect_server = addr;
-                        let rs = current_device.compare_exchange(current_dev, tmp);
-                        log::info!(
-                            "服务端地址变化,旧地址:{}，新地址:{},替换结果:{}",
-                            current_dev.connect_server,
-                            addr,
-                            rs.is_ok()
-                        );
-                        if rs.is_ok() {
-                            current_dev.connect_server = addr;
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::error!("域名地址选择失败:{:?},domain={}", e, config.server_addr);
-                }
-            }
-        }
-        Err(e) => {
-            log::error!("域名解析失败:{:?},domain={}", e, config.server_addr);
+    let addrs = dns_query_all(server_addr, name_servers.to_vec())
+        .with_context(|| format!("域名解析失败,domain={}", server_addr))?;
+    log::info!(
+        "domain {} dns {:?} addr {:?}",
+        server_addr,
+        name_servers,
+        addrs
+    );
+    let addr = address_choose(addrs)
+        .with_context(|| format!("域名地址选择失败,domain={}", server_addr))?;
+    if addr != current_dev.connect_server {
+        let mut tmp = current_dev;
+        tmp.connect_server = addr;
+        let rs = current_device.compare_exchange(current_dev, tmp);
+        log::info!(
+            "服务端地址变化,旧地址:{}，新地址:{},替换结果:{}",
+            current_dev.connect_server,
+            addr,
+            rs.is_ok()
+        );
+        if rs.is_ok() {
+            current_dev.connect_server = addr;
         }
     }
-    current_dev
+    Ok(current_dev)
+}
+
+#[test]
+fn test_check_gateway_failover() {
+    use crate::channel::notify::AcceptNotify;
+    use crate::channel::UseChannelType;
+    #[derive(Clone)]
+    struct Call;
+    impl VntCallback for Call {}
+    // a不响应，连接失败后切换到b
+    let server_a = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_b = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (addr_a, addr_b) = (
+        server_a.local_addr().unwrap(),
+        server_b.local_addr().unwrap(),
+    );
+    for server in [&server_a, &server_b] {
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+    }
+    let (context, _tcp_listener) =
+        crate::channel::init_context(vec![0], UseChannelType::All, false, false, None, 0).unwrap();
+    let current_device = AtomicCell::new(CurrentDeviceInfo::new0(addr_a));
+    let config = BaseConfigInfo::new(
+        "test".to_string(),
+        "token".to_string(),
+        None,
+        None,
+        false,
+        "id".to_string(),
+        addr_a.to_string(),
+        vec![],
+    );
+    let servers = Arc::new(Mutex::new(ServerList::new(vec![
+        addr_a.to_string(),
+        addr_b.to_string(),
+        "bad-server".to_string(),
+    ])));
+    let poll = mio::Poll::new().unwrap();
+    let waker = mio::Waker::new(poll.registry(), mio::Token(0)).unwrap();
+    let (sender, _receiver) = std::sync::mpsc::sync_channel(1);
+    let tcp_socket_sender = AcceptSocketSender::new(AcceptNotify::new(waker), sender);
+    let mut count = 0;
+    let mut check = || {
+        // 握手请求短时间内不重复发送，每次用新的Handshake
+        let connector = ServerConnector {
+            servers: servers.clone(),
+            tcp_socket_sender: tcp_socket_sender.clone(),
+            handshake: Handshake::new(
+                #[cfg(feature = "server_encrypt")]
+                Arc::new(Mutex::new(None)),
+            ),
+        };
+        check_gateway_channel(
+            &context,
+            &current_device,
+            &config,
+            &connector,
+            &Call,
+            &mut count,
+        )
+    };
+    let mut buf = [0u8; 1024];
+    check().unwrap();
+    assert!(server_a.recv_from(&mut buf).is_ok());
+    // a没有响应，下一次握手发给b
+    check().unwrap();
+    assert!(server_b.recv_from(&mut buf).is_ok());
+    assert_eq!(servers.lock().current(), addr_b.to_string());
+    assert_eq!(current_device.load().connect_server, addr_b);
+    // 握手成功
+    crate::handle::change_status(&current_device, ConnectStatus::Connected);
+    check().unwrap();
+    assert_eq!(servers.lock().current(), addr_b.to_string());
+
+    // b断开，a和b都在退避时间内，解析失败的服务器不能成为当前服务器，也不向旧地址握手
+    crate::handle::change_status(&current_device, ConnectStatus::Connecting);
+    check().unwrap();
+    assert!(server_b.recv_from(&mut buf).is_ok());
+    assert!(check().is_err());
+    assert_eq!(servers.lock().current(), addr_b.to_string());
+    assert_eq!(current_device.load().connect_server, addr_b);
+    server_b
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    assert!(server_b.recv_from(&mut buf).is_err());
 }
@@ -14,6 +14,7 @@ pub use punch::*;
 mod idle;
 pub use idle::idle_gateway;
 pub use idle::idle_route;
+pub use idle::ServerConnector;
 
 mod up_status;
 pub use up_status::*;
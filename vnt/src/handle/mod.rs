@@ -5,8 +5,10 @@ pub mod callback;
 mod extension;
 pub mod handshaker;
 pub mod maintain;
+pub mod peer;
 pub mod recv_data;
 pub mod registrar;
+pub mod server_list;
 pub mod tun_tap;
 
 const SELF_IP: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 2);
@@ -0,0 +1,143 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use crate::channel::{Route, RouteKey};
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo, PeerDeviceStatus};
+
+/// 到对端的连接方式
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PeerConnectType {
+    /// udp直连
+    P2p,
+    /// tcp直连
+    TcpP2p,
+    /// 经过服务器中转
+    ServerRelay,
+    /// 经过其他客户端中转
+    ClientRelay,
+}
+
+impl PeerConnectType {
+    pub fn is_p2p(&self) -> bool {
+        matches!(self, PeerConnectType::P2p | PeerConnectType::TcpP2p)
+    }
+}
+
+/// 对端设备的只读快照，查询时复制，不持有任何锁
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerSnapshot {
+    pub virtual_ip: Ipv4Addr,
+    pub name: String,
+    pub status: PeerDeviceStatus,
+    pub connect_type: PeerConnectType,
+    /// 当前使用的通道地址，直连时是对端地址，中转时是下一跳的地址，没有路由时为None
+    pub addr: Option<SocketAddr>,
+    /// 当前通道的延迟(毫秒)，还没有测出延迟或者没有路由时为None
+    pub rt: Option<i64>,
+}
+
+/// 按设备列表生成快照，route查询对端当前使用的路由，next_hop查询通道对应的虚拟ip，
+/// 都只读内存中的路由表，不会有网络io
+pub(crate) fn snapshot<R, N>(
+    current_device: &CurrentDeviceInfo,
+    device_list: Vec<PeerDeviceInfo>,
+    route: R,
+    next_hop: N,
+) -> Vec<PeerSnapshot>
+where
+    R: Fn(&Ipv4Addr) -> Option<Route>,
+    N: Fn(&RouteKey) -> Option<Ipv4Addr>,
+{
+    device_list
+        .into_iter()
+        .map(|peer| {
+            let route = route(&peer.virtual_ip);
+            let connect_type = match &route {
+                Some(route) if route.is_p2p() => {
+                    if route.is_tcp {
+                        PeerConnectType::TcpP2p
+                    } else {
+                        PeerConnectType::P2p
+                    }
+                }
+                Some(route) => match next_hop(&route.route_key()) {
+                    Some(ip) if !current_device.is_gateway(&ip) => PeerConnectType::ClientRelay,
+                    _ => PeerConnectType::ServerRelay,
+                },
+                // 没有路由时数据发给服务器中转
+                None => PeerConnectType::ServerRelay,
+            };
+            PeerSnapshot {
+                virtual_ip: peer.virtual_ip,
+                name: peer.name,
+                status: peer.status,
+                connect_type,
+                addr: route.as_ref().map(|route| route.addr),
+                rt: route.map(|route| route.rt).filter(|rt| *rt >= 0),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_snapshot() {
+    use std::collections::HashMap;
+    let server: SocketAddr = "1.1.1.1:29872".parse().unwrap();
+    let mut current_device = CurrentDeviceInfo::new(
+        Ipv4Addr::new(10, 26, 0, 2),
+        Ipv4Addr::new(255, 255, 255, 0),
+        Ipv4Addr::new(10, 26, 0, 1),
+        server,
+    );
+    current_device.status = crate::handle::ConnectStatus::Connected;
+    let peer = |ip: Ipv4Addr, name: &str, status: u8| {
+        PeerDeviceInfo::new(ip, name.to_string(), status, false, vec![])
+    };
+    let (a, b, c, d) = (
+        Ipv4Addr::new(10, 26, 0, 3),
+        Ipv4Addr::new(10, 26, 0, 4),
+        Ipv4Addr::new(10, 26, 0, 5),
+        Ipv4Addr::new(10, 26, 0, 6),
+    );
+    let a_addr: SocketAddr = "192.168.1.3:5000".parse().unwrap();
+    let relay_addr: SocketAddr = "192.168.1.4:5000".parse().unwrap();
+    let mut routes = HashMap::new();
+    // a直连，b经过a中转，c经过服务器中转，d还没有路由
+    routes.insert(a, Route::new(false, 0, a_addr, 1, 12));
+    routes.insert(b, Route::new(false, 0, relay_addr, 2, 30));
+    routes.insert(c, Route::new(true, 0, server, 2, -1));
+    let next_hops: HashMap<SocketAddr, Ipv4Addr> = [
+        (a_addr, a),
+        (relay_addr, a),
+        (server, current_device.virtual_gateway),
+    ]
+    .into_iter()
+    .collect();
+    let list = snapshot(
+        &current_device,
+        vec![
+            peer(a, "a", 0),
+            peer(b, "b", 0),
+            peer(c, "c", 0),
+            peer(d, "d", 1),
+        ],
+        |ip| routes.get(ip).copied(),
+        |key| next_hops.get(&key.addr).copied(),
+    );
+    let types: Vec<_> = list
+        .iter()
+        .map(|peer| (peer.virtual_ip, peer.connect_type, peer.rt))
+        .collect();
+    assert_eq!(
+        types,
+        vec![
+            (a, PeerConnectType::P2p, Some(12)),
+            (b, PeerConnectType::ClientRelay, Some(30)),
+            (c, PeerConnectType::ServerRelay, None),
+            (d, PeerConnectType::ServerRelay, None),
+        ]
+    );
+    assert_eq!(list[0].addr, Some(a_addr));
+    assert_eq!(list[3].addr, None);
+    assert_eq!(list[3].status, PeerDeviceStatus::Offline);
+    assert!(list[0].connect_type.is_p2p());
+}
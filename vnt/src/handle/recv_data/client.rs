@@ -1,5 +1,7 @@
 use anyhow::anyhow;
 use std::collections::HashMap;
+#[cfg(feature = "ip_proxy")]
+use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 
@@ -22,7 +24,7 @@ use crate::handle::maintain::PunchSender;
 use crate::handle::recv_data::PacketHandler;
 use crate::handle::CurrentDeviceInfo;
 #[cfg(feature = "ip_proxy")]
-use crate::ip_proxy::{IpProxyMap, ProxyHandler};
+use crate::ip_proxy::{IpProxyMap, ProxyAction, ProxyHandler};
 use crate::nat::NatTest;
 use crate::proto::message::{PunchInfo, PunchNatType};
 use crate::protocol::body::ENCRYPTION_RESERVED;
@@ -184,9 +186,10 @@ impl ClientPacketHandler {
                     }
                     #[cfg(feature = "ip_proxy")]
                     if let Some(ip_proxy_map) = &self.ip_proxy_map {
-                        if ip_proxy_map.recv_handle(&mut ipv4, source, destination)? {
-                            return Ok(());
-                        }
+                        proxy_recv(ip_proxy_map, &mut ipv4, source, destination, |buf| {
+                            self.device.write(buf)
+                        })?;
+                        return Ok(());
                     }
                 }
                 self.device.write(net_packet.payload())?;
@@ -387,3 +390,90 @@ impl ClientPacketHandler {
         Ok(())
     }
 }
+
+/// 交给代理处理，代理返回PassThrough时才把（可能已被改写的）包写入tun
+#[cfg(feature = "ip_proxy")]
+fn proxy_recv<H: ProxyHandler>(
+    proxy: &H,
+    ipv4: &mut IpV4Packet<&mut [u8]>,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    write: impl FnOnce(&[u8]) -> io::Result<usize>,
+) -> io::Result<()> {
+    match proxy.recv_handle(ipv4, source, destination)? {
+        ProxyAction::PassThrough => {
+            write(ipv4.buffer)?;
+        }
+        ProxyAction::Handled => {}
+        ProxyAction::Drop => {
+            log::debug!("代理丢弃数据包 {}->{}", source, ipv4.destination_ip());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "ip_proxy"))]
+struct TestProxy {
+    action: ProxyAction,
+}
+
+#[cfg(all(test, feature = "ip_proxy"))]
+impl ProxyHandler for TestProxy {
+    fn recv_handle(
+        &self,
+        ipv4: &mut IpV4Packet<&mut [u8]>,
+        _source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> io::Result<ProxyAction> {
+        ipv4.set_destination_ip(destination);
+        ipv4.update_checksum();
+        Ok(self.action)
+    }
+
+    fn send_handle(&self, _ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "ip_proxy")]
+#[test]
+fn test_proxy_recv() {
+    let source = Ipv4Addr::new(10, 26, 0, 2);
+    let destination = Ipv4Addr::new(10, 26, 0, 3);
+    let real_dest = Ipv4Addr::new(192, 168, 1, 2);
+    let mut buf = [0u8; 28];
+    buf[0] = 0x45;
+    buf[9] = 17;
+    buf[16..20].copy_from_slice(&real_dest.octets());
+    for action in [
+        ProxyAction::Handled,
+        ProxyAction::Drop,
+        ProxyAction::PassThrough,
+    ] {
+        let mut data = buf;
+        let mut ipv4 = IpV4Packet::new(&mut data[..]).unwrap();
+        let mut written = None;
+        proxy_recv(
+            &TestProxy { action },
+            &mut ipv4,
+            source,
+            destination,
+            |buf| {
+                written = Some(buf.to_vec());
+                Ok(buf.len())
+            },
+        )
+        .unwrap();
+        if action.is_claimed() {
+            // 代理接管或者丢弃的包不能写入tun
+            assert!(written.is_none());
+        } else {
+            // 写入的是代理改写后的包
+            let written = written.unwrap();
+            assert_eq!(
+                IpV4Packet::new(&written[..]).unwrap().destination_ip(),
+                destination
+            );
+        }
+    }
+}
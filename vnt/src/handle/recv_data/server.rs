@@ -278,7 +278,7 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                     let public_ip = response.public_ip.into();
                     let public_port = response.public_port as u16;
                     self.nat_test
-                        .update_addr(route_key.index(), public_ip, public_port);
+                        .update_route_addr(&route_key, public_ip, public_port);
                     let old = current_device;
                     let mut cur = *current_device;
                     loop {
@@ -557,11 +557,8 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
             }
             ControlPacket::AddrResponse(addr_packet) => {
                 //更新本地公网ipv4
-                self.nat_test.update_addr(
-                    route_key.index(),
-                    addr_packet.ipv4(),
-                    addr_packet.port(),
-                );
+                self.nat_test
+                    .update_route_addr(&route_key, addr_packet.ipv4(), addr_packet.port());
             }
             _ => {}
         }
@@ -0,0 +1,163 @@
+use std::time::{Duration, Instant};
+
+/// 失败后第一次的退避时间，之后每次翻倍
+const MIN_BACKOFF: Duration = Duration::from_secs(10);
+/// 最长的退避时间
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+struct ServerEntry {
+    addr: String,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// 服务器列表，第一个是主服务器。
+/// 当前服务器连接失败时按顺序选择第一个不在退避时间内的服务器，所以主服务器恢复后会优先使用。
+/// 失败过的服务器在退避时间内不再尝试，退避时间随连续失败次数翻倍；
+/// 只有一个服务器时不退避，和原来一样一直重试
+#[derive(Clone, Debug)]
+pub struct ServerList {
+    servers: Vec<ServerEntry>,
+    /// 正在使用的服务器，要尝试的服务器地址解析成功后才切换
+    current: usize,
+    /// 已经发起连接的服务器，还没有确认结果
+    attempt: Option<usize>,
+}
+
+impl ServerList {
+    pub fn new(servers: Vec<String>) -> Self {
+        assert!(!servers.is_empty());
+        Self {
+            servers: servers
+                .into_iter()
+                .map(|addr| ServerEntry {
+                    addr,
+                    failures: 0,
+                    retry_at: None,
+                })
+                .collect(),
+            current: 0,
+            attempt: None,
+        }
+    }
+    /// 当前使用的服务器地址
+    pub fn current(&self) -> &str {
+        &self.servers[self.current].addr
+    }
+    /// 准备发起一次连接，返回要连接的服务器；
+    /// 上一次连接没有成功时记为失败，按顺序选择不在退避时间内的服务器，都在退避时间内时返回None。
+    /// 这时还没有切换当前服务器，地址解析成功后调用resolved才切换
+    pub fn next_attempt(&mut self, now: Instant) -> Option<&str> {
+        if self.attempt.is_some() {
+            self.failed(now);
+        }
+        let index = if self.servers.len() > 1 {
+            self.servers
+                .iter()
+                .position(|s| s.retry_at.filter(|t| *t > now).is_none())?
+        } else {
+            0
+        };
+        self.attempt = Some(index);
+        Some(&self.servers[index].addr)
+    }
+    /// 要连接的服务器地址解析成功，开始使用它的地址，切换当前服务器
+    pub fn resolved(&mut self) {
+        if let Some(index) = self.attempt {
+            if index != self.current {
+                log::info!(
+                    "切换服务器,旧地址:{},新地址:{}",
+                    self.current(),
+                    self.servers[index].addr
+                );
+                self.current = index;
+            }
+        }
+    }
+    /// 要连接的服务器地址解析失败。
+    /// 还是当前服务器时返回true，继续使用上次解析的地址；否则记为失败，这次不连接
+    pub fn resolve_failed(&mut self, now: Instant) -> bool {
+        match self.attempt {
+            Some(index) if index != self.current => {
+                self.failed(now);
+                false
+            }
+            _ => true,
+        }
+    }
+    /// 已经连接上当前服务器
+    pub fn connected(&mut self) {
+        self.attempt = None;
+        let entry = &mut self.servers[self.current];
+        entry.failures = 0;
+        entry.retry_at = None;
+    }
+    fn failed(&mut self, now: Instant) {
+        let Some(index) = self.attempt.take() else {
+            return;
+        };
+        if self.servers.len() == 1 {
+            return;
+        }
+        let entry = &mut self.servers[index];
+        entry.failures += 1;
+        let backoff = MIN_BACKOFF
+            .saturating_mul(1 << (entry.failures - 1).min(16))
+            .min(MAX_BACKOFF);
+        entry.retry_at = Some(now + backoff);
+        log::warn!(
+            "服务器连接失败:{},连续失败次数:{},{:?}后再尝试",
+            entry.addr,
+            entry.failures,
+            backoff
+        );
+    }
+}
+
+#[test]
+fn test_failover() {
+    let mut list = ServerList::new(vec!["a".to_string(), "b".to_string()]);
+    let now = Instant::now();
+    assert_eq!(list.next_attempt(now), Some("a"));
+    // a没有响应，切换到b
+    assert_eq!(list.next_attempt(now + Duration::from_secs(5)), Some("b"));
+    // 地址解析成功前还是a
+    assert_eq!(list.current(), "a");
+    list.resolved();
+    list.connected();
+    assert_eq!(list.current(), "b");
+    // b断开时a已经过了退避时间，优先使用主服务器
+    assert_eq!(list.next_attempt(now + Duration::from_secs(60)), Some("a"));
+    // a第二次失败，退避时间翻倍
+    assert_eq!(list.next_attempt(now + Duration::from_secs(65)), Some("b"));
+    // b也失败，都在退避时间内时不发起连接
+    assert_eq!(list.next_attempt(now + Duration::from_secs(70)), None);
+    assert_eq!(list.next_attempt(now + Duration::from_secs(80)), Some("b"));
+    list.connected();
+    assert_eq!(list.next_attempt(now + Duration::from_secs(85)), Some("a"));
+}
+
+#[test]
+fn test_single_server() {
+    // 只有一个服务器时一直重试
+    let mut list = ServerList::new(vec!["a".to_string()]);
+    let now = Instant::now();
+    for i in 0..10 {
+        assert_eq!(list.next_attempt(now + Duration::from_secs(i)), Some("a"));
+    }
+}
+
+#[test]
+fn test_resolve_failed() {
+    let mut list = ServerList::new(vec!["a".to_string(), "b".to_string()]);
+    let now = Instant::now();
+    assert_eq!(list.next_attempt(now), Some("a"));
+    // 当前服务器解析失败时继续使用上次的地址
+    assert!(list.resolve_failed(now));
+    assert_eq!(list.next_attempt(now + Duration::from_secs(5)), Some("b"));
+    // b解析失败，不切换，下次跳过b
+    assert!(!list.resolve_failed(now + Duration::from_secs(5)));
+    assert_eq!(list.current(), "a");
+    assert_eq!(list.next_attempt(now + Duration::from_secs(6)), None);
+}
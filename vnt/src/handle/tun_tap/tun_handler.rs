@@ -58,6 +58,7 @@ pub fn start(
     mut up_counter: SingleU64Adder,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     compressor: Compressor,
+    compress_threshold: usize,
 ) -> io::Result<()> {
     if parallel > 1 {
         let (sender, receivers) = channel_group::<(Vec<u8>, usize)>(parallel, 16);
@@ -94,6 +95,7 @@ pub fn start(
                             &server_cipher,
                             &device_list,
                             &compressor,
+                            compress_threshold,
                         ) {
                             Ok(_) => {}
                             Err(e) => {
@@ -132,6 +134,7 @@ pub fn start(
                     &mut up_counter,
                     device_list,
                     compressor,
+                    compress_threshold,
                 ) {
                     log::warn!("stop:{}", e);
                 }
@@ -241,6 +244,7 @@ pub(crate) fn handle(
     server_cipher: &Cipher,
     device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
     compressor: &Compressor,
+    compress_threshold: usize,
 ) -> anyhow::Result<()> {
     //忽略掉结构不对的情况（ipv6数据、win tap会读到空数据），不然日志打印太多了
     let ipv4_packet = match IpV4Packet::new(&mut buf[12..data_len]) {
@@ -304,7 +308,7 @@ pub(crate) fn handle(
         net_packet.set_destination(Ipv4Addr::BROADCAST);
     }
 
-    let mut net_packet = if compressor.compress(&net_packet, &mut out)? {
+    let mut net_packet = if compressor.compress(compress_threshold, &net_packet, &mut out)? {
         out.set_default_version();
         out.set_protocol(protocol::Protocol::IpTurn);
         out.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
@@ -33,6 +33,7 @@ pub(crate) fn start_simple(
     up_counter: &mut SingleU64Adder,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     compressor: Compressor,
+    compress_threshold: usize,
 ) -> anyhow::Result<()> {
     let poll = Poll::new()?;
     let waker = Arc::new(Waker::new(poll.registry(), STOP)?);
@@ -53,6 +54,7 @@ pub(crate) fn start_simple(
         up_counter,
         device_list,
         compressor,
+        compress_threshold,
     ) {
         log::error!("{:?}", e);
     };
@@ -73,6 +75,7 @@ fn start_simple0(
     up_counter: &mut SingleU64Adder,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     compressor: Compressor,
+    compress_threshold: usize,
 ) -> anyhow::Result<()> {
     let mut buf = [0; BUFFER_SIZE];
     let mut extend = [0; BUFFER_SIZE];
@@ -118,6 +121,7 @@ fn start_simple0(
                     &server_cipher,
                     &device_list,
                     &compressor,
+                    compress_threshold,
                 ) {
                     Ok(_) => {}
                     Err(e) => {
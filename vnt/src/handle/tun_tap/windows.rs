@@ -26,6 +26,7 @@ pub(crate) fn start_simple(
     up_counter: &mut SingleU64Adder,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     compressor: Compressor,
+    compress_threshold: usize,
 ) -> anyhow::Result<()> {
     let worker = {
         let device = device.clone();
@@ -47,6 +48,7 @@ pub(crate) fn start_simple(
         up_counter,
         device_list,
         compressor,
+        compress_threshold,
     ) {
         log::error!("{:?}", e);
     }
@@ -64,6 +66,7 @@ fn start_simple0(
     up_counter: &mut SingleU64Adder,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     compressor: Compressor,
+    compress_threshold: usize,
 ) -> anyhow::Result<()> {
     let mut buf = [0; BUFFER_SIZE];
     let mut extend = [0; BUFFER_SIZE];
@@ -87,6 +90,7 @@ fn start_simple0(
             &server_cipher,
             &device_list,
             &compressor,
+            compress_threshold,
         ) {
             Ok(_) => {}
             Err(e) => {
@@ -0,0 +1,245 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+/// 写日志线程来不及处理时最多缓存的记录数，超过后丢弃
+const QUEUE_LEN: usize = 1024;
+/// 默认单个日志文件的最大字节数
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// 默认保留的旧日志文件数
+pub const DEFAULT_MAX_FILES: usize = 5;
+
+/// 连接结束的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// 两个方向都正常结束
+    Eof,
+    /// 连接真实目标失败
+    ConnectFailed,
+    /// 两个方向都没有数据超过tcp_idle_timeout
+    IdleTimeout,
+    /// 写入停滞超过tcp_write_timeout
+    WriteTimeout,
+    /// 读写出错
+    Error,
+}
+
+impl CloseReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::Eof => "eof",
+            CloseReason::ConnectFailed => "connect_failed",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::WriteTimeout => "write_timeout",
+            CloseReason::Error => "error",
+        }
+    }
+}
+
+/// 一条连接的访问记录
+#[derive(Clone, Debug)]
+pub struct AccessRecord {
+    /// 连接结束的时间
+    pub time: SystemTime,
+    pub id: u64,
+    pub src: SocketAddr,
+    pub dst: SocketAddrV4,
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+    pub duration: Duration,
+    pub reason: CloseReason,
+}
+
+impl AccessRecord {
+    /// 一行json，字段固定，方便程序解析：
+    /// {"ts_ms":1700000000000,"id":1,"src":"10.26.0.2:40000","dst":"192.168.1.2:80","up":5,"down":5,"duration_ms":12,"reason":"eof"}
+    pub fn to_json_line(&self) -> String {
+        let ts_ms = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        format!(
+            "{{\"ts_ms\":{},\"id\":{},\"src\":\"{}\",\"dst\":\"{}\",\"up\":{},\"down\":{},\"duration_ms\":{},\"reason\":\"{}\"}}\n",
+            ts_ms,
+            self.id,
+            self.src,
+            self.dst,
+            self.upload_bytes,
+            self.download_bytes,
+            self.duration.as_millis(),
+            self.reason.as_str()
+        )
+    }
+}
+
+/// tcp代理的访问日志，每条连接结束时写一行json。
+/// 记录通过有界channel交给单独的线程写文件，不阻塞代理；线程来不及写时丢弃记录。
+/// 文件超过max_size时轮转：path改名为path.1，原来的path.1改名为path.2，最多保留max_files个旧文件
+#[derive(Clone)]
+pub struct AccessLog {
+    sender: SyncSender<AccessRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    /// 所有AccessLog都drop后写日志线程退出
+    pub fn open(path: &Path, max_size: u64, max_files: usize) -> anyhow::Result<Self> {
+        let writer = RotateWriter::open(path.to_path_buf(), max_size, max_files)?;
+        let (sender, receiver) = sync_channel(QUEUE_LEN);
+        let dropped = Arc::new(AtomicU64::new(0));
+        {
+            let dropped = dropped.clone();
+            thread::Builder::new()
+                .name("proxyAccessLog".into())
+                .spawn(move || write_loop(writer, receiver, &dropped))?;
+        }
+        Ok(Self { sender, dropped })
+    }
+    pub fn write(&self, record: AccessRecord) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AccessLog")
+    }
+}
+
+/// 有记录时一次写完channel里所有的记录再flush
+fn write_loop(mut writer: RotateWriter, receiver: Receiver<AccessRecord>, dropped: &AtomicU64) {
+    while let Ok(record) = receiver.recv() {
+        let rs = std::iter::once(record)
+            .chain(receiver.try_iter())
+            .try_for_each(|record| writer.write(&record));
+        if let Err(e) = rs.and_then(|_| writer.flush()) {
+            log::warn!("tcp proxy access log error={:?}", e);
+        }
+        let count = dropped.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            log::warn!("tcp proxy access log dropped={}", count);
+        }
+    }
+}
+
+struct RotateWriter {
+    path: PathBuf,
+    /// 为0则不轮转
+    max_size: u64,
+    max_files: usize,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl RotateWriter {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> anyhow::Result<Self> {
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file: BufWriter::new(file),
+            size,
+        })
+    }
+    fn write(&mut self, record: &AccessRecord) -> anyhow::Result<()> {
+        let line = record.to_json_line();
+        if self.max_size != 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, i);
+                if from.exists() {
+                    std::fs::rename(&from, rotated_path(&self.path, i + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        } else {
+            std::fs::remove_file(&self.path)?;
+        }
+        self.file = BufWriter::new(open_append(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open access log {:?} failed", path))
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+#[test]
+fn test_rotate() {
+    let dir = std::env::temp_dir().join(format!("vnt-access-log-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+    let record = |id| AccessRecord {
+        time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+        id,
+        src: "10.26.0.2:40000".parse().unwrap(),
+        dst: "192.168.1.2:80".parse().unwrap(),
+        upload_bytes: 5,
+        download_bytes: 7,
+        duration: Duration::from_millis(12),
+        reason: CloseReason::Eof,
+    };
+    let line = record(1).to_json_line();
+    assert_eq!(
+        line,
+        "{\"ts_ms\":1700000000123,\"id\":1,\"src\":\"10.26.0.2:40000\",\"dst\":\"192.168.1.2:80\",\"up\":5,\"down\":7,\"duration_ms\":12,\"reason\":\"eof\"}\n"
+    );
+    // 每个文件只放得下两行，保留两个旧文件
+    let mut writer = RotateWriter::open(path.clone(), line.len() as u64 * 2, 2).unwrap();
+    for id in 1..=7 {
+        writer.write(&record(id)).unwrap();
+    }
+    writer.flush().unwrap();
+    let ids = |path: &Path| -> Vec<u64> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let id = line.split("\"id\":").nth(1).unwrap();
+                id.split(',').next().unwrap().parse().unwrap()
+            })
+            .collect()
+    };
+    assert_eq!(ids(&path), vec![7]);
+    assert_eq!(ids(&rotated_path(&path, 1)), vec![5, 6]);
+    assert_eq!(ids(&rotated_path(&path, 2)), vec![3, 4]);
+    assert!(!rotated_path(&path, 3).exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
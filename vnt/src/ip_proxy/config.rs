@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ip_proxy::access_log;
+use crate::ip_proxy::nat64::Nat64;
+use crate::ip_proxy::policy::ProxyPolicy;
+use crate::ip_proxy::port_filter::PortFilter;
+use crate::ip_proxy::proxy_protocol::ProxyProtocolRule;
+use crate::ip_proxy::registry::HandlerRegistry;
+use crate::ip_proxy::socks5::{Socks5Listen, UpstreamProxy};
+use crate::ip_proxy::tcp_proxy::ProxyObserver;
+#[cfg(feature = "tls")]
+use crate::ip_proxy::tls::TlsOrigin;
+use crate::ip_proxy::{tcp_proxy, udp_proxy, PacketObserver};
+
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    /// tcp代理监听的地址，只能是ipv4地址或者未指定地址
+    pub tcp_bind_addr: IpAddr,
+    /// 按目标端口过滤需要代理的tcp连接
+    pub tcp_port_filter: PortFilter,
+    /// 按目标网段和端口过滤需要代理的tcp连接，和tcp_port_filter都允许时才走代理
+    pub tcp_policy: ProxyPolicy,
+    /// tcp代理和socks5监听socket的backlog，突发大量连接时调大可以减少被丢弃的连接
+    pub tcp_listen_backlog: u32,
+    /// 监听socket开启SO_REUSEADDR，重启时端口还有TIME_WAIT的连接也能绑定。
+    /// windows上这个选项允许其他程序绑定同一个端口，所以默认只在其他平台开启
+    pub tcp_reuse_addr: bool,
+    /// tcp代理每个转发方向的缓冲区大小
+    pub tcp_buf_len: usize,
+    /// tcp代理连接真实目标的超时时间
+    pub tcp_connect_timeout: Duration,
+    /// 把来源SYN包的MSS改成不超过这个值，避免本地发给来源的数据段超过隧道mtu，
+    /// 为0时由vnt根据网卡mtu计算(mtu-40)，直接创建TcpProxy时为0则不修改
+    pub tcp_mss: u16,
+    /// tcp代理连接真实目标时必须使用来源的端口，端口被占用时连接失败，为false则改用随机端口
+    pub tcp_require_src_port: bool,
+    /// 目标在这些网段内时改为连接映射的ipv6地址，来源仍然使用ipv4，用于访问只有ipv6的服务
+    pub tcp_nat64: Vec<Nat64>,
+    /// 把来源SYN包的DSCP设置到连接目标的socket(IP_TOS)，让出口也带上来源的QoS标记，只用于ipv4目标
+    pub tcp_tos_passthrough: bool,
+    /// 目标是这些地址时改为连接本机的unix socket，不经过上游代理，只支持unix
+    pub tcp_unix_targets: HashMap<SocketAddrV4, PathBuf>,
+    /// 目标匹配这些规则时，连接目标后先发送PROXY protocol头部，携带真实的来源地址，按配置顺序第一条匹配的生效
+    pub tcp_proxy_protocol: Vec<ProxyProtocolRule>,
+    /// 目标匹配这些规则时，连接目标(和发送PROXY protocol头部)后发起TLS握手，把来源的明文加密后转发，为None则不发起
+    #[cfg(feature = "tls")]
+    pub tcp_tls: Option<TlsOrigin>,
+    /// tcp代理连接ipv4目标(或上游代理)时使用的本地地址，多网卡时用来选择出口，为None则由系统选择
+    pub tcp_egress_bind: Option<Ipv4Addr>,
+    /// tcp代理连接目标的socket绑定到这个网卡(SO_BINDTODEVICE)，只支持linux
+    pub tcp_egress_device: Option<String>,
+    /// tcp代理连接真实目标(或上游代理)的socket设置的SO_MARK，用于策略路由，只支持linux
+    pub tcp_fwmark: Option<u32>,
+    /// tcp代理两端连接是否开启TCP_NODELAY，交互式的流量（如ssh）开启后延迟更低
+    pub tcp_nodelay: bool,
+    /// 目标是这些端口时总是开启TCP_NODELAY，例如22
+    pub tcp_nodelay_ports: Vec<u16>,
+    /// tcp代理两端连接开启TCP keepalive，连接空闲超过这个时间后由系统发送探测包，
+    /// 避免中间设备丢弃长时间空闲的连接，为0则不开启
+    pub tcp_keepalive_idle: Duration,
+    /// keepalive探测包的发送间隔，为0则使用系统默认值
+    pub tcp_keepalive_interval: Duration,
+    /// keepalive连续探测失败多少次后断开连接，为0则使用系统默认值，windows上不支持
+    pub tcp_keepalive_count: u32,
+    /// tcp代理连接两个方向都没有数据超过这个时间就关闭
+    pub tcp_idle_timeout: Duration,
+    /// tcp代理写入超过这个时间没有任何进展（对端不再读取）就关闭连接，为0则一直等待
+    pub tcp_write_timeout: Duration,
+    /// tcp代理的nat映射超过这个时间没有使用就删除
+    pub tcp_nat_ttl: Duration,
+    /// tcp代理nat映射的最大数量，达到上限时删除最久没有使用的映射，为0则不限制
+    pub tcp_nat_max: usize,
+    /// 停止时先不再接收新的tcp连接，等待已有连接结束的最长时间，为0则立即关闭
+    pub tcp_drain_timeout: Duration,
+    /// tcp代理同时转发的最大连接数，达到上限后新连接直接关闭，为0则不限制
+    pub tcp_max_connections: usize,
+    /// tcp代理到同一个目标ip的最大连接数，为0则不限制
+    pub tcp_max_connections_per_dest: usize,
+    /// tcp代理和socks5合计每秒最多接收的新连接数，最多积累1秒的突发，为0则不限制
+    pub tcp_accept_rate: u64,
+    /// tcp代理连接真实目标的方式，可以经过上游socks5代理
+    pub tcp_upstream: UpstreamProxy,
+    /// tcp代理所有连接合计的转发速率上限(字节/秒)，为0则不限制
+    pub tcp_rate_limit: u64,
+    /// tcp代理单个连接两个方向合计的转发速率上限(字节/秒)，为0则不限制，和tcp_rate_limit同时生效
+    pub tcp_rate_limit_per_conn: u64,
+    /// 内置socks5服务端，应用可以直接通过它连接目标，不经过tun，为None则不开启。
+    /// 没有设置认证时只能监听回环地址
+    pub socks5: Option<Socks5Listen>,
+    /// tcp代理连接开始和结束的回调
+    pub tcp_observer: Option<Arc<dyn ProxyObserver>>,
+    /// 每条tcp代理连接结束时写一行json访问日志到这个文件，为None则不写
+    pub tcp_access_log: Option<PathBuf>,
+    /// 访问日志文件超过这个大小(字节)时轮转，为0则不轮转
+    pub tcp_access_log_max_size: u64,
+    /// 轮转时保留的旧日志文件数
+    pub tcp_access_log_max_files: usize,
+    /// udp代理的映射和转发socket超过这个时间没有数据就删除
+    pub udp_idle_timeout: Duration,
+    /// 拦截经过代理的udp dns查询，转发到这个dns服务器，为None则不拦截
+    pub dns_upstream: Option<SocketAddr>,
+    /// 只拦截这些域名(包括子域名)的dns查询，为空则拦截所有查询
+    pub dns_domains: Vec<String>,
+    /// 自定义的代理处理器，优先于内置代理
+    pub handlers: HandlerRegistry,
+    /// 只读的流量观察者，为None则不观察
+    pub packet_observer: Option<Arc<dyn PacketObserver>>,
+    /// init_proxy创建的运行时的工作线程数，所有代理共用，为0则等于cpu核数
+    pub worker_threads: usize,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            tcp_bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            tcp_port_filter: PortFilter::All,
+            tcp_policy: ProxyPolicy::default(),
+            tcp_listen_backlog: tcp_proxy::DEFAULT_LISTEN_BACKLOG,
+            tcp_reuse_addr: cfg!(not(target_os = "windows")),
+            tcp_buf_len: tcp_proxy::DEFAULT_BUF_LEN,
+            tcp_connect_timeout: tcp_proxy::DEFAULT_CONNECT_TIMEOUT,
+            tcp_mss: 0,
+            tcp_require_src_port: false,
+            tcp_nat64: Vec::new(),
+            tcp_tos_passthrough: false,
+            tcp_unix_targets: HashMap::new(),
+            tcp_proxy_protocol: Vec::new(),
+            #[cfg(feature = "tls")]
+            tcp_tls: None,
+            tcp_egress_bind: None,
+            tcp_egress_device: None,
+            tcp_fwmark: None,
+            tcp_nodelay: false,
+            tcp_nodelay_ports: Vec::new(),
+            tcp_keepalive_idle: Duration::ZERO,
+            tcp_keepalive_interval: Duration::ZERO,
+            tcp_keepalive_count: 0,
+            tcp_idle_timeout: tcp_proxy::DEFAULT_IDLE_TIMEOUT,
+            tcp_write_timeout: Duration::ZERO,
+            tcp_nat_ttl: tcp_proxy::DEFAULT_NAT_TTL,
+            tcp_nat_max: 0,
+            tcp_drain_timeout: Duration::ZERO,
+            tcp_max_connections: 0,
+            tcp_max_connections_per_dest: 0,
+            tcp_accept_rate: 0,
+            tcp_upstream: UpstreamProxy::Direct,
+            tcp_rate_limit: 0,
+            tcp_rate_limit_per_conn: 0,
+            socks5: None,
+            tcp_observer: None,
+            tcp_access_log: None,
+            tcp_access_log_max_size: access_log::DEFAULT_MAX_SIZE,
+            tcp_access_log_max_files: access_log::DEFAULT_MAX_FILES,
+            udp_idle_timeout: udp_proxy::DEFAULT_IDLE_TIMEOUT,
+            dns_upstream: None,
+            dns_domains: Vec::new(),
+            handlers: HandlerRegistry::default(),
+            packet_observer: None,
+            worker_threads: 0,
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// 到目标端口的tcp连接是否开启TCP_NODELAY
+    pub fn tcp_nodelay_for(&self, dest_port: u16) -> bool {
+        self.tcp_nodelay || self.tcp_nodelay_ports.contains(&dest_port)
+    }
+}
+
+#[test]
+fn test_tcp_nodelay_for() {
+    let mut config = ProxyConfig {
+        tcp_nodelay_ports: vec![22],
+        ..ProxyConfig::default()
+    };
+    assert!(config.tcp_nodelay_for(22));
+    assert!(!config.tcp_nodelay_for(80));
+    config.tcp_nodelay = true;
+    assert!(config.tcp_nodelay_for(80));
+}
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+
+use packet::ip::ipv4::packet::IpV4Packet;
+use packet::ip::ipv4::protocol::Protocol;
+use packet::udp::udp::UdpPacket;
+
+use crate::ip_proxy::{spawn_evict, NatMap, ProxyAction, ProxyHandler};
+
+pub const DNS_PORT: u16 = 53;
+/// 等待上游dns回复的时间
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+/// 映射保留的时间，要比DNS_TIMEOUT长
+const DNS_NAT_TTL: Duration = Duration::from_secs(60);
+
+/// 拦截从虚拟网络来的udp dns查询，转发给配置的上游dns服务器，回复的来源还原成原来的dns服务器。
+/// 和udp代理一样把目标改成本地端口后写入tun，只拦截domains里的域名(包括子域名)，为空则拦截所有查询。
+///
+/// tcp的dns查询在建立连接时还不知道域名，不拦截，仍然由tcp代理连接原来的dns服务器
+#[derive(Clone)]
+pub struct DnsProxy {
+    port: u16,
+    domains: Arc<Vec<String>>,
+    /// 来源地址 -> (原来的dns服务器, 最后使用时间)
+    nat_map: NatMap,
+}
+
+impl DnsProxy {
+    pub async fn new(upstream: SocketAddr, domains: Vec<String>) -> anyhow::Result<Self> {
+        let udp = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .context("DnsProxy bind failed")?;
+        let port = udp.local_addr()?.port();
+        tokio::spawn(dns_proxy(Arc::new(udp), upstream));
+        let nat_map: NatMap = Arc::new(Mutex::new(HashMap::with_capacity(16)));
+        spawn_evict(nat_map.clone(), DNS_NAT_TTL);
+        let domains = domains
+            .iter()
+            .map(|domain| domain.trim().trim_matches('.').to_ascii_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        Ok(Self {
+            port,
+            domains: Arc::new(domains),
+            nat_map,
+        })
+    }
+    fn is_intercepted(&self, name: &str) -> bool {
+        self.domains.is_empty()
+            || self.domains.iter().any(|domain| {
+                name == domain
+                    || name
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            })
+    }
+    /// 需要拦截的查询把目标改成本地端口，返回是否拦截
+    pub fn intercept(
+        &self,
+        ipv4: &mut IpV4Packet<&mut [u8]>,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> io::Result<bool> {
+        if ipv4.protocol() != Protocol::Udp {
+            return Ok(false);
+        }
+        let dest_ip = ipv4.destination_ip();
+        // udp头不完整的不拦截，由udp代理丢弃
+        let Ok(mut udp_packet) = UdpPacket::new(source, destination, ipv4.payload_mut()) else {
+            return Ok(false);
+        };
+        if udp_packet.destination_port() != DNS_PORT {
+            return Ok(false);
+        }
+        match query_name(udp_packet.payload()) {
+            Some(name) if self.is_intercepted(&name) => {}
+            _ => return Ok(false),
+        }
+        let key = SocketAddrV4::new(source, udp_packet.source_port());
+        udp_packet.set_destination_port(self.port);
+        udp_packet.update_checksum();
+        ipv4.set_destination_ip(destination);
+        ipv4.update_checksum();
+        self.nat_map
+            .lock()
+            .insert(key, (SocketAddrV4::new(dest_ip, DNS_PORT), Instant::now()));
+        Ok(true)
+    }
+    /// 拦截的查询的回复把来源还原成原来的dns服务器，返回是否还原
+    pub fn restore(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<bool> {
+        if ipv4.protocol() != Protocol::Udp {
+            return Ok(false);
+        }
+        let src_ip = ipv4.source_ip();
+        let dest_ip = ipv4.destination_ip();
+        let dest_addr = {
+            let Ok(udp_packet) = UdpPacket::new(src_ip, dest_ip, ipv4.payload_mut()) else {
+                return Ok(false);
+            };
+            if udp_packet.source_port() != self.port {
+                return Ok(false);
+            }
+            SocketAddrV4::new(dest_ip, udp_packet.destination_port())
+        };
+        let server_addr = self.nat_map.lock().get(&dest_addr).map(|(addr, _)| *addr);
+        let Some(server_addr) = server_addr else {
+            return Ok(false);
+        };
+        let server_ip = *server_addr.ip();
+        let mut udp_packet = UdpPacket::new(server_ip, dest_ip, ipv4.payload_mut())?;
+        udp_packet.set_source_port(server_addr.port());
+        udp_packet.update_checksum();
+        ipv4.set_source_ip(server_ip);
+        ipv4.update_checksum();
+        Ok(true)
+    }
+}
+
+/// 改写后的查询还需要写入tun，所以总是返回PassThrough。
+/// 不能注册到HandlerRegistry，否则改写后的包还会交给内置的udp代理，配置了dns_upstream时由IpProxyMap在udp代理之前调用
+impl ProxyHandler for DnsProxy {
+    fn recv_handle(
+        &self,
+        ipv4: &mut IpV4Packet<&mut [u8]>,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> io::Result<ProxyAction> {
+        self.intercept(ipv4, source, destination)?;
+        Ok(ProxyAction::PassThrough)
+    }
+
+    fn send_handle(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
+        self.restore(ipv4)?;
+        Ok(())
+    }
+}
+
+/// 解析dns查询中第一个问题的域名(小写)，不是查询或者格式不对时返回None
+fn query_name(dns: &[u8]) -> Option<String> {
+    // 头部12字节，QR为1的是回复，QDCOUNT为0的没有问题
+    if dns.len() < 12 || dns[2] & 0x80 != 0 || u16::from_be_bytes([dns[4], dns[5]]) == 0 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *dns.get(pos)? as usize;
+        if len == 0 {
+            break;
+        }
+        // 查询的第一个问题前面没有可以引用的域名，不会出现压缩指针
+        if len > 63 {
+            return None;
+        }
+        let label = dns.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += 1 + len;
+    }
+    Some(labels.join("."))
+}
+
+async fn dns_proxy(udp: Arc<UdpSocket>, upstream: SocketAddr) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, sender_addr) = match udp.recv_from(&mut buf).await {
+            Ok(rs) => rs,
+            Err(e) => {
+                log::warn!("dns代理异常:{:?}", e);
+                continue;
+            }
+        };
+        let query = buf[..len].to_vec();
+        let udp = udp.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(DNS_TIMEOUT, forward(&query, upstream)).await {
+                Ok(Ok(reply)) => {
+                    if let Err(e) = udp.send_to(&reply, sender_addr).await {
+                        log::warn!("dns proxy {}->{} {:?}", sender_addr, upstream, e);
+                    }
+                }
+                Ok(Err(e)) => {
+                    log::warn!("dns proxy {}->{} {:?}", sender_addr, upstream, e);
+                }
+                Err(_) => {
+                    log::warn!("dns proxy timeout {}->{}", sender_addr, upstream);
+                }
+            }
+        });
+    }
+}
+
+/// 每个查询使用单独的socket，不需要按事务id区分回复
+async fn forward(query: &[u8], upstream: SocketAddr) -> io::Result<Vec<u8>> {
+    let bind_addr: SocketAddr = if upstream.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; 65536];
+    let len = socket.recv(&mut buf).await?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// 构造一个查询A记录的dns请求
+#[cfg(test)]
+fn dns_query(id: u16, name: &str) -> Vec<u8> {
+    let mut buf = id.to_be_bytes().to_vec();
+    buf.extend_from_slice(&[1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.extend_from_slice(&[0, 0, 1, 0, 1]);
+    buf
+}
+
+#[test]
+fn test_query_name() {
+    assert_eq!(
+        query_name(&dns_query(1, "WWW.Example.com")).as_deref(),
+        Some("www.example.com")
+    );
+    let mut reply = dns_query(1, "example.com");
+    reply[2] |= 0x80;
+    assert_eq!(query_name(&reply), None);
+    // 长度超出包的范围
+    let query = dns_query(1, "example.com");
+    assert_eq!(query_name(&query[..16]), None);
+    assert_eq!(query_name(&query[..8]), None);
+}
+
+#[tokio::test]
+async fn test_dns_proxy() {
+    // 模拟的上游dns，把查询的QR置1后原样回复
+    let resolver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let resolver_addr = resolver.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        while let Ok((len, addr)) = resolver.recv_from(&mut buf).await {
+            buf[2] |= 0x80;
+            let _ = resolver.send_to(&buf[..len], addr).await;
+        }
+    });
+    let proxy = DnsProxy::new(resolver_addr, vec![".Example.com.".to_string()])
+        .await
+        .unwrap();
+    let server = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), DNS_PORT);
+    let local_ip = Ipv4Addr::LOCALHOST;
+    // 真实的查询从这个socket发出，相当于来源的查询写入tun后交给了本地的代理端口
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let guest = match client.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    // 不在域名列表里的不拦截
+    for name in ["example.org", "badexample.com"] {
+        let query = dns_query(1, name);
+        let mut buf = crate::ip_proxy::udp_proxy::udp_ipv4_packet(guest, server, &query);
+        let origin = buf.clone();
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        assert!(!proxy.intercept(&mut ipv4, *guest.ip(), local_ip).unwrap());
+        assert_eq!(buf, origin);
+    }
+
+    let query = dns_query(2, "www.example.com");
+    let mut buf = crate::ip_proxy::udp_proxy::udp_ipv4_packet(guest, server, &query);
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        proxy.recv_handle(&mut ipv4, *guest.ip(), local_ip).unwrap(),
+        ProxyAction::PassThrough
+    );
+    assert_eq!(ipv4.destination_ip(), local_ip);
+    let udp_packet = UdpPacket::new(*guest.ip(), local_ip, ipv4.payload_mut()).unwrap();
+    assert!(udp_packet.is_valid());
+    assert_eq!(udp_packet.destination_port(), proxy.port);
+
+    client
+        .send_to(udp_packet.payload(), (local_ip, proxy.port))
+        .await
+        .unwrap();
+    let mut recv_buf = [0u8; 1500];
+    let (len, from) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut recv_buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(from.port(), proxy.port);
+    assert_eq!(recv_buf[2] & 0x80, 0x80);
+    assert_eq!(&recv_buf[3..len], &query[3..]);
+
+    // 回复的来源还原成原来的dns服务器
+    let mut buf = crate::ip_proxy::udp_proxy::udp_ipv4_packet(
+        SocketAddrV4::new(local_ip, proxy.port),
+        guest,
+        &recv_buf[..len],
+    );
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    proxy.send_handle(&mut ipv4).unwrap();
+    assert!(ipv4.is_valid());
+    assert_eq!(ipv4.source_ip(), *server.ip());
+    let udp_packet = UdpPacket::new(*server.ip(), *guest.ip(), ipv4.payload_mut()).unwrap();
+    assert!(udp_packet.is_valid());
+    assert_eq!(udp_packet.source_port(), DNS_PORT);
+    assert_eq!(udp_packet.destination_port(), guest.port());
+    // 不是代理端口发出的包不修改
+    let mut buf = crate::ip_proxy::udp_proxy::udp_ipv4_packet(
+        SocketAddrV4::new(local_ip, 1000),
+        guest,
+        b"data",
+    );
+    let origin = buf.clone();
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert!(!proxy.restore(&mut ipv4).unwrap());
+    assert_eq!(buf, origin);
+}
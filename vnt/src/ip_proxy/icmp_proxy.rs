@@ -3,26 +3,136 @@ use std::collections::HashMap;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
 use tokio::net::UdpSocket;
 
-use packet::icmp::icmp;
 use packet::icmp::icmp::HeaderOther;
+use packet::icmp::{icmp, Kind};
 use packet::ip::ipv4::packet::IpV4Packet;
 
 use crate::channel::context::ChannelContext;
 use crate::cipher::Cipher;
 use crate::handle::CurrentDeviceInfo;
-use crate::ip_proxy::ProxyHandler;
+use crate::ip_proxy::{spawn_evict, Evict, ProxyAction, ProxyHandler};
 use crate::protocol;
 use crate::protocol::{NetPacket, MAX_TTL};
+
+/// 收不到回复的请求超过这个时间就删除
+pub const ICMP_TTL: Duration = Duration::from_secs(10);
+
+type IcmpNatMap = Arc<Mutex<IcmpNat>>;
+
+/// 不同来源可能用相同的identifier去ping同一个目标，所以发出去前把identifier换成代理分配的，
+/// 回复时再换回来，和tcp/udp代理改写端口是一样的
+#[derive(Default)]
+struct IcmpNat {
+    next_id: u16,
+    /// (真实来源,identifier) -> 代理identifier
+    requests: HashMap<(Ipv4Addr, u16), u16>,
+    /// 代理identifier -> ((真实来源,identifier), 最后使用时间)
+    replies: HashMap<u16, ((Ipv4Addr, u16), Instant)>,
+}
+
+impl IcmpNat {
+    /// 给(来源,identifier)分配代理identifier，同一个来源持续ping时使用同一个，全部占满时返回None
+    fn map_request(&mut self, source: Ipv4Addr, id: u16, now: Instant) -> Option<u16> {
+        if let Some(proxy_id) = self.requests.get(&(source, id)).copied() {
+            if let Some((_, time)) = self.replies.get_mut(&proxy_id) {
+                *time = now;
+            }
+            return Some(proxy_id);
+        }
+        if self.replies.len() > u16::MAX as usize {
+            return None;
+        }
+        loop {
+            let proxy_id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.replies.contains_key(&proxy_id) {
+                self.requests.insert((source, id), proxy_id);
+                self.replies.insert(proxy_id, ((source, id), now));
+                return Some(proxy_id);
+            }
+        }
+    }
+    /// 用回复里的代理identifier找到(真实来源,identifier)
+    fn map_reply(&mut self, proxy_id: u16, now: Instant) -> Option<(Ipv4Addr, u16)> {
+        self.replies.get_mut(&proxy_id).map(|(key, time)| {
+            *time = now;
+            *key
+        })
+    }
+}
+
+impl Evict for IcmpNat {
+    fn evict(&mut self, ttl: Duration, now: Instant) {
+        let requests = &mut self.requests;
+        self.replies.retain(|_, (key, time)| {
+            let keep = now.saturating_duration_since(*time) < ttl;
+            if !keep {
+                requests.remove(key);
+            }
+            keep
+        });
+    }
+}
+
+/// 把请求的identifier换成代理identifier，返回需要发出去的icmp数据
+fn rewrite_request<'a>(
+    ipv4: &'a mut IpV4Packet<&mut [u8]>,
+    source: Ipv4Addr,
+    nat: &Mutex<IcmpNat>,
+) -> io::Result<Option<&'a [u8]>> {
+    let mut icmp_packet = icmp::IcmpPacket::new(ipv4.payload_mut())?;
+    match icmp_packet.header_other() {
+        HeaderOther::Identifier(id, _) => {
+            let Some(proxy_id) = nat.lock().map_request(source, id, Instant::now()) else {
+                log::warn!("icmp代理identifier已用完，丢弃{}的请求", source);
+                return Ok(None);
+            };
+            icmp_packet.set_identifier(proxy_id);
+            icmp_packet.update_checksum();
+            Ok(Some(ipv4.payload()))
+        }
+        header_other => {
+            log::warn!(
+                "不支持的ip代理Icmp协议:{}->{},{:?}",
+                source,
+                ipv4.destination_ip(),
+                header_other
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// 把回复还原成发给真实来源的包，返回真实来源，不是代理请求的回复返回None
+fn restore_reply(ipv4: &mut IpV4Packet<&mut [u8]>, nat: &Mutex<IcmpNat>) -> Option<Ipv4Addr> {
+    let mut icmp_packet = icmp::IcmpPacket::new(ipv4.payload_mut()).ok()?;
+    if !matches!(
+        icmp_packet.kind(),
+        Kind::EchoReply | Kind::TimestampReply | Kind::InformationReply
+    ) {
+        return None;
+    }
+    let HeaderOther::Identifier(proxy_id, _) = icmp_packet.header_other() else {
+        return None;
+    };
+    let (source, id) = nat.lock().map_reply(proxy_id, Instant::now())?;
+    icmp_packet.set_identifier(id);
+    icmp_packet.update_checksum();
+    ipv4.set_destination_ip(source);
+    ipv4.update_checksum();
+    Some(source)
+}
+
 #[derive(Clone)]
 pub struct IcmpProxy {
     icmp_socket: Arc<std::net::UdpSocket>,
-    // 对端-> 真实来源
-    nat_map: Arc<Mutex<HashMap<(Ipv4Addr, u16, u16), Ipv4Addr>>>,
+    nat_map: IcmpNatMap,
 }
 
 impl IcmpProxy {
@@ -53,8 +163,7 @@ impl IcmpProxy {
         let std_socket: std::net::UdpSocket = icmp_socket.into();
 
         let tokio_icmp_socket = UdpSocket::from_std(std_socket.try_clone()?)?;
-        let nat_map: Arc<Mutex<HashMap<(Ipv4Addr, u16, u16), Ipv4Addr>>> =
-            Arc::new(Mutex::new(HashMap::with_capacity(16)));
+        let nat_map: IcmpNatMap = Arc::new(Mutex::new(IcmpNat::default()));
         {
             let nat_map = nat_map.clone();
             tokio::spawn(async {
@@ -71,6 +180,7 @@ impl IcmpProxy {
                 }
             });
         }
+        spawn_evict(nat_map.clone(), ICMP_TTL);
         Ok(Self {
             icmp_socket: Arc::new(std_socket),
             nat_map,
@@ -80,8 +190,7 @@ impl IcmpProxy {
 
 async fn icmp_proxy(
     icmp_socket: UdpSocket,
-    // 对端-> 真实来源
-    nat_map: Arc<Mutex<HashMap<(Ipv4Addr, u16, u16), Ipv4Addr>>>,
+    nat_map: IcmpNatMap,
     context: ChannelContext,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     client_cipher: Cipher,
@@ -125,56 +234,43 @@ fn recv_handle(
     buf: &mut [u8],
     data_len: usize,
     peer_ip: Ipv4Addr,
-    nat_map: &Mutex<HashMap<(Ipv4Addr, u16, u16), Ipv4Addr>>,
+    nat_map: &IcmpNatMap,
     context: &ChannelContext,
     current_device: &AtomicCell<CurrentDeviceInfo>,
     client_cipher: &Cipher,
 ) {
-    match IpV4Packet::new(&mut buf[12..data_len]) {
-        Ok(mut ipv4_packet) => match icmp::IcmpPacket::new(ipv4_packet.payload()) {
-            Ok(icmp_packet) => match icmp_packet.header_other() {
-                HeaderOther::Identifier(id, seq) => {
-                    if let Some(dest_ip) = nat_map.lock().get(&(peer_ip, id, seq)).cloned() {
-                        ipv4_packet.set_destination_ip(dest_ip);
-                        ipv4_packet.update_checksum();
-
-                        let current_device = current_device.load();
-                        let virtual_ip = current_device.virtual_ip();
-
-                        let mut net_packet = NetPacket::new0(data_len, buf).unwrap();
-                        net_packet.set_default_version();
-                        net_packet.set_protocol(protocol::Protocol::IpTurn);
-                        net_packet.set_transport_protocol(
-                            protocol::ip_turn_packet::Protocol::Ipv4.into(),
-                        );
-                        net_packet.first_set_ttl(MAX_TTL);
-                        net_packet.set_source(virtual_ip);
-                        net_packet.set_destination(dest_ip);
-                        if let Err(e) = client_cipher.encrypt_ipv4(&mut net_packet) {
-                            log::warn!("加密失败:{}", e);
-                            return;
-                        }
-                        if let Err(e) = context.send_ipv4_by_id(
-                            net_packet.buffer(),
-                            &dest_ip,
-                            current_device.connect_server,
-                            current_device.status.online(),
-                        ) {
-                            log::warn!("发送到目标失败:{}", e);
-                        }
-                    }
-                }
-                h => {
-                    log::debug!("不支持的icmp代理 {:?},{:?}", peer_ip, h)
-                }
-            },
-            Err(e) => {
-                log::warn!("icmp {:?}", e)
-            }
-        },
+    let mut ipv4_packet = match IpV4Packet::new(&mut buf[12..data_len]) {
+        Ok(ipv4_packet) => ipv4_packet,
         Err(e) => {
-            log::warn!("icmp {:?}", e)
+            log::warn!("icmp {:?}", e);
+            return;
         }
+    };
+    let Some(dest_ip) = restore_reply(&mut ipv4_packet, nat_map) else {
+        log::debug!("不是icmp代理的回复 {:?}", peer_ip);
+        return;
+    };
+    let current_device = current_device.load();
+    let virtual_ip = current_device.virtual_ip();
+
+    let mut net_packet = NetPacket::new0(data_len, buf).unwrap();
+    net_packet.set_default_version();
+    net_packet.set_protocol(protocol::Protocol::IpTurn);
+    net_packet.set_transport_protocol(protocol::ip_turn_packet::Protocol::Ipv4.into());
+    net_packet.first_set_ttl(MAX_TTL);
+    net_packet.set_source(virtual_ip);
+    net_packet.set_destination(dest_ip);
+    if let Err(e) = client_cipher.encrypt_ipv4(&mut net_packet) {
+        log::warn!("加密失败:{}", e);
+        return;
+    }
+    if let Err(e) = context.send_ipv4_by_id(
+        net_packet.buffer(),
+        &dest_ip,
+        current_device.connect_server,
+        current_device.status.online(),
+    ) {
+        log::warn!("发送到目标失败:{}", e);
     }
 }
 
@@ -184,37 +280,98 @@ impl ProxyHandler for IcmpProxy {
         &self,
         ipv4: &mut IpV4Packet<&mut [u8]>,
         source: Ipv4Addr,
-        destination: Ipv4Addr,
-    ) -> io::Result<bool> {
+        _destination: Ipv4Addr,
+    ) -> io::Result<ProxyAction> {
         if ipv4.offset() != 0 || ipv4.flags() & 1 == 1 {
             // ip分片的直接丢弃
-            return Ok(true);
+            return Ok(ProxyAction::Drop);
+        }
+        if icmp::IcmpPacket::new(ipv4.payload()).is_err() {
+            // icmp头不完整
+            return Ok(ProxyAction::Drop);
         }
         let dest_ip = ipv4.destination_ip();
         //转发到代理目标地址
-        let icmp_packet = icmp::IcmpPacket::new(ipv4.payload())?;
-        match icmp_packet.header_other() {
-            HeaderOther::Identifier(id, seq) => {
-                self.nat_map.lock().insert((dest_ip, id, seq), source);
-                self.icmp_socket.send_to(
-                    ipv4.payload(),
-                    SocketAddr::from(SocketAddrV4::new(dest_ip, 0)),
-                )?;
-            }
-            header_other => {
-                log::warn!(
-                    "不支持的ip代理Icmp协议:{}->{}->{},{:?}",
-                    source,
-                    destination,
-                    dest_ip,
-                    header_other
-                );
-            }
+        if let Some(icmp) = rewrite_request(ipv4, source, &self.nat_map)? {
+            self.icmp_socket
+                .send_to(icmp, SocketAddr::from(SocketAddrV4::new(dest_ip, 0)))?;
         }
-        Ok(true)
+        Ok(ProxyAction::Handled)
     }
 
     fn send_handle(&self, _ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+fn echo_packet(kind: Kind, source: Ipv4Addr, destination: Ipv4Addr, id: u16) -> Vec<u8> {
+    let mut icmp = [0u8; 8 + 4];
+    icmp[4..6].copy_from_slice(&id.to_be_bytes());
+    icmp[6..8].copy_from_slice(&7u16.to_be_bytes());
+    icmp[8..].copy_from_slice(b"ping");
+    let mut buf = super::ipv4_packet(
+        packet::ip::ipv4::protocol::Protocol::Icmp,
+        source,
+        destination,
+        &icmp,
+    );
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    let mut icmp_packet = icmp::IcmpPacket::new(ipv4.payload_mut()).unwrap();
+    icmp_packet.set_kind(kind);
+    icmp_packet.update_checksum();
+    buf
+}
+
+#[test]
+fn test_identifier_rewrite() {
+    let nat = Mutex::new(IcmpNat::default());
+    let target = Ipv4Addr::new(192, 168, 1, 2);
+    let local = Ipv4Addr::new(192, 168, 1, 10);
+    let mut proxy_ids = Vec::new();
+    for source in [Ipv4Addr::new(10, 26, 0, 2), Ipv4Addr::new(10, 26, 0, 3)] {
+        // 两个来源用同一个identifier
+        let mut request = echo_packet(Kind::EchoRequest, source, target, 0x1234);
+        let mut ipv4 = IpV4Packet::new(&mut request[..]).unwrap();
+        let icmp_data = rewrite_request(&mut ipv4, source, &nat)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        let icmp_packet = icmp::IcmpPacket::new(&icmp_data[..]).unwrap();
+        assert!(icmp_packet.is_valid());
+        let HeaderOther::Identifier(proxy_id, 7) = icmp_packet.header_other() else {
+            panic!()
+        };
+        assert!(!proxy_ids.contains(&proxy_id));
+        proxy_ids.push(proxy_id);
+
+        let mut reply = echo_packet(Kind::EchoReply, target, local, proxy_id);
+        let mut ipv4 = IpV4Packet::new(&mut reply[..]).unwrap();
+        assert_eq!(restore_reply(&mut ipv4, &nat), Some(source));
+        assert!(ipv4.is_valid());
+        assert_eq!(ipv4.source_ip(), target);
+        assert_eq!(ipv4.destination_ip(), source);
+        let icmp_packet = icmp::IcmpPacket::new(ipv4.payload()).unwrap();
+        assert!(icmp_packet.is_valid());
+        assert!(matches!(
+            icmp_packet.header_other(),
+            HeaderOther::Identifier(0x1234, 7)
+        ));
+    }
+    // 同一个来源继续ping使用同一个代理identifier
+    let source = Ipv4Addr::new(10, 26, 0, 2);
+    assert_eq!(
+        nat.lock().map_request(source, 0x1234, Instant::now()),
+        Some(proxy_ids[0])
+    );
+    // 请求不会被当成回复
+    let mut request = echo_packet(Kind::EchoRequest, target, local, proxy_ids[0]);
+    let mut ipv4 = IpV4Packet::new(&mut request[..]).unwrap();
+    assert_eq!(restore_reply(&mut ipv4, &nat), None);
+    // 过期后删除
+    nat.lock().evict(ICMP_TTL, Instant::now() + ICMP_TTL);
+    let mut reply = echo_packet(Kind::EchoReply, target, local, proxy_ids[1]);
+    let mut ipv4 = IpV4Packet::new(&mut reply[..]).unwrap();
+    assert_eq!(restore_reply(&mut ipv4, &nat), None);
+    assert!(nat.lock().requests.is_empty());
+}
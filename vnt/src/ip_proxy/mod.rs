@@ -1,42 +1,187 @@
-use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
+use anyhow::Context;
 use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
 
 use packet::ip::ipv4;
 use packet::ip::ipv4::packet::IpV4Packet;
+use packet::ip::ipv4::protocol::Protocol;
 
 use crate::channel::context::ChannelContext;
 use crate::cipher::Cipher;
 use crate::handle::CurrentDeviceInfo;
+use crate::ip_proxy::dns_proxy::DnsProxy;
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 use crate::ip_proxy::icmp_proxy::IcmpProxy;
-use crate::ip_proxy::tcp_proxy::TcpProxy;
+use crate::ip_proxy::port_filter::PortFilter;
+use crate::ip_proxy::registry::HandlerRegistry;
+use crate::ip_proxy::tcp_proxy::{ConnSnapshot, ProxyStatsSnapshot, TcpProxy};
 use crate::ip_proxy::udp_proxy::UdpProxy;
 use crate::util::StopManager;
 
+mod config;
+pub use config::ProxyConfig;
+
+pub mod access_log;
+pub mod dns_proxy;
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 pub mod icmp_proxy;
+pub mod nat64;
+pub mod policy;
+pub mod port_filter;
+pub mod proxy_protocol;
+mod rate_limit;
+pub mod registry;
+pub mod socks5;
 pub mod tcp_proxy;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod udp_proxy;
 
+/// 来源地址 -> (真实目标地址, 最后使用时间)
+pub(crate) type NatMap = Arc<Mutex<HashMap<SocketAddrV4, (SocketAddrV4, Instant)>>>;
+
+/// 代理处理器对收到的包的处理结果
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProxyAction {
+    /// 包已被代理接管（例如已经转发出去），调用方不会再把它写入tun，也不再交给后面的处理器
+    Handled,
+    /// 调用方继续处理这个包，最后写入tun，代理可以先就地改写包
+    /// （例如把目标改成本机代理的监听地址），改写后需要自己更新校验和
+    PassThrough,
+    /// 丢弃这个包（例如被禁止的端口），调用方不会再把它写入tun，也不再交给后面的处理器
+    Drop,
+}
+
+impl ProxyAction {
+    /// 包是否已经被处理器认领（接管或者丢弃）
+    pub fn is_claimed(self) -> bool {
+        self != ProxyAction::PassThrough
+    }
+}
+
+/// 虚拟网络只承载ipv4，ipv6的数据不会进入代理，所以这里只处理IpV4Packet
 pub trait ProxyHandler {
+    /// 处理从虚拟网络收到、真实目标不是本机虚拟ip的包（source是对端虚拟ip，destination是本机虚拟ip）。
+    ///
+    /// 返回错误时包不会写入tun，错误交给调用方处理。
+    /// 包来自虚拟网络里的其他设备，传输层头部不完整的包应该返回Drop，不能panic
     fn recv_handle(
         &self,
         ipv4: &mut IpV4Packet<&mut [u8]>,
         source: Ipv4Addr,
         destination: Ipv4Addr,
-    ) -> io::Result<bool>;
+    ) -> io::Result<ProxyAction>;
+    /// 处理从tun读到、准备发往虚拟网络的包，把代理回复的包就地还原成真实目标的地址和端口，
+    /// 不是代理产生的包保持不变。这里不能丢弃包，处理完后总是会发送出去
     fn send_handle(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()>;
+    /// 串联另一个处理器，这个处理器返回PassThrough时才交给next
+    fn chain<B: ProxyHandler>(self, next: B) -> Chain<Self, B>
+    where
+        Self: Sized,
+    {
+        Chain { first: self, next }
+    }
+}
+
+/// 依次执行两个处理器，直到有一个认领了包；
+/// first返回PassThrough时改写过的包会交给next。回复包依次经过两个处理器还原
+#[derive(Clone)]
+pub struct Chain<A, B> {
+    first: A,
+    next: B,
+}
+
+impl<A: ProxyHandler, B: ProxyHandler> ProxyHandler for Chain<A, B> {
+    fn recv_handle(
+        &self,
+        ipv4: &mut IpV4Packet<&mut [u8]>,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> io::Result<ProxyAction> {
+        match self.first.recv_handle(ipv4, source, destination)? {
+            ProxyAction::PassThrough => self.next.recv_handle(ipv4, source, destination),
+            action => Ok(action),
+        }
+    }
+
+    fn send_handle(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
+        self.first.send_handle(ipv4)?;
+        self.next.send_handle(ipv4)
+    }
+}
+
+/// PacketObserver看到的数据方向
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// 从虚拟网络收到、交给代理处理前的包
+    Recv,
+    /// 代理还原后发往虚拟网络的包
+    Send,
+    /// tcp代理从来源读到、已经写给真实目标的数据
+    Upload,
+    /// tcp代理从真实目标读到、已经写给来源的数据
+    Download,
+}
+
+/// 只读的流量观察者，用于流量分析，只能看到地址、协议和长度，不能修改包也不影响转发。
+/// Recv/Send是经过代理的每个ip包，src/dest是包里的地址（没有端口的协议端口为0），len是ip包长度；
+/// Upload/Download是tcp代理每次转发的数据，src/dest是连接的来源和目标，len是数据长度。
+/// 回调在转发线程里同步调用，必须很快返回、不能阻塞，没有注册观察者时没有额外开销
+pub trait PacketObserver: Send + Sync {
+    fn on_packet(
+        &self,
+        src: SocketAddr,
+        dest: SocketAddr,
+        protocol: Protocol,
+        len: usize,
+        direction: Direction,
+    );
+}
+
+impl std::fmt::Debug for dyn PacketObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PacketObserver")
+    }
+}
+
+/// 按包里的地址通知观察者，tcp和udp的端口在传输层头部的前4个字节
+fn observe(observer: &dyn PacketObserver, ipv4: &IpV4Packet<&mut [u8]>, direction: Direction) {
+    let payload = ipv4.payload();
+    let protocol = ipv4.protocol();
+    let (src_port, dest_port) = match protocol {
+        Protocol::Tcp | Protocol::Udp if payload.len() >= 4 => (
+            u16::from_be_bytes([payload[0], payload[1]]),
+            u16::from_be_bytes([payload[2], payload[3]]),
+        ),
+        _ => (0, 0),
+    };
+    observer.on_packet(
+        SocketAddrV4::new(ipv4.source_ip(), src_port).into(),
+        SocketAddrV4::new(ipv4.destination_ip(), dest_port).into(),
+        protocol,
+        ipv4.header().len() + payload.len(),
+        direction,
+    );
 }
 
 #[derive(Clone)]
 pub struct IpProxyMap {
+    /// 没有权限创建原始套接字时为None，icmp不走代理
     #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-    icmp_proxy: IcmpProxy,
+    icmp_proxy: Option<IcmpProxy>,
     tcp_proxy: TcpProxy,
     udp_proxy: UdpProxy,
+    /// 没有配置dns_upstream时为None，dns查询和其他udp一样走udp代理
+    dns_proxy: Option<DnsProxy>,
+    handlers: HandlerRegistry,
+    packet_observer: Option<Arc<dyn PacketObserver>>,
 }
 
 pub fn init_proxy(
@@ -44,21 +189,38 @@ pub fn init_proxy(
     stop_manager: StopManager,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     client_cipher: Cipher,
+    proxy_config: ProxyConfig,
 ) -> anyhow::Result<IpProxyMap> {
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .thread_name("ipProxy")
-        .build()?;
-    let proxy_map = runtime.block_on(init_proxy0(context, current_device, client_cipher))?;
+    // 已经停止时直接返回，不再创建运行时和绑定端口
+    if stop_manager.is_stop() {
+        return Err(anyhow::anyhow!("ipProxy start failed: stopped"));
+    }
+    let runtime = build_runtime(proxy_config.worker_threads)?;
+    let drain_timeout = proxy_config.tcp_drain_timeout;
+    let proxy_map = runtime.block_on(init_proxy0(
+        context,
+        current_device,
+        client_cipher,
+        proxy_config,
+    ))?;
+    let tcp_proxy = proxy_map.tcp_proxy.clone();
     let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
-    let worker = stop_manager.add_listener("ipProxy".into(), move || {
-        let _ = sender.send(());
-    })?;
+    let worker = stop_manager
+        .add_listener("ipProxy".into(), move || {
+            let _ = sender.send(());
+        })
+        .context("ipProxy add_listener failed")?;
     thread::Builder::new()
         .name("ipProxy".into())
         .spawn(move || {
             runtime.block_on(async {
                 let _ = receiver.await;
+                if !drain_timeout.is_zero() {
+                    let remaining = tcp_proxy.drain(drain_timeout).await;
+                    if remaining > 0 {
+                        log::warn!("tcp代理排空超时，强制关闭{}个连接", remaining);
+                    }
+                }
             });
             runtime.shutdown_background();
             drop(worker);
@@ -67,21 +229,122 @@ pub fn init_proxy(
     return Ok(proxy_map);
 }
 
+/// 代理的连接都是独立的任务，由tokio在工作线程间调度，空闲的线程会从繁忙的线程窃取任务，
+/// 监听socket只有一个，accept之后的转发任务会分散到所有工作线程；nat映射等状态由所有线程共享。
+/// 停止时shutdown_background会结束所有工作线程上的任务
+fn build_runtime(worker_threads: usize) -> io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if worker_threads > 0 {
+        builder.worker_threads(worker_threads);
+    }
+    builder.enable_all().thread_name("ipProxy").build()
+}
+
+/// 按最后使用时间清理的映射
+pub(crate) trait Evict {
+    /// 删除超过ttl没有使用的映射
+    fn evict(&mut self, ttl: Duration, now: Instant);
+}
+
+impl<K: Eq + Hash, V> Evict for HashMap<K, (V, Instant)> {
+    fn evict(&mut self, ttl: Duration, now: Instant) {
+        self.retain(|_, (_, time)| now.saturating_duration_since(*time) < ttl);
+    }
+}
+
+fn evict_expired<T: Evict>(nat_map: &Mutex<T>, ttl: Duration, now: Instant) {
+    nat_map.lock().evict(ttl, now);
+}
+
+/// 定时清理过期的映射，运行时停止时一起退出
+pub(crate) fn spawn_evict<T: Evict + Send + 'static>(nat_map: Arc<Mutex<T>>, ttl: Duration) {
+    let period = (ttl / 2).max(Duration::from_secs(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            evict_expired(&nat_map, ttl, Instant::now());
+        }
+    });
+}
+
+impl IpProxyMap {
+    /// tcp代理实际监听的端口
+    pub fn tcp_port(&self) -> u16 {
+        self.tcp_proxy.local_port()
+    }
+    pub fn tcp_stats(&self) -> ProxyStatsSnapshot {
+        self.tcp_proxy.stats()
+    }
+    /// tcp代理当前的映射(来源地址,真实目标地址)
+    pub fn tcp_mappings(&self) -> Vec<(SocketAddrV4, SocketAddrV4)> {
+        self.tcp_proxy.mappings()
+    }
+    pub fn tcp_connections(&self) -> Vec<ConnSnapshot> {
+        self.tcp_proxy.connections()
+    }
+    /// 运行时修改tcp代理的端口过滤规则
+    pub fn set_tcp_port_filter(&self, port_filter: PortFilter) {
+        self.tcp_proxy.set_port_filter(port_filter)
+    }
+    /// 运行时修改tcp代理的限速，为0则不限制
+    pub fn set_tcp_rate_limit(&self, rate: u64) {
+        self.tcp_proxy.set_rate_limit(rate)
+    }
+    /// 把代理回复的包还原成真实目标的地址和端口
+    fn restore(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
+        self.handlers.send_handle(ipv4)?;
+        match ipv4.protocol() {
+            ipv4::protocol::Protocol::Tcp => self.tcp_proxy.send_handle(ipv4),
+            ipv4::protocol::Protocol::Udp => {
+                if let Some(dns_proxy) = &self.dns_proxy {
+                    if dns_proxy.restore(ipv4)? {
+                        return Ok(());
+                    }
+                }
+                self.udp_proxy.send_handle(ipv4)
+            }
+            #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+            ipv4::protocol::Protocol::Icmp => match &self.icmp_proxy {
+                Some(icmp_proxy) => icmp_proxy.send_handle(ipv4),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
 async fn init_proxy0(
     _context: ChannelContext,
     _current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     _client_cipher: Cipher,
+    proxy_config: ProxyConfig,
 ) -> anyhow::Result<IpProxyMap> {
     #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-    let icmp_proxy = IcmpProxy::new(_context, _current_device, _client_cipher).await?;
-    let tcp_proxy = TcpProxy::new().await?;
-    let udp_proxy = UdpProxy::new().await?;
+    let icmp_proxy = match IcmpProxy::new(_context, _current_device, _client_cipher).await {
+        Ok(icmp_proxy) => Some(icmp_proxy),
+        Err(e) => {
+            log::warn!("icmp代理启动失败，不代理icmp:{:?}", e);
+            None
+        }
+    };
+    let tcp_proxy = TcpProxy::new(&proxy_config).await?;
+    let udp_proxy = UdpProxy::new(&proxy_config).await?;
+    let dns_proxy = match proxy_config.dns_upstream {
+        Some(upstream) => Some(DnsProxy::new(upstream, proxy_config.dns_domains.clone()).await?),
+        None => None,
+    };
+    let handlers = proxy_config.handlers;
+    let packet_observer = proxy_config.packet_observer;
 
     Ok(IpProxyMap {
         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
         icmp_proxy,
         tcp_proxy,
         udp_proxy,
+        dns_proxy,
+        handlers,
+        packet_observer,
     })
 }
 
@@ -91,14 +354,30 @@ impl ProxyHandler for IpProxyMap {
         ipv4: &mut IpV4Packet<&mut [u8]>,
         source: Ipv4Addr,
         destination: Ipv4Addr,
-    ) -> io::Result<bool> {
+    ) -> io::Result<ProxyAction> {
+        if let Some(observer) = &self.packet_observer {
+            observe(observer.as_ref(), ipv4, Direction::Recv);
+        }
+        let action = self.handlers.recv_handle(ipv4, source, destination)?;
+        if action.is_claimed() {
+            return Ok(action);
+        }
         match ipv4.protocol() {
             ipv4::protocol::Protocol::Tcp => self.tcp_proxy.recv_handle(ipv4, source, destination),
-            ipv4::protocol::Protocol::Udp => self.udp_proxy.recv_handle(ipv4, source, destination),
-            #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-            ipv4::protocol::Protocol::Icmp => {
-                self.icmp_proxy.recv_handle(ipv4, source, destination)
+            ipv4::protocol::Protocol::Udp => {
+                // 拦截的dns查询已经改写成本地端口，不能再交给udp代理
+                if let Some(dns_proxy) = &self.dns_proxy {
+                    if dns_proxy.intercept(ipv4, source, destination)? {
+                        return Ok(ProxyAction::PassThrough);
+                    }
+                }
+                self.udp_proxy.recv_handle(ipv4, source, destination)
             }
+            #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+            ipv4::protocol::Protocol::Icmp => match &self.icmp_proxy {
+                Some(icmp_proxy) => icmp_proxy.recv_handle(ipv4, source, destination),
+                None => Ok(ProxyAction::PassThrough),
+            },
             _ => {
                 log::warn!(
                     "不支持的ip代理ipv4协议{:?}:{}->{}->{}",
@@ -107,18 +386,296 @@ impl ProxyHandler for IpProxyMap {
                     destination,
                     ipv4.destination_ip()
                 );
-                Ok(false)
+                Ok(ProxyAction::PassThrough)
             }
         }
     }
 
     fn send_handle(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
-        match ipv4.protocol() {
-            ipv4::protocol::Protocol::Tcp => self.tcp_proxy.send_handle(ipv4),
-            ipv4::protocol::Protocol::Udp => self.udp_proxy.send_handle(ipv4),
-            #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-            ipv4::protocol::Protocol::Icmp => self.icmp_proxy.send_handle(ipv4),
-            _ => Ok(()),
+        self.restore(ipv4)?;
+        if let Some(observer) = &self.packet_observer {
+            observe(observer.as_ref(), ipv4, Direction::Send);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_evict_expired() {
+    let nat_map = Mutex::new(HashMap::new());
+    let start = Instant::now();
+    let ttl = Duration::from_secs(300);
+    let old: SocketAddrV4 = "10.26.0.2:1000".parse().unwrap();
+    let new: SocketAddrV4 = "10.26.0.2:1001".parse().unwrap();
+    let dest: SocketAddrV4 = "192.168.1.2:80".parse().unwrap();
+    nat_map.lock().insert(old, (dest, start));
+    nat_map
+        .lock()
+        .insert(new, (dest, start + Duration::from_secs(200)));
+    evict_expired(&nat_map, ttl, start + Duration::from_secs(100));
+    assert_eq!(nat_map.lock().len(), 2);
+    evict_expired(&nat_map, ttl, start + Duration::from_secs(301));
+    assert!(!nat_map.lock().contains_key(&old));
+    assert!(nat_map.lock().contains_key(&new));
+}
+
+#[test]
+fn test_build_runtime() {
+    let runtime = build_runtime(2).unwrap();
+    assert_eq!(runtime.metrics().num_workers(), 2);
+    // 任务都在名为ipProxy的工作线程上执行
+    let names = runtime.block_on(async {
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                tokio::spawn(async {
+                    tokio::task::yield_now().await;
+                    thread::current().name().map(|name| name.to_string())
+                })
+            })
+            .collect();
+        let mut names = Vec::new();
+        for task in tasks {
+            names.push(task.await.unwrap());
+        }
+        names
+    });
+    assert!(names.iter().all(|name| name.as_deref() == Some("ipProxy")));
+    assert!(build_runtime(0).unwrap().metrics().num_workers() > 0);
+}
+
+/// 构造测试用的ipv4包，l4是传输层的头部和数据，传输层的校验和由调用方计算
+#[cfg(test)]
+pub(crate) fn ipv4_packet(
+    protocol: Protocol,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    l4: &[u8],
+) -> Vec<u8> {
+    let total_len = 20 + l4.len();
+    let mut buf = vec![0u8; total_len];
+    buf[0] = 0x45;
+    buf[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    buf[8] = 64;
+    buf[9] = protocol.into();
+    buf[20..].copy_from_slice(l4);
+    let mut ipv4 = IpV4Packet::unchecked(&mut buf[..]);
+    ipv4.set_source_ip(source);
+    ipv4.set_destination_ip(destination);
+    ipv4.update_checksum();
+    buf
+}
+
+#[cfg(test)]
+struct TtlHandler(u8, ProxyAction);
+
+#[cfg(test)]
+impl ProxyHandler for TtlHandler {
+    fn recv_handle(
+        &self,
+        ipv4: &mut IpV4Packet<&mut [u8]>,
+        _source: Ipv4Addr,
+        _destination: Ipv4Addr,
+    ) -> io::Result<ProxyAction> {
+        ipv4.set_ttl(self.0);
+        Ok(self.1)
+    }
+
+    fn send_handle(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
+        ipv4.set_ttl(ipv4.ttl() + self.0);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_chain() {
+    let source = Ipv4Addr::new(10, 26, 0, 2);
+    let destination = Ipv4Addr::new(10, 26, 0, 3);
+    let mut buf = [0u8; 20];
+    buf[0] = 0x45;
+    let recv = |handler: &dyn Fn(&mut IpV4Packet<&mut [u8]>) -> ProxyAction| {
+        let mut data = buf;
+        let mut ipv4 = IpV4Packet::new(&mut data[..]).unwrap();
+        let action = handler(&mut ipv4);
+        (action, ipv4.ttl())
+    };
+    // 前一个PassThrough时交给后一个，后一个看到的是改写过的包
+    let chain = TtlHandler(1, ProxyAction::PassThrough).chain(TtlHandler(2, ProxyAction::Drop));
+    assert_eq!(
+        recv(&|ipv4| chain.recv_handle(ipv4, source, destination).unwrap()),
+        (ProxyAction::Drop, 2)
+    );
+    // 前一个认领后不再交给后一个
+    let chain = TtlHandler(1, ProxyAction::Handled)
+        .chain(TtlHandler(2, ProxyAction::PassThrough))
+        .chain(TtlHandler(3, ProxyAction::PassThrough));
+    assert_eq!(
+        recv(&|ipv4| chain.recv_handle(ipv4, source, destination).unwrap()),
+        (ProxyAction::Handled, 1)
+    );
+    // 回复包依次经过所有处理器
+    assert_eq!(
+        recv(&|ipv4| {
+            chain.send_handle(ipv4).unwrap();
+            ProxyAction::PassThrough
+        }),
+        (ProxyAction::PassThrough, 6)
+    );
+}
+
+#[tokio::test]
+async fn test_malformed_packets() {
+    use rand::{Rng, SeedableRng};
+    let config = ProxyConfig {
+        tcp_mss: 1360,
+        ..ProxyConfig::default()
+    };
+    let tcp_proxy = TcpProxy::new(&config).await.unwrap();
+    let udp_proxy = UdpProxy::new(&config).await.unwrap();
+    let dns_proxy = DnsProxy::new("127.0.0.1:53".parse().unwrap(), vec![])
+        .await
+        .unwrap();
+    // icmp代理需要ChannelContext，不在这里测试
+    let handlers: [&dyn ProxyHandler; 3] = [&tcp_proxy, &udp_proxy, &dns_proxy];
+    let source = Ipv4Addr::new(10, 26, 0, 2);
+    let destination = Ipv4Addr::new(10, 26, 0, 3);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    for _ in 0..10000 {
+        let mut buf = vec![0u8; rng.gen_range(0..80)];
+        rng.fill(&mut buf[..]);
+        // 版本固定是4，头部长度和其他字段随机
+        if let Some(first) = buf.first_mut() {
+            *first = 0x40 | (*first & 0x0f);
+        }
+        if buf.len() > 9 {
+            buf[9] = [1, 6, 17][rng.gen_range(0..3)];
+        }
+        for handler in handlers {
+            let mut data = buf.clone();
+            let Ok(mut ipv4) = IpV4Packet::new(&mut data[..]) else {
+                continue;
+            };
+            assert!(handler.recv_handle(&mut ipv4, source, destination).is_ok());
+            let mut data = buf.clone();
+            let mut ipv4 = IpV4Packet::new(&mut data[..]).unwrap();
+            assert!(handler.send_handle(&mut ipv4).is_ok());
+        }
+    }
+    // 数据偏移小于5的SYN包，修改MSS时会越界
+    let mut buf = [0u8; 40];
+    buf[0] = 0x45;
+    buf[9] = 6;
+    buf[32] = 0x20;
+    buf[33] = 0x02;
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        tcp_proxy
+            .recv_handle(&mut ipv4, source, destination)
+            .unwrap(),
+        ProxyAction::Drop
+    );
+    // udp头不完整
+    let mut buf = [0u8; 24];
+    buf[0] = 0x45;
+    buf[9] = 17;
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        udp_proxy
+            .recv_handle(&mut ipv4, source, destination)
+            .unwrap(),
+        ProxyAction::Drop
+    );
+}
+
+#[cfg(test)]
+type PacketEvent = (SocketAddr, SocketAddr, Protocol, usize, Direction);
+
+/// 记录观察到的所有事件
+#[cfg(test)]
+#[derive(Default)]
+struct RecordObserver(Mutex<Vec<PacketEvent>>);
+
+#[cfg(test)]
+impl PacketObserver for RecordObserver {
+    fn on_packet(
+        &self,
+        src: SocketAddr,
+        dest: SocketAddr,
+        protocol: Protocol,
+        len: usize,
+        direction: Direction,
+    ) {
+        self.0.lock().push((src, dest, protocol, len, direction));
+    }
+}
+
+#[tokio::test]
+async fn test_packet_observer() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    // 回显服务作为真实目标
+    let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let SocketAddr::V4(target_addr) = target.local_addr().unwrap() else {
+        unreachable!()
+    };
+    tokio::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let (mut read, mut write) = stream.split();
+        let _ = tokio::io::copy(&mut read, &mut write).await;
+    });
+    let observer = Arc::new(RecordObserver::default());
+    let config = ProxyConfig {
+        packet_observer: Some(observer.clone()),
+        ..ProxyConfig::default()
+    };
+    let proxy_map = IpProxyMap {
+        #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+        icmp_proxy: None,
+        tcp_proxy: TcpProxy::new(&config).await.unwrap(),
+        udp_proxy: UdpProxy::new(&config).await.unwrap(),
+        dns_proxy: None,
+        handlers: HandlerRegistry::default(),
+        packet_observer: config.packet_observer.clone(),
+    };
+    // 来源绑定本机端口，包经过recv_handle后目标端口改成代理的监听端口
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let SocketAddr::V4(guest) = socket.local_addr().unwrap() else {
+        unreachable!()
+    };
+    let local = Ipv4Addr::LOCALHOST;
+    let mut buf = tcp_proxy::tcp_ipv4_packet(guest, target_addr);
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        proxy_map.recv_handle(&mut ipv4, local, local).unwrap(),
+        ProxyAction::PassThrough
+    );
+    let proxy_port = u16::from_be_bytes([ipv4.payload()[2], ipv4.payload()[3]]);
+    assert_eq!(proxy_port, proxy_map.tcp_port());
+    let mut client = socket
+        .connect(SocketAddrV4::new(local, proxy_port).into())
+        .await
+        .unwrap();
+    client.write_all(b"hello").await.unwrap();
+    let mut data = [0u8; 5];
+    client.read_exact(&mut data).await.unwrap();
+    // 写给来源之后才通知Download，等它记录
+    for _ in 0..100 {
+        if observer.0.lock().len() >= 3 {
+            break;
         }
+        tokio::time::sleep(Duration::from_millis(10)).await;
     }
+    // 代理回复的包经过send_handle还原成真实目标的地址
+    let mut buf = tcp_proxy::tcp_ipv4_packet(SocketAddrV4::new(local, proxy_port), guest);
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    proxy_map.send_handle(&mut ipv4).unwrap();
+    let (guest, target) = (SocketAddr::V4(guest), SocketAddr::V4(target_addr));
+    assert_eq!(
+        *observer.0.lock(),
+        vec![
+            (guest, target, Protocol::Tcp, 40, Direction::Recv),
+            (guest, target, Protocol::Tcp, 5, Direction::Upload),
+            (target, guest, Protocol::Tcp, 5, Direction::Download),
+            (target, guest, Protocol::Tcp, 40, Direction::Send),
+        ]
+    );
 }
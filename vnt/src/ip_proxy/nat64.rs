@@ -0,0 +1,77 @@
+use std::net::{Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
+
+use crate::ip_proxy::port_filter::Cidr;
+
+/// RFC 6052的知名前缀64:ff9b::/96，目标网络里有NAT64网关时使用
+pub const WELL_KNOWN_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+
+/// 把ipv4网段映射到ipv6目标，来源仍然只用ipv4，tcp代理连接真实目标时改用ipv6。
+/// 目标在cidr内时连接prefix的/96地址，低32位是原来的ipv4地址(RFC 6052)，
+/// 例如前缀2001:db8:64::/96时10.64.0.5映射为2001:db8:64::a40:5
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Nat64 {
+    pub cidr: Cidr,
+    /// 低32位必须是0
+    pub prefix: Ipv6Addr,
+}
+
+impl Nat64 {
+    /// 不在网段内时返回None
+    pub fn map(&self, dest: SocketAddrV4) -> Option<SocketAddrV6> {
+        if !self.cidr.contains(*dest.ip()) {
+            return None;
+        }
+        let ip = Ipv6Addr::from(u128::from(self.prefix) | u32::from(*dest.ip()) as u128);
+        Some(SocketAddrV6::new(ip, dest.port(), 0, 0))
+    }
+}
+
+/// 按配置顺序找第一个包含目标的网段
+pub fn map_dest(rules: &[Nat64], dest: SocketAddrV4) -> Option<SocketAddrV6> {
+    rules.iter().find_map(|rule| rule.map(dest))
+}
+
+impl FromStr for Nat64 {
+    type Err = String;
+    /// 格式：10.64.0.0/16,2001:db8:64:: 省略ipv6前缀时使用知名前缀64:ff9b::
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cidr, prefix) = match s.split_once(',') {
+            Some((cidr, prefix)) => (cidr.trim(), Some(prefix.trim())),
+            None => (s.trim(), None),
+        };
+        let cidr = Cidr::from_str(cidr)?;
+        let prefix = match prefix {
+            Some(prefix) => {
+                let ip = prefix.strip_suffix("/96").unwrap_or(prefix);
+                let ip = Ipv6Addr::from_str(ip)
+                    .map_err(|e| format!("prefix '{}' {}, exp: 64:ff9b::", prefix, e))?;
+                if u128::from(ip) as u32 != 0 {
+                    return Err(format!("prefix '{}' is not a /96 prefix", prefix));
+                }
+                ip
+            }
+            None => WELL_KNOWN_PREFIX,
+        };
+        Ok(Nat64 { cidr, prefix })
+    }
+}
+
+#[test]
+fn test_nat64() {
+    let rule = Nat64::from_str("10.64.0.0/16,2001:db8:64::/96").unwrap();
+    assert_eq!(
+        rule.map("10.64.0.5:80".parse().unwrap()),
+        Some("[2001:db8:64::a40:5]:80".parse().unwrap())
+    );
+    assert_eq!(rule.map("10.65.0.5:80".parse().unwrap()), None);
+    let rule = Nat64::from_str("192.0.2.0/24").unwrap();
+    assert_eq!(rule.prefix, WELL_KNOWN_PREFIX);
+    assert_eq!(
+        map_dest(&[rule], "192.0.2.33:443".parse().unwrap()),
+        Some("[64:ff9b::192.0.2.33]:443".parse().unwrap())
+    );
+    assert!(Nat64::from_str("10.64.0.0/16,2001:db8::1").is_err());
+    assert!(Nat64::from_str("10.64.0.0/33").is_err());
+    assert!(Nat64::from_str("10.64.0.0/16,abc").is_err());
+}
@@ -0,0 +1,127 @@
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::ip_proxy::port_filter::{parse_ports, ports_match, Cidr};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyAction {
+    Allow,
+    Deny,
+}
+
+/// 一条代理规则，目标在网段内并且端口匹配时生效
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyRule {
+    pub action: PolicyAction,
+    pub cidr: Cidr,
+    /// 为空则匹配所有端口
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+impl PolicyRule {
+    fn matches(&self, ip: Ipv4Addr, port: u16) -> bool {
+        self.cidr.contains(ip) && ports_match(&self.ports, port)
+    }
+}
+
+impl FromStr for PolicyRule {
+    type Err = String;
+    /// 格式：allow 10.0.0.0/8、deny 0.0.0.0/0 25、deny 192.168.1.0/24 22,8000-9000
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let action = match parts.next().map(|v| v.to_lowercase()).as_deref() {
+            Some("allow") => PolicyAction::Allow,
+            Some("deny") => PolicyAction::Deny,
+            _ => {
+                return Err(format!(
+                    "not match '{}', exp: allow 10.0.0.0/8 or deny 0.0.0.0/0 25",
+                    s
+                ))
+            }
+        };
+        let cidr = parts
+            .next()
+            .ok_or_else(|| format!("'{}' cidr not found", s))?
+            .parse()?;
+        let ports = match parts.next() {
+            Some(ports) => parse_ports(ports)?,
+            None => Vec::new(),
+        };
+        if parts.next().is_some() {
+            return Err(format!("'{}' too many fields", s));
+        }
+        Ok(PolicyRule {
+            action,
+            cidr,
+            ports,
+        })
+    }
+}
+
+/// 按目标网段和端口决定tcp连接是否走代理，不走代理的包原样写入tun。
+/// 多条规则重叠时最具体的规则生效：网段前缀更长的优先，前缀相同时指定了端口的优先，
+/// 仍然相同时按配置的顺序。没有规则匹配时走代理
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProxyPolicy {
+    /// 按优先级排好序，第一条匹配的规则生效
+    rules: Vec<PolicyRule>,
+}
+
+impl ProxyPolicy {
+    pub fn new(mut rules: Vec<PolicyRule>) -> Self {
+        // 稳定排序，保留相同优先级规则的配置顺序
+        rules.sort_by_key(|rule| std::cmp::Reverse((rule.cidr.prefix_len, !rule.ports.is_empty())));
+        Self { rules }
+    }
+    pub fn is_allowed(&self, ip: Ipv4Addr, port: u16) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(ip, port))
+            .is_none_or(|rule| rule.action == PolicyAction::Allow)
+    }
+}
+
+#[test]
+fn test_policy_rule() {
+    let rule = PolicyRule::from_str("deny 192.168.1.0/24 22,8000-9000").unwrap();
+    assert_eq!(rule.action, PolicyAction::Deny);
+    assert_eq!(rule.cidr.prefix_len, 24);
+    assert_eq!(rule.ports, vec![22..=22, 8000..=9000]);
+    let rule = PolicyRule::from_str("ALLOW 10.0.0.1").unwrap();
+    assert_eq!(
+        (rule.action, rule.cidr.prefix_len),
+        (PolicyAction::Allow, 32)
+    );
+    assert!(rule.ports.is_empty());
+    assert!(PolicyRule::from_str("block 10.0.0.0/8").is_err());
+    assert!(PolicyRule::from_str("allow 10.0.0.0/33").is_err());
+    assert!(PolicyRule::from_str("allow").is_err());
+    assert!(PolicyRule::from_str("allow 10.0.0.0/8 80 443").is_err());
+}
+
+#[test]
+fn test_policy_precedence() {
+    let rules = [
+        "deny 0.0.0.0/0",
+        "allow 10.0.0.0/8",
+        "allow 192.168.0.0/16",
+        "deny 10.0.0.0/8 25",
+        "deny 192.168.1.0/24",
+        "allow 192.168.1.0/24",
+        "allow 192.168.1.10",
+    ];
+    let policy = ProxyPolicy::new(rules.iter().map(|v| v.parse().unwrap()).collect());
+    // 只代理内网
+    assert!(!policy.is_allowed(Ipv4Addr::new(8, 8, 8, 8), 443));
+    assert!(policy.is_allowed(Ipv4Addr::new(10, 1, 2, 3), 443));
+    // 相同网段指定了端口的规则优先，和配置顺序无关
+    assert!(!policy.is_allowed(Ipv4Addr::new(10, 1, 2, 3), 25));
+    // 更小的网段优先
+    assert!(policy.is_allowed(Ipv4Addr::new(192, 168, 2, 1), 80));
+    // 完全相同的规则按配置顺序
+    assert!(!policy.is_allowed(Ipv4Addr::new(192, 168, 1, 1), 80));
+    assert!(policy.is_allowed(Ipv4Addr::new(192, 168, 1, 10), 80));
+    // 没有规则时全部代理
+    assert!(ProxyPolicy::default().is_allowed(Ipv4Addr::new(8, 8, 8, 8), 25));
+}
@@ -0,0 +1,133 @@
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// 按目标端口决定是否走代理，不走代理的包原样写入tun
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PortFilter {
+    /// 全部代理
+    #[default]
+    All,
+    /// 只代理这些端口
+    Allow(Vec<RangeInclusive<u16>>),
+    /// 除了这些端口都代理
+    Deny(Vec<RangeInclusive<u16>>),
+}
+
+impl PortFilter {
+    pub fn is_allowed(&self, port: u16) -> bool {
+        match self {
+            PortFilter::All => true,
+            PortFilter::Allow(ranges) => ranges.iter().any(|range| range.contains(&port)),
+            PortFilter::Deny(ranges) => !ranges.iter().any(|range| range.contains(&port)),
+        }
+    }
+}
+
+impl FromStr for PortFilter {
+    type Err = String;
+    /// 格式：all、allow:80,443,8000-9000、deny:22,3389
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("all") {
+            return Ok(PortFilter::All);
+        }
+        let (mode, ports) = match s.split_once(':') {
+            Some(v) => v,
+            None => return Err(format!("not match '{}', exp: allow:80,443,8000-9000", s)),
+        };
+        let ranges = parse_ports(ports)?;
+        match mode.trim().to_lowercase().as_str() {
+            "allow" => Ok(PortFilter::Allow(ranges)),
+            "deny" => Ok(PortFilter::Deny(ranges)),
+            _ => Err(format!("not match '{}', exp: allow or deny", mode)),
+        }
+    }
+}
+
+/// ipv4网段，代理规则按目标是否在网段内匹配
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.network) & mask
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+    /// 格式：192.168.1.0/24，省略前缀长度时是单个地址
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = s.split_once('/').unwrap_or((s, "32"));
+        let network = Ipv4Addr::from_str(network).map_err(|e| format!("cidr '{}' {}", s, e))?;
+        let prefix_len = match u8::from_str(prefix_len) {
+            Ok(prefix_len) if prefix_len <= 32 => prefix_len,
+            _ => return Err(format!("cidr '{}' invalid prefix length", s)),
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+/// 格式：80,443,8000-9000
+pub(crate) fn parse_ports(s: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    s.split(',').map(parse_range).collect()
+}
+
+/// 规则里的端口列表，为空则匹配所有端口
+pub(crate) fn ports_match(ports: &[RangeInclusive<u16>], port: u16) -> bool {
+    ports.is_empty() || ports.iter().any(|range| range.contains(&port))
+}
+
+fn parse_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let s = s.trim();
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let start = u16::from_str(start.trim()).map_err(|e| format!("port '{}' {}", s, e))?;
+    let end = u16::from_str(end.trim()).map_err(|e| format!("port '{}' {}", s, e))?;
+    if start > end {
+        return Err(format!("port range '{}' start > end", s));
+    }
+    Ok(start..=end)
+}
+
+#[test]
+fn test_port_filter() {
+    let filter = PortFilter::from_str("allow:80,443,8000-8080").unwrap();
+    assert!(filter.is_allowed(80));
+    assert!(filter.is_allowed(8000));
+    assert!(filter.is_allowed(8080));
+    assert!(!filter.is_allowed(22));
+    let filter = PortFilter::from_str("deny: 22, 3389").unwrap();
+    assert!(!filter.is_allowed(22));
+    assert!(filter.is_allowed(80));
+    assert!(PortFilter::from_str("all").unwrap().is_allowed(22));
+    assert!(PortFilter::from_str("allow:90-80").is_err());
+    assert!(PortFilter::from_str("block:80").is_err());
+}
+
+#[test]
+fn test_cidr() {
+    let cidr = Cidr::from_str("192.168.1.0/24").unwrap();
+    assert!(cidr.contains(Ipv4Addr::new(192, 168, 1, 9)));
+    assert!(!cidr.contains(Ipv4Addr::new(192, 168, 2, 9)));
+    let cidr = Cidr::from_str("10.26.0.3").unwrap();
+    assert_eq!(cidr.prefix_len, 32);
+    assert!(!cidr.contains(Ipv4Addr::new(10, 26, 0, 4)));
+    assert!(Cidr::from_str("0.0.0.0/0")
+        .unwrap()
+        .contains(Ipv4Addr::new(8, 8, 8, 8)));
+    assert!(Cidr::from_str("10.0.0.0/33").is_err());
+    assert!(Cidr::from_str("10.0.0/8").is_err());
+    assert!(ports_match(&[], 22));
+    assert!(ports_match(&parse_ports("80,8000-9000").unwrap(), 8080));
+    assert!(!ports_match(&parse_ports("80,8000-9000").unwrap(), 22));
+}
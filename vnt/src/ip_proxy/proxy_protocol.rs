@@ -0,0 +1,173 @@
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::ip_proxy::port_filter::{parse_ports, ports_match, Cidr};
+
+/// v2头部固定的12字节签名
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// PROXY protocol的版本
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    /// 文本格式：PROXY TCP4 10.26.0.2 192.168.1.2 40000 80\r\n
+    V1,
+    /// 二进制格式
+    V2,
+}
+
+impl ProxyProtocolVersion {
+    /// 连接目标后最先发送的头部，携带真实的来源和目标地址。
+    /// 来源和目标的地址族不同时都转成ipv6(ipv4映射地址)
+    pub fn header(self, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        let (src, dst) = match (src, dst) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+                (src, dst)
+            }
+            _ => (to_ipv6(src), to_ipv6(dst)),
+        };
+        match self {
+            ProxyProtocolVersion::V1 => {
+                let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    family,
+                    src.ip(),
+                    dst.ip(),
+                    src.port(),
+                    dst.port()
+                )
+                .into_bytes()
+            }
+            ProxyProtocolVersion::V2 => {
+                let mut buf = Vec::with_capacity(16 + 36);
+                buf.extend_from_slice(&V2_SIGNATURE);
+                // 版本2，命令PROXY
+                buf.push(0x21);
+                match (src.ip(), dst.ip()) {
+                    (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                        // AF_INET + STREAM
+                        buf.push(0x11);
+                        buf.extend_from_slice(&12u16.to_be_bytes());
+                        buf.extend_from_slice(&src_ip.octets());
+                        buf.extend_from_slice(&dst_ip.octets());
+                    }
+                    (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+                        // AF_INET6 + STREAM
+                        buf.push(0x21);
+                        buf.extend_from_slice(&36u16.to_be_bytes());
+                        buf.extend_from_slice(&src_ip.octets());
+                        buf.extend_from_slice(&dst_ip.octets());
+                    }
+                    _ => unreachable!(),
+                }
+                buf.extend_from_slice(&src.port().to_be_bytes());
+                buf.extend_from_slice(&dst.port().to_be_bytes());
+                buf
+            }
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        SocketAddr::V6(_) => addr,
+    }
+}
+
+/// 目标在网段内并且端口匹配时，连接目标后先发送PROXY protocol头部，
+/// 让目标(例如开启了proxy_protocol的nginx)拿到真实的来源地址，而不是代理的地址
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyProtocolRule {
+    pub version: ProxyProtocolVersion,
+    pub cidr: Cidr,
+    /// 为空则匹配所有端口
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+impl ProxyProtocolRule {
+    fn matches(&self, dest: SocketAddrV4) -> bool {
+        self.cidr.contains(*dest.ip()) && ports_match(&self.ports, dest.port())
+    }
+}
+
+/// 按配置顺序找第一条匹配目标的规则，没有匹配时不发送头部
+pub fn find(rules: &[ProxyProtocolRule], dest: SocketAddrV4) -> Option<ProxyProtocolVersion> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(dest))
+        .map(|rule| rule.version)
+}
+
+impl FromStr for ProxyProtocolRule {
+    type Err = String;
+    /// 格式：v1 10.26.0.3 80、v2 192.168.1.0/24 80,443,8000-9000，省略端口时匹配所有端口
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let version = match parts.next().map(|v| v.to_lowercase()).as_deref() {
+            Some("v1") => ProxyProtocolVersion::V1,
+            Some("v2") => ProxyProtocolVersion::V2,
+            _ => {
+                return Err(format!(
+                    "not match '{}', exp: v1 10.26.0.3 80 or v2 192.168.1.0/24",
+                    s
+                ))
+            }
+        };
+        let cidr = parts
+            .next()
+            .ok_or_else(|| format!("'{}' cidr not found", s))?
+            .parse()?;
+        let ports = match parts.next() {
+            Some(ports) => parse_ports(ports)?,
+            None => Vec::new(),
+        };
+        if parts.next().is_some() {
+            return Err(format!("'{}' too many fields", s));
+        }
+        Ok(ProxyProtocolRule {
+            version,
+            cidr,
+            ports,
+        })
+    }
+}
+
+#[test]
+fn test_proxy_protocol() {
+    let rules: Vec<ProxyProtocolRule> = ["v1 10.26.0.3 80", "v2 192.168.1.0/24 443,8000-9000"]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+    let find = |dest: &str| find(&rules, dest.parse().unwrap());
+    assert_eq!(find("10.26.0.3:80"), Some(ProxyProtocolVersion::V1));
+    assert_eq!(find("10.26.0.3:81"), None);
+    assert_eq!(find("192.168.1.9:8080"), Some(ProxyProtocolVersion::V2));
+    assert_eq!(find("192.168.2.9:443"), None);
+    assert!(ProxyProtocolRule::from_str("v3 10.0.0.0/8").is_err());
+    assert!(ProxyProtocolRule::from_str("v1 10.0.0.0/33").is_err());
+    assert!(ProxyProtocolRule::from_str("v1 10.0.0.0/8 80 1").is_err());
+
+    let src: SocketAddr = "10.26.0.2:40000".parse().unwrap();
+    let dst: SocketAddr = "192.168.1.2:80".parse().unwrap();
+    assert_eq!(
+        ProxyProtocolVersion::V1.header(src, dst),
+        b"PROXY TCP4 10.26.0.2 192.168.1.2 40000 80\r\n"
+    );
+    let v2 = ProxyProtocolVersion::V2.header(src, dst);
+    assert_eq!(&v2[..12], &V2_SIGNATURE);
+    assert_eq!(
+        &v2[12..],
+        &[0x21, 0x11, 0, 12, 10, 26, 0, 2, 192, 168, 1, 2, 0x9c, 0x40, 0, 80]
+    );
+    // socks5来源可能是ipv6，目标转成ipv4映射地址
+    let src: SocketAddr = "[fd00::2]:40000".parse().unwrap();
+    assert_eq!(
+        ProxyProtocolVersion::V1.header(src, dst),
+        b"PROXY TCP6 fd00::2 ::ffff:192.168.1.2 40000 80\r\n"
+    );
+    let v2 = ProxyProtocolVersion::V2.header(src, dst);
+    assert_eq!(&v2[12..16], &[0x21, 0x21, 0, 36]);
+    assert_eq!(v2.len(), 16 + 36);
+}
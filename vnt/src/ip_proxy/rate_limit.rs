@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 令牌桶限速，rate是每秒的字节数，为0则不限制，最多积累1秒的令牌。
+/// 令牌可以透支，读到的数据总是能写出去，透支的部分在下一次读取前等待补齐，
+/// 等待期间不读取，数据留在内核缓冲区里，通过tcp窗口限制发送方
+pub(crate) struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+    /// 修改速率，正在等待的连接在下一次转发时使用新的速率
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock();
+        bucket.tokens = if bucket.rate == 0 {
+            rate as f64
+        } else {
+            bucket.tokens.min(rate as f64)
+        };
+        bucket.rate = rate;
+        bucket.last = Instant::now();
+    }
+    /// 消耗len个令牌，返回令牌补齐需要等待的时间
+    fn consume(&self, len: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock();
+        if bucket.rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = bucket.rate as f64;
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.last = now.max(bucket.last);
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - len as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+    /// 转发len字节后调用，令牌不足时等待
+    pub async fn acquire(&self, len: usize) {
+        let wait = self.consume(len, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[test]
+fn test_consume() {
+    let limiter = RateLimiter::new(1000);
+    let start = limiter.bucket.lock().last;
+    // 开始时有1秒的令牌
+    assert_eq!(limiter.consume(1000, start), Duration::ZERO);
+    // 透支的部分按速率等待
+    assert_eq!(limiter.consume(500, start), Duration::from_millis(500));
+    // 等待结束后令牌刚好补齐
+    assert_eq!(
+        limiter.consume(0, start + Duration::from_millis(500)),
+        Duration::ZERO
+    );
+    // 空闲很久也最多积累1秒的令牌
+    let later = start + Duration::from_secs(10);
+    assert_eq!(limiter.consume(1000, later), Duration::ZERO);
+    assert_eq!(limiter.consume(100, later), Duration::from_millis(100));
+    // 为0时不限制
+    limiter.set_rate(0);
+    assert_eq!(limiter.consume(usize::MAX, later), Duration::ZERO);
+    limiter.set_rate(1000);
+    let now = limiter.bucket.lock().last;
+    assert_eq!(limiter.consume(1000, now), Duration::ZERO);
+    assert_eq!(limiter.consume(1000, now), Duration::from_secs(1));
+}
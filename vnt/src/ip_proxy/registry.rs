@@ -0,0 +1,208 @@
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use packet::ip::ipv4::packet::IpV4Packet;
+use packet::ip::ipv4::protocol::Protocol;
+
+use crate::ip_proxy::{ProxyAction, ProxyHandler};
+
+#[derive(Clone)]
+struct HandlerEntry {
+    protocol: Protocol,
+    ports: Option<RangeInclusive<u16>>,
+    priority: i32,
+    handler: Arc<dyn ProxyHandler + Send + Sync>,
+}
+
+impl HandlerEntry {
+    fn matches(&self, ipv4: &IpV4Packet<&mut [u8]>) -> bool {
+        if ipv4.protocol() != self.protocol {
+            return false;
+        }
+        match &self.ports {
+            None => true,
+            Some(ports) => match ipv4.protocol() {
+                Protocol::Tcp | Protocol::Udp => {
+                    let payload = ipv4.payload();
+                    payload.len() >= 4
+                        && ports.contains(&u16::from_be_bytes([payload[2], payload[3]]))
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// 自定义的代理处理器，在内置的tcp/udp/icmp代理之前处理，用于扩展其他协议或者接管部分端口
+#[derive(Clone, Default)]
+pub struct HandlerRegistry {
+    entries: Vec<HandlerEntry>,
+}
+
+impl HandlerRegistry {
+    /// 注册处理器，protocol是ip协议号，ports按目标端口匹配，为None则匹配这个协议的所有包，
+    /// 只有tcp和udp能按端口匹配。priority大的先处理，相同时按注册顺序
+    pub fn register(
+        &mut self,
+        protocol: Protocol,
+        ports: Option<RangeInclusive<u16>>,
+        priority: i32,
+        handler: Arc<dyn ProxyHandler + Send + Sync>,
+    ) {
+        let index = self.entries.partition_point(|e| e.priority >= priority);
+        self.entries.insert(
+            index,
+            HandlerEntry {
+                protocol,
+                ports,
+                priority,
+                handler,
+            },
+        );
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// 按优先级交给匹配的处理器，和Chain一样有一个认领了包(Handled或者Drop)就停止；
+/// 返回PassThrough的处理器改写过的包会继续交给后面的处理器，最后是内置代理
+impl ProxyHandler for HandlerRegistry {
+    fn recv_handle(
+        &self,
+        ipv4: &mut IpV4Packet<&mut [u8]>,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> io::Result<ProxyAction> {
+        for entry in &self.entries {
+            if !entry.matches(ipv4) {
+                continue;
+            }
+            let action = entry.handler.recv_handle(ipv4, source, destination)?;
+            if action.is_claimed() {
+                return Ok(action);
+            }
+        }
+        Ok(ProxyAction::PassThrough)
+    }
+
+    /// 回复包的端口已经是处理器自己改写后的，所以这里只按协议匹配，
+    /// 处理器需要忽略不是自己产生的包
+    fn send_handle(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
+        for entry in &self.entries {
+            if ipv4.protocol() == entry.protocol {
+                entry.handler.send_handle(ipv4)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for HandlerRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(
+                self.entries
+                    .iter()
+                    .map(|e| (e.protocol, e.ports.clone(), e.priority)),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+struct TestHandler {
+    name: &'static str,
+    action: ProxyAction,
+    calls: Arc<parking_lot::Mutex<Vec<&'static str>>>,
+}
+
+#[cfg(test)]
+impl ProxyHandler for TestHandler {
+    fn recv_handle(
+        &self,
+        _ipv4: &mut IpV4Packet<&mut [u8]>,
+        _source: Ipv4Addr,
+        _destination: Ipv4Addr,
+    ) -> io::Result<ProxyAction> {
+        self.calls.lock().push(self.name);
+        Ok(self.action)
+    }
+
+    fn send_handle(&self, _ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
+        self.calls.lock().push(self.name);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_registry() {
+    let calls = Arc::new(parking_lot::Mutex::new(Vec::new()));
+    let handler = |name, action| {
+        Arc::new(TestHandler {
+            name,
+            action,
+            calls: calls.clone(),
+        })
+    };
+    let mut registry = HandlerRegistry::default();
+    registry.register(
+        Protocol::Tcp,
+        None,
+        0,
+        handler("tcp", ProxyAction::PassThrough),
+    );
+    registry.register(
+        Protocol::Tcp,
+        Some(5000..=6000),
+        10,
+        handler("port", ProxyAction::Handled),
+    );
+    registry.register(
+        Protocol::Tcp,
+        Some(5000..=5000),
+        10,
+        handler("late", ProxyAction::Handled),
+    );
+    registry.register(
+        Protocol::Tcp,
+        Some(23..=23),
+        10,
+        handler("block", ProxyAction::Drop),
+    );
+    registry.register(
+        Protocol::Udp,
+        None,
+        20,
+        handler("udp", ProxyAction::Handled),
+    );
+
+    let source = Ipv4Addr::new(10, 26, 0, 2);
+    let destination = Ipv4Addr::new(10, 26, 0, 3);
+    let mut buf = [0u8; 40];
+    buf[0] = 0x45;
+    buf[9] = 6;
+    let recv = |port: u16| {
+        let mut data = buf;
+        data[22..24].copy_from_slice(&port.to_be_bytes());
+        let mut ipv4 = IpV4Packet::new(&mut data[..]).unwrap();
+        let action = registry
+            .recv_handle(&mut ipv4, source, destination)
+            .unwrap();
+        (action, std::mem::take(&mut *calls.lock()))
+    };
+    // 同优先级按注册顺序，认领后不再往后传
+    assert_eq!(recv(5000), (ProxyAction::Handled, vec!["port"]));
+    // 丢弃也会停止
+    assert_eq!(recv(23), (ProxyAction::Drop, vec!["block"]));
+    // 端口不匹配的跳过，返回PassThrough的继续交给后面的处理器
+    assert_eq!(recv(80), (ProxyAction::PassThrough, vec!["tcp"]));
+
+    let mut data = buf;
+    let mut ipv4 = IpV4Packet::new(&mut data[..]).unwrap();
+    registry.send_handle(&mut ipv4).unwrap();
+    assert_eq!(*calls.lock(), vec!["port", "late", "block", "tcp"]);
+}
@@ -1,36 +1,741 @@
-use anyhow::Context;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use anyhow::{anyhow, Context};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use std::{collections::HashMap, io, net::SocketAddr};
 
-use parking_lot::Mutex;
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::{Mutex, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::watch;
 
 use packet::ip::ipv4::packet::IpV4Packet;
+use packet::ip::ipv4::protocol::Protocol;
 use packet::tcp::tcp::TcpPacket;
 
-use crate::ip_proxy::ProxyHandler;
+use crate::ip_proxy::access_log::{AccessLog, AccessRecord, CloseReason};
+use crate::ip_proxy::nat64;
+use crate::ip_proxy::policy::ProxyPolicy;
+use crate::ip_proxy::port_filter::PortFilter;
+use crate::ip_proxy::proxy_protocol;
+use crate::ip_proxy::rate_limit::RateLimiter;
+#[cfg(test)]
+use crate::ip_proxy::socks5::Socks5Listen;
+use crate::ip_proxy::socks5::{self, TargetAddr, UpstreamProxy};
+use crate::ip_proxy::{spawn_evict, Direction, Evict, ProxyAction, ProxyConfig, ProxyHandler};
+use crate::util::is_fd_exhausted;
+
+/// 默认的转发缓冲区大小
+pub const DEFAULT_BUF_LEN: usize = 8 * 1024;
+/// 缓冲区至少要能放下一个mtu的数据
+pub const MIN_BUF_LEN: usize = 1500;
+/// 默认的连接目标超时时间
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// 默认的连接空闲超时时间
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// 默认的nat映射过期时间
+pub const DEFAULT_NAT_TTL: Duration = Duration::from_secs(300);
+/// 默认的监听backlog，和tokio的TcpListener::bind相同
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+/// 文件描述符用完时暂停accept的时间
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(500);
+/// 检查连接是否排空的间隔
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// 连接数超限的警告日志最短间隔
+const LIMIT_WARN_INTERVAL: Duration = Duration::from_secs(10);
+/// 连接目标失败后，等RST经过tun发回来源再删除映射
+const RST_FLUSH_DELAY: Duration = Duration::from_secs(1);
+/// 关闭连接时等待已经读到的数据写完的最长时间
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// nat映射和连接表预分配的容量范围，按最大连接数取值，避免连接突增时频繁扩容
+const MIN_MAP_CAPACITY: usize = 16;
+const MAX_MAP_CAPACITY: usize = 4096;
+
+/// 预分配的容量，不限制连接数时使用最小值
+fn map_capacity(max_connections: usize) -> usize {
+    max_connections.clamp(MIN_MAP_CAPACITY, MAX_MAP_CAPACITY)
+}
+
+/// 代理的统计计数，全部是原子操作，不和nat_map共用锁
+#[derive(Default)]
+struct ProxyStats {
+    active_connections: AtomicU64,
+    accepted: AtomicU64,
+    closed: AtomicU64,
+    rejected: AtomicU64,
+    connect_refused: AtomicU64,
+    connect_timeout: AtomicU64,
+    connect_unreachable: AtomicU64,
+    connect_other: AtomicU64,
+    nat_miss: AtomicU64,
+    /// 来源->目标
+    upload_bytes: AtomicU64,
+    /// 目标->来源
+    download_bytes: AtomicU64,
+    /// 连接id -> 正在转发的连接，用于排查单个连接的转发情况
+    connections: Mutex<HashMap<u64, ConnEntry>>,
+}
+
+/// 正在转发的连接，字节数和ConnGuard共用
+struct ConnEntry {
+    sender_addr: SocketAddr,
+    dest_addr: SocketAddrV4,
+    start: Instant,
+    upload_bytes: Arc<AtomicU64>,
+    download_bytes: Arc<AtomicU64>,
+}
+
+impl ProxyStats {
+    fn snapshot(&self) -> ProxyStatsSnapshot {
+        ProxyStatsSnapshot {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            closed: self.closed.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            connect_refused: self.connect_refused.load(Ordering::Relaxed),
+            connect_timeout: self.connect_timeout.load(Ordering::Relaxed),
+            connect_unreachable: self.connect_unreachable.load(Ordering::Relaxed),
+            connect_other: self.connect_other.load(Ordering::Relaxed),
+            nat_miss: self.nat_miss.load(Ordering::Relaxed),
+            upload_bytes: self.upload_bytes.load(Ordering::Relaxed),
+            download_bytes: self.download_bytes.load(Ordering::Relaxed),
+        }
+    }
+    fn connections(&self) -> Vec<ConnSnapshot> {
+        let mut connections: Vec<ConnSnapshot> = self
+            .connections
+            .lock()
+            .iter()
+            .map(|(id, entry)| ConnSnapshot {
+                id: *id,
+                src: entry.sender_addr,
+                dst: entry.dest_addr,
+                duration: entry.start.elapsed(),
+                upload_bytes: entry.upload_bytes.load(Ordering::Relaxed),
+                download_bytes: entry.download_bytes.load(Ordering::Relaxed),
+            })
+            .collect();
+        connections.sort_by_key(|v| v.id);
+        connections
+    }
+    fn connect_failed(&self, failure: ConnectFailure) {
+        let counter = match failure {
+            ConnectFailure::Refused => &self.connect_refused,
+            ConnectFailure::Timeout => &self.connect_timeout,
+            ConnectFailure::Unreachable => &self.connect_unreachable,
+            ConnectFailure::Other => &self.connect_other,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// 连接目标失败的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConnectFailure {
+    Refused,
+    Timeout,
+    Unreachable,
+    Other,
+}
+
+impl ConnectFailure {
+    /// socks5客户端连接目标失败时的应答码
+    fn socks5_reply(self) -> u8 {
+        match self {
+            ConnectFailure::Refused => socks5::error_reply(io::ErrorKind::ConnectionRefused),
+            ConnectFailure::Timeout => socks5::error_reply(io::ErrorKind::TimedOut),
+            ConnectFailure::Unreachable => socks5::error_reply(io::ErrorKind::HostUnreachable),
+            ConnectFailure::Other => socks5::REPLY_GENERAL_FAILURE,
+        }
+    }
+    fn classify(e: &anyhow::Error) -> Self {
+        if e.downcast_ref::<tokio::time::error::Elapsed>().is_some() {
+            return ConnectFailure::Timeout;
+        }
+        match e.downcast_ref::<io::Error>().map(|e| e.kind()) {
+            Some(io::ErrorKind::ConnectionRefused) => ConnectFailure::Refused,
+            Some(io::ErrorKind::TimedOut) => ConnectFailure::Timeout,
+            Some(io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable) => {
+                ConnectFailure::Unreachable
+            }
+            _ => ConnectFailure::Other,
+        }
+    }
+}
+
+/// 某一时刻的代理统计
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProxyStatsSnapshot {
+    /// 当前正在转发的连接数
+    pub active_connections: u64,
+    /// 累计接收的连接数
+    pub accepted: u64,
+    /// 累计关闭的连接数
+    pub closed: u64,
+    /// 因为连接数超限被拒绝的连接数
+    pub rejected: u64,
+    /// 连接目标被拒绝的次数
+    pub connect_refused: u64,
+    /// 连接目标超时的次数
+    pub connect_timeout: u64,
+    /// 目标网络或主机不可达的次数
+    pub connect_unreachable: u64,
+    /// 其他原因连接目标失败的次数
+    pub connect_other: u64,
+    /// 代理发出的回复包找不到映射、没有还原地址的次数，
+    /// 这些包来源不会接受，持续增长说明映射过期太快(tcp_nat_ttl、tcp_nat_max)
+    pub nat_miss: u64,
+    /// 来源->目标 累计转发的字节数
+    pub upload_bytes: u64,
+    /// 目标->来源 累计转发的字节数
+    pub download_bytes: u64,
+}
+
+/// 某一时刻单个连接的转发情况
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnSnapshot {
+    /// 连接id，和日志里的id相同
+    pub id: u64,
+    /// tun进入的连接是虚拟网络里的来源，socks5连接是socks5客户端的地址
+    pub src: SocketAddr,
+    pub dst: SocketAddrV4,
+    /// 已经持续的时间
+    pub duration: Duration,
+    /// 来源->目标 已经转发的字节数
+    pub upload_bytes: u64,
+    /// 目标->来源 已经转发的字节数
+    pub download_bytes: u64,
+}
+
+/// 目标ip -> 正在转发的连接数
+type DestCounts = Arc<Mutex<HashMap<Ipv4Addr, usize>>>;
+
+type TcpNatMap = Arc<Mutex<TcpNat>>;
+
+/// tcp代理的nat映射。同一个来源地址可以同时连接不同的目标，
+/// 这时后来的连接换一个来源端口进入代理，回复时再换回原来的端口，没有冲突时保持原来的端口
+#[derive(Default)]
+struct TcpNat {
+    /// 进入代理的来源地址 -> ((真实目标地址, 原来的来源端口), 最后使用时间)
+    map: HashMap<SocketAddrV4, ((SocketAddrV4, u16), Instant)>,
+    /// 换了端口的连接 (来源地址,真实目标地址) -> 进入代理的来源端口
+    remapped: HashMap<(SocketAddrV4, SocketAddrV4), u16>,
+    /// 进入代理的来源地址 -> 来源SYN包的TOS，只在开启tcp_tos_passthrough时记录
+    tos: HashMap<SocketAddrV4, u8>,
+    /// 映射的最大数量，为0则不限制
+    max: usize,
+}
+
+impl TcpNat {
+    /// 已有映射时返回进入代理的来源端口，并刷新使用时间
+    fn find(&mut self, source: SocketAddrV4, dest: SocketAddrV4, now: Instant) -> Option<u16> {
+        let port = self
+            .remapped
+            .get(&(source, dest))
+            .copied()
+            .unwrap_or(source.port());
+        match self.map.get_mut(&SocketAddrV4::new(*source.ip(), port)) {
+            Some((mapping, time)) if *mapping == (dest, source.port()) => {
+                *time = now;
+                Some(port)
+            }
+            _ => None,
+        }
+    }
+    /// 记录来源到目标的映射，返回进入代理的来源端口。
+    /// 来源端口被同一个来源到其他目标的连接占用时，换一个没有使用的端口
+    fn insert(&mut self, source: SocketAddrV4, dest: SocketAddrV4, now: Instant) -> u16 {
+        if let Some(port) = self.find(source, dest, now) {
+            return port;
+        }
+        self.remapped.remove(&(source, dest));
+        let mut port = source.port();
+        // 端口全部被占用时只能覆盖原来的映射
+        for _ in 0..u16::MAX {
+            if !self
+                .map
+                .contains_key(&SocketAddrV4::new(*source.ip(), port))
+            {
+                break;
+            }
+            port = port.checked_add(1).unwrap_or(1);
+        }
+        if port != source.port() {
+            self.remapped.insert((source, dest), port);
+        }
+        let mapped = SocketAddrV4::new(*source.ip(), port);
+        if self.max != 0 && self.map.len() >= self.max && !self.map.contains_key(&mapped) {
+            self.remove_oldest();
+        }
+        self.map.insert(
+            SocketAddrV4::new(*source.ip(), port),
+            ((dest, source.port()), now),
+        );
+        port
+    }
+    /// 记录连接的TOS，连接目标时使用
+    fn set_tos(&mut self, mapped: SocketAddrV4, tos: u8) {
+        if self.map.contains_key(&mapped) {
+            self.tos.insert(mapped, tos);
+        }
+    }
+    fn tos(&self, mapped: &SocketAddrV4) -> Option<u8> {
+        self.tos.get(mapped).copied()
+    }
+    /// 用进入代理的来源地址找到(真实目标地址, 原来的来源端口)
+    fn get(&self, mapped: &SocketAddrV4) -> Option<(SocketAddrV4, u16)> {
+        self.map.get(mapped).map(|(mapping, _)| *mapping)
+    }
+    fn get_mut(&mut self, mapped: &SocketAddrV4, now: Instant) -> Option<(SocketAddrV4, u16)> {
+        self.map.get_mut(mapped).map(|(mapping, time)| {
+            *time = now;
+            *mapping
+        })
+    }
+    /// 达到上限时删除最久没有使用的映射，大多是反向没有数据的短连接
+    fn remove_oldest(&mut self) {
+        let oldest = self
+            .map
+            .iter()
+            .min_by_key(|(_, (_, time))| *time)
+            .map(|(mapped, ((dest, _), _))| (*mapped, *dest));
+        if let Some((mapped, dest)) = oldest {
+            log::debug!("tcp proxy nat_map full, evict src={} dst={}", mapped, dest);
+            self.remove(&mapped, dest);
+        }
+    }
+    /// 删除映射，进入代理的来源端口可能已经被新的连接复用，所以要比较目标
+    fn remove(&mut self, mapped: &SocketAddrV4, dest: SocketAddrV4) {
+        if let Some(((addr, source_port), _)) = self.map.get(mapped) {
+            if *addr == dest {
+                let source = SocketAddrV4::new(*mapped.ip(), *source_port);
+                self.remapped.remove(&(source, dest));
+                self.map.remove(mapped);
+                self.tos.remove(mapped);
+            }
+        }
+    }
+}
+
+impl Evict for TcpNat {
+    fn evict(&mut self, ttl: Duration, now: Instant) {
+        self.map.evict(ttl, now);
+        let map = &self.map;
+        self.remapped.retain(|(source, dest), port| {
+            map.get(&SocketAddrV4::new(*source.ip(), *port))
+                .is_some_and(|(mapping, _)| *mapping == (*dest, source.port()))
+        });
+        self.tos.retain(|mapped, _| map.contains_key(mapped));
+    }
+}
+
+/// tcp代理连接的回调，用于把每条连接的记录输出到外部。
+/// 回调在代理的运行时线程里同步调用，必须很快返回、不能阻塞，
+/// 耗时的处理（例如写数据库）应该发送到channel交给其他线程
+pub trait ProxyObserver: Send + Sync {
+    /// 连接通过连接数限制、开始连接目标时调用，被拒绝的连接不会调用
+    fn on_open(&self, src: SocketAddr, dest: SocketAddrV4);
+    /// 调用过on_open的连接结束时调用，包括连接目标失败、转发出错、超时，每条连接只调用一次
+    fn on_close(
+        &self,
+        src: SocketAddr,
+        dest: SocketAddrV4,
+        bytes_up: u64,
+        bytes_down: u64,
+        duration: Duration,
+    );
+}
+
+impl std::fmt::Debug for dyn ProxyObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProxyObserver")
+    }
+}
+
+/// 占用的一个总连接数，释放时减少计数。
+/// socks5连接在握手和解析域名时还不知道目标，先用它计数，知道目标后转给ConnGuard
+struct ConnSlot {
+    stats: Arc<ProxyStats>,
+}
+
+impl ConnSlot {
+    /// 超过总连接数上限时返回None。
+    /// tun和socks5的连接在不同的任务里并发调用，原子地检查并占用
+    fn acquire(config: &ProxyConfig, stats: &Arc<ProxyStats>) -> Option<Self> {
+        let max = config.tcp_max_connections as u64;
+        stats
+            .active_connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                if max != 0 && active >= max {
+                    None
+                } else {
+                    Some(active + 1)
+                }
+            })
+            .ok()?;
+        Some(Self {
+            stats: stats.clone(),
+        })
+    }
+}
+
+impl Drop for ConnSlot {
+    fn drop(&mut self) {
+        self.stats
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 一条代理连接，结束时（包括连接目标失败）更新计数并输出关闭日志。
+/// 生命周期日志使用英文key=value格式，方便按id过滤
+struct ConnGuard {
+    /// 连接id，进程内递增
+    id: u64,
+    /// tun进入的连接是虚拟网络里的来源，socks5连接是socks5客户端的地址
+    sender_addr: SocketAddr,
+    dest_addr: SocketAddrV4,
+    start: Instant,
+    /// 这条连接来源->目标的字节数
+    upload_bytes: Arc<AtomicU64>,
+    /// 这条连接目标->来源的字节数
+    download_bytes: Arc<AtomicU64>,
+    /// 在drop里先更新其他计数再释放，Option只是为了能在drop里take
+    slot: Option<ConnSlot>,
+    stats: Arc<ProxyStats>,
+    dest_counts: DestCounts,
+    observer: Option<Arc<dyn ProxyObserver>>,
+    /// 连接结束的原因，出错的地方设置，没有设置就是正常结束
+    reason: AtomicCell<CloseReason>,
+    access_log: Option<AccessLog>,
+}
+
+impl ConnGuard {
+    /// 超过总连接数或者单个目标的连接数上限时返回None
+    fn acquire(
+        config: &ProxyConfig,
+        stats: &Arc<ProxyStats>,
+        dest_counts: &DestCounts,
+        access_log: &Option<AccessLog>,
+        sender_addr: SocketAddr,
+        dest_addr: SocketAddrV4,
+    ) -> Option<Self> {
+        let slot = ConnSlot::acquire(config, stats)?;
+        Self::with_slot(
+            slot,
+            config,
+            dest_counts,
+            access_log,
+            sender_addr,
+            dest_addr,
+        )
+    }
+    /// 已经占用了总连接数，单个目标超限时返回None，slot随之释放
+    fn with_slot(
+        slot: ConnSlot,
+        config: &ProxyConfig,
+        dest_counts: &DestCounts,
+        access_log: &Option<AccessLog>,
+        sender_addr: SocketAddr,
+        dest_addr: SocketAddrV4,
+    ) -> Option<Self> {
+        let stats = &slot.stats;
+        {
+            let mut guard = dest_counts.lock();
+            let count = guard.entry(*dest_addr.ip()).or_insert(0);
+            let max_per_dest = config.tcp_max_connections_per_dest;
+            if max_per_dest != 0 && *count >= max_per_dest {
+                if *count == 0 {
+                    guard.remove(dest_addr.ip());
+                }
+                return None;
+            }
+            *count += 1;
+        }
+        let id = stats.accepted.fetch_add(1, Ordering::Relaxed) + 1;
+        log::info!(
+            "tcp proxy accept id={} src={} dst={}",
+            id,
+            sender_addr,
+            dest_addr
+        );
+        if let Some(observer) = &config.tcp_observer {
+            observer.on_open(sender_addr, dest_addr);
+        }
+        let start = Instant::now();
+        let upload_bytes = Arc::new(AtomicU64::new(0));
+        let download_bytes = Arc::new(AtomicU64::new(0));
+        stats.connections.lock().insert(
+            id,
+            ConnEntry {
+                sender_addr,
+                dest_addr,
+                start,
+                upload_bytes: upload_bytes.clone(),
+                download_bytes: download_bytes.clone(),
+            },
+        );
+        Some(Self {
+            id,
+            sender_addr,
+            dest_addr,
+            start,
+            upload_bytes,
+            download_bytes,
+            stats: stats.clone(),
+            slot: Some(slot),
+            dest_counts: dest_counts.clone(),
+            observer: config.tcp_observer.clone(),
+            reason: AtomicCell::new(CloseReason::Eof),
+            access_log: access_log.clone(),
+        })
+    }
+    fn set_reason(&self, reason: CloseReason) {
+        self.reason.store(reason);
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        let up = self.upload_bytes.load(Ordering::Relaxed);
+        let down = self.download_bytes.load(Ordering::Relaxed);
+        let duration = self.start.elapsed();
+        let reason = self.reason.load();
+        log::info!(
+            "tcp proxy close id={} src={} dst={} up={} down={} duration_ms={} reason={}",
+            self.id,
+            self.sender_addr,
+            self.dest_addr,
+            up,
+            down,
+            duration.as_millis(),
+            reason.as_str()
+        );
+        if let Some(access_log) = &self.access_log {
+            access_log.write(AccessRecord {
+                time: SystemTime::now(),
+                id: self.id,
+                src: self.sender_addr,
+                dst: self.dest_addr,
+                upload_bytes: up,
+                download_bytes: down,
+                duration,
+                reason,
+            });
+        }
+        // 先回调再更新计数，closed计数增加时回调已经完成
+        if let Some(observer) = &self.observer {
+            observer.on_close(self.sender_addr, self.dest_addr, up, down, duration);
+        }
+        {
+            let mut guard = self.dest_counts.lock();
+            let dest_ip = self.dest_addr.ip();
+            if let Some(count) = guard.get_mut(dest_ip) {
+                *count -= 1;
+                if *count == 0 {
+                    guard.remove(dest_ip);
+                }
+            }
+        }
+        self.stats.connections.lock().remove(&self.id);
+        drop(self.slot.take());
+        self.stats.closed.fetch_add(1, Ordering::Relaxed);
+    }
+}
 
 #[derive(Clone)]
 pub struct TcpProxy {
     port: u16,
-    nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
+    /// 绑定了具体地址时，转发到代理的数据要改成这个目标地址
+    bind_ip: Option<Ipv4Addr>,
+    /// 来源SYN包的MSS大于这个值时改小，为0则不修改
+    mss: u16,
+    /// 记录来源SYN包的TOS，连接目标时设置到socket
+    tos_passthrough: bool,
+    /// 可以在运行时替换，见set_port_filter
+    port_filter: Arc<RwLock<PortFilter>>,
+    policy: Arc<ProxyPolicy>,
+    /// 可以在运行时修改速率，见set_rate_limit
+    rate_limiter: Arc<RateLimiter>,
+    /// 限制tcp代理和socks5合计每秒接收的新连接数，令牌是连接数
+    accept_limiter: Arc<RateLimiter>,
+    nat_map: TcpNatMap,
+    stats: Arc<ProxyStats>,
+    dest_counts: DestCounts,
+    /// 发送true后tcp代理和socks5都不再接收新连接
+    stop_accept: Arc<watch::Sender<bool>>,
+    /// 内置socks5服务端实际监听的地址
+    socks5_addr: Option<SocketAddr>,
+    access_log: Option<AccessLog>,
 }
 
 impl TcpProxy {
-    pub async fn new() -> anyhow::Result<Self> {
-        let nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>> =
-            Arc::new(Mutex::new(HashMap::with_capacity(16)));
-        let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", 0))
-            .await
-            .context("TcpProxy bind failed")?;
+    /// tcp_buf_len是每个转发方向的缓冲区大小，一条代理连接占用2*tcp_buf_len内存，
+    /// 连接数多的设备可以调小来节省内存，高带宽链路可以调大来减少读写次数。
+    /// 监听和转发的任务都spawn到调用者所在的tokio运行时，已经有运行时的应用可以直接在自己的运行时里创建，
+    /// 只有init_proxy会单独创建运行时和线程
+    pub async fn new(config: &ProxyConfig) -> anyhow::Result<Self> {
+        let buf_len = config.tcp_buf_len;
+        if buf_len < MIN_BUF_LEN {
+            return Err(anyhow!(
+                "TcpProxy buf_len {} less than {}",
+                buf_len,
+                MIN_BUF_LEN
+            ));
+        }
+        let capacity = map_capacity(config.tcp_max_connections);
+        let nat_map: TcpNatMap = Arc::new(Mutex::new(TcpNat {
+            map: HashMap::with_capacity(capacity),
+            max: config.tcp_nat_max,
+            ..TcpNat::default()
+        }));
+        // 代理的数据从tun进入，只会是ipv4
+        let bind_ip = match config.tcp_bind_addr {
+            IpAddr::V4(ip) if ip.is_unspecified() => None,
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(ip) if ip.is_unspecified() => None,
+            IpAddr::V6(ip) => {
+                return Err(anyhow!("TcpProxy bind_addr {} is not ipv4", ip));
+            }
+        };
+        let tcp_listener = listen(SocketAddr::new(config.tcp_bind_addr, 0), config)
+            .with_context(|| format!("TcpProxy bind {} failed", config.tcp_bind_addr))?;
         let port = tcp_listener.local_addr()?.port();
+        let socks5_listener = match config.socks5.as_ref() {
+            Some(socks5) => {
+                socks5.check().map_err(|e| anyhow!("socks5 listen {}", e))?;
+                Some(
+                    listen(socks5.addr, config)
+                        .with_context(|| format!("socks5 bind {} failed", socks5.addr))?,
+                )
+            }
+            None => None,
+        };
+        let socks5_addr = match socks5_listener.as_ref() {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
         {
-            let nat_map = nat_map.clone();
-            tokio::spawn(tcp_proxy(tcp_listener, nat_map));
+            if let Some(mark) = config.tcp_fwmark {
+                log::warn!(
+                    "tcp proxy fwmark={} is only supported on linux, ignored",
+                    mark
+                );
+            }
+            if let Some(device) = config.tcp_egress_device.as_ref() {
+                log::warn!(
+                    "tcp proxy egress_device={} is only supported on linux, ignored",
+                    device
+                );
+            }
+        }
+        #[cfg(not(unix))]
+        if !config.tcp_unix_targets.is_empty() {
+            log::warn!("tcp proxy unix_targets are only supported on unix, ignored");
+        }
+        let access_log = match config.tcp_access_log.as_ref() {
+            Some(path) => Some(AccessLog::open(
+                path,
+                config.tcp_access_log_max_size,
+                config.tcp_access_log_max_files,
+            )?),
+            None => None,
+        };
+        let config = Arc::new(config.clone());
+        let proxy = Self {
+            port,
+            bind_ip,
+            mss: config.tcp_mss,
+            tos_passthrough: config.tcp_tos_passthrough,
+            port_filter: Arc::new(RwLock::new(config.tcp_port_filter.clone())),
+            policy: Arc::new(config.tcp_policy.clone()),
+            // 所有连接的两个方向共用一个令牌桶
+            rate_limiter: Arc::new(RateLimiter::new(config.tcp_rate_limit)),
+            accept_limiter: Arc::new(RateLimiter::new(config.tcp_accept_rate)),
+            nat_map,
+            stats: Arc::new(ProxyStats {
+                connections: Mutex::new(HashMap::with_capacity(capacity)),
+                ..ProxyStats::default()
+            }),
+            dest_counts: Arc::new(Mutex::new(HashMap::new())),
+            stop_accept: Arc::new(watch::channel(false).0),
+            socks5_addr,
+            access_log,
+        };
+        log::info!(
+            "tcp proxy listen addr={}",
+            SocketAddr::new(config.tcp_bind_addr, port)
+        );
+        tokio::spawn(tcp_proxy(tcp_listener, proxy.clone(), config.clone()));
+        if let Some(socks5_listener) = socks5_listener {
+            log::info!("socks5 listen {:?}", socks5_addr);
+            tokio::spawn(socks5_proxy(socks5_listener, proxy.clone(), config.clone()));
+        }
+        spawn_evict(proxy.nat_map.clone(), config.tcp_nat_ttl);
+        Ok(proxy)
+    }
+    /// tcp代理实际监听的端口，由系统分配，来自tun的连接会被改写到这个端口
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+    /// 内置socks5服务端实际监听的地址，没有开启时为None
+    pub fn socks5_addr(&self) -> Option<SocketAddr> {
+        self.socks5_addr
+    }
+    /// 目标是否允许走代理
+    fn is_allowed(&self, dest_ip: Ipv4Addr, dest_port: u16) -> bool {
+        self.port_filter.read().is_allowed(dest_port) && self.policy.is_allowed(dest_ip, dest_port)
+    }
+    /// 替换端口过滤规则，只影响新连接，已经在代理的连接继续走代理
+    pub fn set_port_filter(&self, port_filter: PortFilter) {
+        *self.port_filter.write() = port_filter;
+    }
+    /// 修改所有连接合计的转发速率上限(字节/秒)，为0则不限制，已有的连接也会生效
+    pub fn set_rate_limit(&self, rate: u64) {
+        self.rate_limiter.set_rate(rate);
+    }
+    /// 当前的连接数和转发字节数
+    pub fn stats(&self) -> ProxyStatsSnapshot {
+        self.stats.snapshot()
+    }
+    /// 正在转发的连接和各自两个方向的字节数，按连接id排序，用于排查单个连接传输慢的问题
+    pub fn connections(&self) -> Vec<ConnSnapshot> {
+        self.stats.connections()
+    }
+    /// 当前的映射(来源地址,真实目标地址)，按来源地址排序，用于排查连接为什么没有走代理。
+    /// 复制时短暂持有nat_map的锁，转发的每个包都要用这个锁，不要频繁调用；
+    /// 返回的是复制的数据，调用方不会持有锁
+    pub fn mappings(&self) -> Vec<(SocketAddrV4, SocketAddrV4)> {
+        let mut mappings: Vec<(SocketAddrV4, SocketAddrV4)> = self
+            .nat_map
+            .lock()
+            .map
+            .iter()
+            .map(|(mapped, ((dest, source_port), _))| {
+                (SocketAddrV4::new(*mapped.ip(), *source_port), *dest)
+            })
+            .collect();
+        mappings.sort();
+        mappings
+    }
+    /// 停止接收新连接，等待已有连接自然结束，
+    /// 超过drain_timeout还没结束的连接数作为返回值，由调用方强制关闭
+    pub async fn drain(&self, drain_timeout: Duration) -> u64 {
+        self.stop_accept.send_replace(true);
+        let deadline = tokio::time::Instant::now() + drain_timeout;
+        loop {
+            let active = self.stats.active_connections.load(Ordering::Relaxed);
+            if active == 0 || tokio::time::Instant::now() >= deadline {
+                return active;
+            }
+            tokio::time::sleep_until(
+                deadline.min(tokio::time::Instant::now() + DRAIN_CHECK_INTERVAL),
+            )
+            .await;
         }
-        Ok(Self { port, nat_map })
     }
 }
 
@@ -40,110 +745,2728 @@ impl ProxyHandler for TcpProxy {
         ipv4: &mut IpV4Packet<&mut [u8]>,
         source: Ipv4Addr,
         destination: Ipv4Addr,
-    ) -> io::Result<bool> {
+    ) -> io::Result<ProxyAction> {
+        if ipv4.protocol() != Protocol::Tcp {
+            // 不是tcp的包不处理，交给其他处理器或者原样写入tun
+            return Ok(ProxyAction::PassThrough);
+        }
         let dest_ip = ipv4.destination_ip();
-        //转发到代理目标地址
-        let mut tcp_packet = TcpPacket::new(source, destination, ipv4.payload_mut())?;
+        // 只保留DSCP，ECN由本机协议栈处理
+        let tos = ipv4.dscp() << 2;
+        let proxy_ip = self.bind_ip.unwrap_or(destination);
+        //转发到代理目标地址，tcp头不完整的包直接丢弃
+        let Ok(mut tcp_packet) = TcpPacket::new(source, proxy_ip, ipv4.payload_mut()) else {
+            return Ok(ProxyAction::Drop);
+        };
         let source_port = tcp_packet.source_port();
         let dest_port = tcp_packet.destination_port();
+        let key = SocketAddrV4::new(source, source_port);
+        let dest_addr = SocketAddrV4::new(dest_ip, dest_port);
+        let mapped_port = if self.is_allowed(dest_ip, dest_port) {
+            self.nat_map.lock().insert(key, dest_addr, Instant::now())
+        } else {
+            // 规则修改前建立的连接还在映射里，继续走代理
+            match self.nat_map.lock().find(key, dest_addr, Instant::now()) {
+                Some(port) => port,
+                // 不代理的端口原样写入tun
+                None => return Ok(ProxyAction::PassThrough),
+            }
+        };
+        let syn = tcp_packet.flags().contains(packet::tcp::SYN);
+        if self.tos_passthrough && syn {
+            self.nat_map
+                .lock()
+                .set_tos(SocketAddrV4::new(source, mapped_port), tos);
+        }
+        tcp_packet.set_source_port(mapped_port);
         tcp_packet.set_destination_port(self.port);
+        if self.mss != 0 && syn {
+            // 本地发给来源的数据段不能超过隧道的mtu，否则会被分片或者丢弃
+            tcp_packet.clamp_mss(self.mss);
+        }
         tcp_packet.update_checksum();
-        ipv4.set_destination_ip(destination);
+        ipv4.set_destination_ip(proxy_ip);
         ipv4.update_checksum();
-        let key = SocketAddrV4::new(source, source_port);
-        self.nat_map
-            .lock()
-            .insert(key, SocketAddrV4::new(dest_ip, dest_port));
-        Ok(false)
+        // 改写成代理的监听地址后写入tun，由本机协议栈交给代理
+        Ok(ProxyAction::PassThrough)
     }
 
     fn send_handle(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
+        if ipv4.protocol() != Protocol::Tcp {
+            return Ok(());
+        }
         let src_ip = ipv4.source_ip();
         let dest_ip = ipv4.destination_ip();
-        let dest_addr = {
-            let tcp_packet = TcpPacket::new(src_ip, dest_ip, ipv4.payload_mut())?;
-            SocketAddrV4::new(dest_ip, tcp_packet.destination_port())
+        let (src_port, dest_addr) = {
+            // tcp头不完整的不会是代理的回复，原样发送
+            let Ok(tcp_packet) = TcpPacket::new(src_ip, dest_ip, ipv4.payload_mut()) else {
+                return Ok(());
+            };
+            (
+                tcp_packet.source_port(),
+                SocketAddrV4::new(dest_ip, tcp_packet.destination_port()),
+            )
         };
-        if let Some(source_addr) = self.nat_map.lock().get(&dest_addr) {
+        let mapping = self.nat_map.lock().get_mut(&dest_addr, Instant::now());
+        if let Some((source_addr, dest_port)) = mapping {
             let source_ip = *source_addr.ip();
             let mut tcp_packet = TcpPacket::new(source_ip, dest_ip, ipv4.payload_mut())?;
             tcp_packet.set_source_port(source_addr.port());
+            tcp_packet.set_destination_port(dest_port);
             tcp_packet.update_checksum();
             ipv4.set_source_ip(source_ip);
             ipv4.update_checksum();
+        } else if src_port == self.port {
+            // 其他端口的包不是代理发出的，不需要还原
+            self.stats.nat_miss.fetch_add(1, Ordering::Relaxed);
+            log::debug!("tcp proxy nat miss src_port={} dst={}", src_port, dest_addr);
         }
         Ok(())
     }
 }
 
-async fn tcp_proxy(
-    tcp_listener: TcpListener,
-    nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
-) {
+/// 按配置的SO_REUSEADDR和backlog创建监听socket
+fn listen(addr: SocketAddr, config: &ProxyConfig) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(config.tcp_reuse_addr)?;
+    socket.bind(addr)?;
+    socket.listen(config.tcp_listen_backlog)
+}
+
+async fn tcp_proxy(tcp_listener: TcpListener, shared: TcpProxy, config: Arc<ProxyConfig>) {
+    let mut stop_accept = shared.stop_accept.subscribe();
+    let TcpProxy {
+        nat_map,
+        stats,
+        dest_counts,
+        rate_limiter,
+        accept_limiter,
+        access_log,
+        ..
+    } = shared;
+    // 超限的日志做限流，避免被大量连接刷屏
+    let mut rejected = 0u64;
+    let mut last_warn: Option<Instant> = None;
     loop {
-        match tcp_listener.accept().await {
-            Ok((tcp_stream, sender_addr)) => match sender_addr {
-                SocketAddr::V4(sender_addr) => {
-                    if let Some(dest_addr) = nat_map.lock().get(&sender_addr).cloned() {
-                        tokio::spawn(async move {
-                            let peer_tcp_stream =
-                                match tcp_connect(sender_addr.port(), dest_addr.into()).await {
-                                    Ok(peer_tcp_stream) => peer_tcp_stream,
-                                    Err(e) => {
-                                        log::warn!(
-                                            "tcp代理异常:{:?},来源:{},目标：{}",
-                                            e,
-                                            sender_addr,
-                                            dest_addr
-                                        );
-                                        return;
-                                    }
-                                };
-                            proxy(sender_addr, dest_addr, tcp_stream, peer_tcp_stream).await
-                        });
-                    } else {
-                        log::warn!("tcp代理异常: 来源:{},未找到目标", sender_addr);
+        // 新连接的令牌不足时等待后再accept，期间的连接留在backlog里，
+        // 短时间内大量连接只会排队，不会占满代理
+        accept_limiter.acquire(1).await;
+        let rs = tokio::select! {
+            rs = tcp_listener.accept() => rs,
+            Ok(_) = stop_accept.wait_for(|stop| *stop) => {
+                log::info!("tcp proxy stop accepting");
+                return;
+            }
+        };
+        match rs {
+            Ok((tcp_stream, sender_addr)) => {
+                let sender_addr = match sender_addr {
+                    SocketAddr::V4(sender_addr) => sender_addr,
+                    SocketAddr::V6(sender_addr) => {
+                        // 双栈监听时ipv4来源以映射地址的形式出现
+                        if let Some(ip) = sender_addr.ip().to_ipv4_mapped() {
+                            SocketAddrV4::new(ip, sender_addr.port())
+                        } else {
+                            log::warn!("tcp proxy reject src={} reason=ipv6_source", sender_addr);
+                            continue;
+                        }
                     }
+                };
+                let (mapping, tos) = {
+                    let nat = nat_map.lock();
+                    (nat.get(&sender_addr), nat.tos(&sender_addr))
+                };
+                if let Some((dest_addr, source_port)) = mapping {
+                    // 来源端口冲突时进入代理的端口和原来的不同，日志和连接目标使用原来的端口
+                    let mapped_addr = sender_addr;
+                    let sender_addr = SocketAddrV4::new(*sender_addr.ip(), source_port);
+                    let guard = match ConnGuard::acquire(
+                        &config,
+                        &stats,
+                        &dest_counts,
+                        &access_log,
+                        sender_addr.into(),
+                        dest_addr,
+                    ) {
+                        Some(guard) => guard,
+                        None => {
+                            log::debug!(
+                                "tcp proxy reject src={} dst={} reason=connection_limit",
+                                sender_addr,
+                                dest_addr
+                            );
+                            stats.rejected.fetch_add(1, Ordering::Relaxed);
+                            rejected += 1;
+                            if !matches!(last_warn, Some(t) if t.elapsed() < LIMIT_WARN_INTERVAL) {
+                                log::warn!(
+                                    "tcp proxy connection limit reached rejected={}",
+                                    rejected
+                                );
+                                rejected = 0;
+                                last_warn = Some(Instant::now());
+                            }
+                            continue;
+                        }
+                    };
+                    let config = config.clone();
+                    let nat_map = nat_map.clone();
+                    let rate_limiter = rate_limiter.clone();
+                    tokio::spawn(async move {
+                        let peer_stream = match connect_dest(
+                            guard.id,
+                            sender_addr.into(),
+                            sender_addr.port(),
+                            dest_addr,
+                            tos,
+                            &config,
+                        )
+                        .await
+                        {
+                            Ok(peer_stream) => peer_stream,
+                            Err(e) => {
+                                let failure = ConnectFailure::classify(&e);
+                                guard.stats.connect_failed(failure);
+                                guard.set_reason(CloseReason::ConnectFailed);
+                                log::warn!(
+                                        "tcp proxy error id={} src={} dst={} reason=connect_{:?} error={:?}",
+                                        guard.id,
+                                        sender_addr,
+                                        dest_addr,
+                                        failure,
+                                        e
+                                    );
+                                // 连接已经结束，等待RST发出时不再占用连接数
+                                drop(guard);
+                                connect_failed(tcp_stream, &nat_map, mapped_addr, dest_addr).await;
+                                return;
+                            }
+                        };
+                        set_socket_options(&tcp_stream, &peer_stream, &config, dest_addr.port());
+                        proxy(tcp_stream, peer_stream, &config, &guard, &rate_limiter).await
+                    });
+                } else {
+                    log::warn!("tcp proxy reject src={} reason=no_mapping", sender_addr);
                 }
-                SocketAddr::V6(_) => {}
-            },
+            }
             Err(e) => {
-                log::warn!("tcp代理监听:{:?}", e);
+                log::warn!("tcp proxy accept error={:?}", e);
+                if is_fd_exhausted(&e) {
+                    // 等待的连接还在backlog里，监听socket一直可读，立即重试会空转，
+                    // 等已有连接关闭释放描述符后再accept
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+/// 连接目标失败时给来源回RST，让来源的tcp栈立即感知失败，而不是连接建立后又无故断开，
+/// 然后删除这次连接的映射
+async fn connect_failed(
+    tcp_stream: TcpStream,
+    nat_map: &TcpNatMap,
+    mapped_addr: SocketAddrV4,
+    dest_addr: SocketAddrV4,
+) {
+    // linger为0时close会直接发送RST
+    let _ = socket2::SockRef::from(&tcp_stream).set_linger(Some(Duration::ZERO));
+    drop(tcp_stream);
+    // RST要经过send_handle改写地址，所以映射稍后再删
+    tokio::time::sleep(RST_FLUSH_DELAY).await;
+    nat_map.lock().remove(&mapped_addr, dest_addr);
+}
+
+/// 内置socks5服务端，CONNECT的目标和tun进入的连接一样经过过滤规则、连接数限制和限速，
+/// 然后用相同的方式连接目标并转发
+async fn socks5_proxy(listener: TcpListener, shared: TcpProxy, config: Arc<ProxyConfig>) {
+    let mut stop_accept = shared.stop_accept.subscribe();
+    loop {
+        shared.accept_limiter.acquire(1).await;
+        let rs = tokio::select! {
+            rs = listener.accept() => rs,
+            Ok(_) = stop_accept.wait_for(|stop| *stop) => {
+                log::info!("socks5 stop accepting");
+                return;
+            }
+        };
+        match rs {
+            Ok((stream, sender_addr)) => {
+                tokio::spawn(socks5_connection(
+                    stream,
+                    sender_addr,
+                    shared.clone(),
+                    config.clone(),
+                ));
+            }
+            Err(e) => {
+                log::warn!("socks5 accept error={:?}", e);
+                if is_fd_exhausted(&e) {
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+async fn socks5_connection(
+    mut stream: TcpStream,
+    sender_addr: SocketAddr,
+    shared: TcpProxy,
+    config: Arc<ProxyConfig>,
+) {
+    // 握手和解析域名时也占用连接数，否则不发请求的客户端不受tcp_max_connections限制
+    let slot = match ConnSlot::acquire(&config, &shared.stats) {
+        Some(slot) => slot,
+        None => {
+            log::debug!("socks5 reject src={} reason=connection_limit", sender_addr);
+            shared.stats.rejected.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    let auth = config
+        .socks5
+        .as_ref()
+        .and_then(|socks5| socks5.auth.as_ref());
+    // 握手也使用连接超时，避免不发请求的客户端一直占用
+    let target = match tokio::time::timeout(
+        config.tcp_connect_timeout,
+        socks5::accept(&mut stream, auth),
+    )
+    .await
+    {
+        Ok(Ok(target)) => target,
+        Ok(Err(e)) => {
+            log::warn!("socks5 reject src={} error={:?}", sender_addr, e);
+            return;
+        }
+        Err(_) => {
+            log::warn!("socks5 reject src={} reason=handshake_timeout", sender_addr);
+            return;
+        }
+    };
+    let dest_addr = match tokio::time::timeout(config.tcp_connect_timeout, resolve_target(&target))
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "resolve target timeout",
+            ))
+        }) {
+        Ok(dest_addr) => dest_addr,
+        Err(e) => {
+            log::warn!(
+                "socks5 reject src={} dst={:?} error={:?}",
+                sender_addr,
+                target,
+                e
+            );
+            let _ = socks5::reply(&mut stream, socks5::error_reply(e.kind()), None).await;
+            return;
+        }
+    };
+    if !shared.is_allowed(*dest_addr.ip(), dest_addr.port()) {
+        log::debug!(
+            "socks5 reject src={} dst={} reason=policy",
+            sender_addr,
+            dest_addr
+        );
+        let _ = socks5::reply(&mut stream, socks5::REPLY_NOT_ALLOWED, None).await;
+        return;
+    }
+    let guard = match ConnGuard::with_slot(
+        slot,
+        &config,
+        &shared.dest_counts,
+        &shared.access_log,
+        sender_addr,
+        dest_addr,
+    ) {
+        Some(guard) => guard,
+        None => {
+            log::debug!(
+                "socks5 reject src={} dst={} reason=connection_limit",
+                sender_addr,
+                dest_addr
+            );
+            shared.stats.rejected.fetch_add(1, Ordering::Relaxed);
+            let _ = socks5::reply(&mut stream, socks5::REPLY_GENERAL_FAILURE, None).await;
+            return;
+        }
+    };
+    let peer_stream = match connect_dest(guard.id, sender_addr, 0, dest_addr, None, &config).await {
+        Ok(peer_stream) => peer_stream,
+        Err(e) => {
+            let failure = ConnectFailure::classify(&e);
+            guard.stats.connect_failed(failure);
+            guard.set_reason(CloseReason::ConnectFailed);
+            log::warn!(
+                "tcp proxy error id={} src={} dst={} reason=connect_{:?} error={:?}",
+                guard.id,
+                sender_addr,
+                dest_addr,
+                failure,
+                e
+            );
+            let _ = socks5::reply(&mut stream, failure.socks5_reply(), None).await;
+            return;
+        }
+    };
+    let bind = peer_stream
+        .tcp()
+        .and_then(|stream| stream.local_addr().ok());
+    if let Err(e) = socks5::reply(&mut stream, socks5::REPLY_SUCCEEDED, bind).await {
+        guard.set_reason(CloseReason::Error);
+        log::warn!(
+            "tcp proxy error id={} src={} dst={} reason=socks5_reply error={:?}",
+            guard.id,
+            sender_addr,
+            dest_addr,
+            e
+        );
+        return;
+    }
+    set_socket_options(&stream, &peer_stream, &config, dest_addr.port());
+    proxy(stream, peer_stream, &config, &guard, &shared.rate_limiter).await
+}
+
+/// 代理的统计和限制都是按ipv4目标计算的，所以socks5也只支持ipv4目标，域名取第一个ipv4地址
+async fn resolve_target(target: &TargetAddr) -> io::Result<SocketAddrV4> {
+    let unsupported = || io::Error::new(io::ErrorKind::Unsupported, "ipv6 target");
+    match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => Ok(*addr),
+        TargetAddr::Ip(SocketAddr::V6(_)) => Err(unsupported()),
+        TargetAddr::Domain(domain, port) => tokio::net::lookup_host((domain.as_str(), *port))
+            .await?
+            .find_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::HostUnreachable,
+                    format!("{} no ipv4 address", domain),
+                )
+            }),
+    }
+}
+
+/// 设置两端的nodelay和keepalive，unix socket目标没有这些选项，只设置来源
+fn set_socket_options(
+    src_stream: &TcpStream,
+    dest_stream: &TargetStream,
+    config: &ProxyConfig,
+    dest_port: u16,
+) {
+    let dest_stream = dest_stream.tcp().unwrap_or(src_stream);
+    set_nodelay(src_stream, dest_stream, config.tcp_nodelay_for(dest_port));
+    set_keepalive(src_stream, dest_stream, config);
+}
+
+/// 来源和目标两端使用相同的nodelay设置
+fn set_nodelay(src_stream: &TcpStream, dest_stream: &TcpStream, nodelay: bool) {
+    if let Err(e) = src_stream.set_nodelay(nodelay) {
+        log::warn!("tcp proxy set_nodelay error={:?}", e);
+    }
+    if let Err(e) = dest_stream.set_nodelay(nodelay) {
+        log::warn!("tcp proxy set_nodelay error={:?}", e);
+    }
+}
+
+/// 两端都开启TCP keepalive，tcp_keepalive_idle为0时不修改
+fn set_keepalive(src_stream: &TcpStream, dest_stream: &TcpStream, config: &ProxyConfig) {
+    if config.tcp_keepalive_idle.is_zero() {
+        return;
+    }
+    let keepalive = tcp_keepalive(config);
+    for stream in [src_stream, dest_stream] {
+        if let Err(e) = socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+            log::warn!("tcp proxy set_keepalive error={:?}", e);
+        }
+    }
+}
+
+fn tcp_keepalive(config: &ProxyConfig) -> socket2::TcpKeepalive {
+    let keepalive = socket2::TcpKeepalive::new().with_time(config.tcp_keepalive_idle);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "windows"
+    ))]
+    let keepalive = if config.tcp_keepalive_interval.is_zero() {
+        keepalive
+    } else {
+        keepalive.with_interval(config.tcp_keepalive_interval)
+    };
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    ))]
+    let keepalive = if config.tcp_keepalive_count == 0 {
+        keepalive
+    } else {
+        keepalive.with_retries(config.tcp_keepalive_count)
+    };
+    keepalive
+}
+
+/// 连接真实目标的流，unix下目标可以映射到本机的unix socket
+enum TargetStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+    /// 匹配tcp_tls规则的目标，握手后转发的是加密后的数据
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+impl TargetStream {
+    fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            TargetStream::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            TargetStream::Unix(_) => None,
+            #[cfg(feature = "tls")]
+            TargetStream::Tls(stream) => Some(stream.get_ref().0),
+        }
+    }
+    fn into_split(
+        self,
+    ) -> (
+        Box<dyn AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWrite + Send + Unpin>,
+    ) {
+        match self {
+            TargetStream::Tcp(stream) => {
+                let (read, write) = stream.into_split();
+                (Box::new(read), Box::new(write))
+            }
+            #[cfg(unix)]
+            TargetStream::Unix(stream) => {
+                let (read, write) = stream.into_split();
+                (Box::new(read), Box::new(write))
+            }
+            #[cfg(feature = "tls")]
+            TargetStream::Tls(stream) => {
+                let (read, write) = tokio::io::split(*stream);
+                (Box::new(read), Box::new(write))
             }
         }
     }
 }
-/// 优先使用来源端口建立tcp连接
-async fn tcp_connect(src_port: u16, addr: SocketAddr) -> anyhow::Result<TcpStream> {
-    let socket = TcpSocket::new_v4()?;
-    if socket
-        .bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, src_port).into())
-        .is_err()
+
+/// 目标配置了unix socket时直接连接unix socket，不经过上游代理，否则和connect_target一样。
+/// src是真实的来源地址，目标匹配tcp_proxy_protocol时连接后先发送携带它的PROXY protocol头部，
+/// src_port是连接目标时优先使用的本地端口。目标匹配tcp_tls时在发送头部之后发起TLS握手
+async fn connect_dest(
+    id: u64,
+    src: SocketAddr,
+    src_port: u16,
+    dest: SocketAddrV4,
+    tos: Option<u8>,
+    config: &ProxyConfig,
+) -> anyhow::Result<TargetStream> {
+    let header = proxy_protocol::find(&config.tcp_proxy_protocol, dest)
+        .map(|version| (version, version.header(src, dest.into())));
+    #[cfg(unix)]
+    if let Some(path) = config.tcp_unix_targets.get(&dest) {
+        let mut stream = tokio::time::timeout(
+            config.tcp_connect_timeout,
+            tokio::net::UnixStream::connect(path),
+        )
+        .await
+        .with_context(|| format!("unix socket connection timeout {:?}", path))?
+        .with_context(|| format!("unix socket connection failed {:?}", path))?;
+        log::debug!("tcp proxy unix id={} dst={} path={:?}", id, dest, path);
+        if let Some((version, header)) = header {
+            write_proxy_header(id, &mut stream, version, &header).await?;
+        }
+        return Ok(TargetStream::Unix(stream));
+    }
+    let mut stream = connect_target(id, src_port, dest.into(), tos, config).await?;
+    if let Some((version, header)) = header {
+        write_proxy_header(id, &mut stream, version, &header).await?;
+    }
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &config.tcp_tls {
+        if let Some(server_name) = tls.find(dest) {
+            let name = server_name.to_str().into_owned();
+            let stream =
+                tokio::time::timeout(config.tcp_connect_timeout, tls.connect(server_name, stream))
+                    .await
+                    .with_context(|| format!("tls handshake timeout {}", name))?
+                    .with_context(|| format!("tls handshake failed {}", name))?;
+            log::debug!("tcp proxy tls id={} dst={} sni={}", id, dest, name);
+            return Ok(TargetStream::Tls(Box::new(stream)));
+        }
+    }
+    Ok(TargetStream::Tcp(stream))
+}
+
+/// 头部必须在转发任何数据之前发送，刚建立的连接发送缓冲区是空的，不会阻塞
+async fn write_proxy_header<W: AsyncWrite + Unpin>(
+    id: u64,
+    stream: &mut W,
+    version: proxy_protocol::ProxyProtocolVersion,
+    header: &[u8],
+) -> anyhow::Result<()> {
+    stream
+        .write_all(header)
+        .await
+        .with_context(|| format!("send proxy protocol {:?} header failed", version))?;
+    log::debug!("tcp proxy id={} proxy_protocol={:?}", id, version);
+    Ok(())
+}
+
+/// 根据配置直接连接目标，或者经过上游代理连接目标，id是连接id，只用于日志
+async fn connect_target(
+    id: u64,
+    src_port: u16,
+    dest: SocketAddr,
+    tos: Option<u8>,
+    config: &ProxyConfig,
+) -> anyhow::Result<TcpStream> {
+    let dest = match dest {
+        SocketAddr::V4(addr) => match nat64::map_dest(&config.tcp_nat64, addr) {
+            Some(mapped) => {
+                log::debug!("tcp proxy nat64 id={} dst={} mapped={}", id, addr, mapped);
+                SocketAddr::V6(mapped)
+            }
+            None => dest,
+        },
+        SocketAddr::V6(_) => dest,
+    };
+    match &config.tcp_upstream {
+        UpstreamProxy::Direct => tcp_connect(id, src_port, dest, tos, config).await,
+        UpstreamProxy::Socks5 { addr, auth } => {
+            // 连接代理和握手共用一个超时时间
+            tokio::time::timeout(config.tcp_connect_timeout, async {
+                let mut tcp_stream = tcp_connect(id, 0, *addr, tos, config).await?;
+                socks5::handshake(&mut tcp_stream, dest, auth.as_ref())
+                    .await
+                    .with_context(|| format!("socks5 {} connect target failed {}", addr, dest))?;
+                Ok(tcp_stream)
+            })
+            .await
+            .with_context(|| format!("socks5 {} connection timeout {}", addr, dest))?
+        }
+    }
+}
+
+/// 优先使用来源端口建立tcp连接，根据目标地址选择ipv4或ipv6，
+/// 来源端口被占用时tcp_require_src_port为true则返回错误，否则使用随机端口。
+/// 出口地址tcp_egress_bind只用于ipv4目标，fwmark和网卡绑定只在linux上生效，其他平台在创建代理时警告。
+/// tos只用于ipv4目标，设置失败时只记录日志
+async fn tcp_connect(
+    id: u64,
+    src_port: u16,
+    addr: SocketAddr,
+    tos: Option<u8>,
+    config: &ProxyConfig,
+) -> anyhow::Result<TcpStream> {
+    let (socket, bind_ip) = match addr {
+        SocketAddr::V4(_) => (
+            TcpSocket::new_v4()?,
+            IpAddr::V4(config.tcp_egress_bind.unwrap_or(Ipv4Addr::UNSPECIFIED)),
+        ),
+        SocketAddr::V6(_) => (TcpSocket::new_v6()?, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+    if let (Some(tos), SocketAddr::V4(_)) = (tos, addr) {
+        // 连接前设置，SYN也带上来源的标记
+        if let Err(e) = socket2::SockRef::from(&socket).set_tos(tos as u32) {
+            log::warn!("tcp proxy id={} set_tos={} error={:?}", id, tos, e);
+        }
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
     {
-        socket.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into())?;
+        let sock_ref = socket2::SockRef::from(&socket);
+        if let Some(mark) = config.tcp_fwmark {
+            sock_ref
+                .set_mark(mark)
+                .with_context(|| format!("set fwmark {} failed", mark))?;
+        }
+        if let Some(device) = config.tcp_egress_device.as_ref() {
+            sock_ref
+                .bind_device(Some(device.as_bytes()))
+                .with_context(|| format!("bind device {} failed", device))?;
+        }
     }
-    let _ = socket.set_nodelay(false);
-    let tcp_stream = tokio::time::timeout(Duration::from_secs(5), socket.connect(addr))
+    if let Err(e) = socket.bind(SocketAddr::new(bind_ip, src_port)) {
+        if config.tcp_require_src_port {
+            return Err(e).with_context(|| format!("bind source port {} failed", src_port));
+        }
+        socket
+            .bind(SocketAddr::new(bind_ip, 0))
+            .with_context(|| format!("bind {} failed", bind_ip))?;
+        log::info!(
+            "tcp proxy id={} src_port={} unavailable fallback_port={} dst={} error={:?}",
+            id,
+            src_port,
+            socket.local_addr()?.port(),
+            addr,
+            e
+        );
+    }
+    let tcp_stream = tokio::time::timeout(config.tcp_connect_timeout, socket.connect(addr))
         .await
         .with_context(|| format!("TCP connection timeout {}", addr))?
         .with_context(|| format!("TCP connection target failed {}", addr))?;
     Ok(tcp_stream)
 }
 
+/// 双向转发。没有使用tokio::io::copy_bidirectional，因为每个方向要单独统计字节数、限速、
+/// 检查写入停滞，两个方向还要共用空闲超时，这些都需要在每次读写之间处理
 async fn proxy(
-    sender_addr: SocketAddrV4,
-    dest_addr: SocketAddrV4,
     client: TcpStream,
-    server: TcpStream,
+    server: TargetStream,
+    config: &ProxyConfig,
+    conn: &ConnGuard,
+    rate_limiter: &RateLimiter,
 ) {
+    let stats = &conn.stats;
+    let buf_len = config.tcp_buf_len;
     let (mut client_read, mut client_write) = client.into_split();
     let (mut server_read, mut server_write) = server.into_split();
+    let state = ConnState::new();
+    let conn_limiter = RateLimiter::new(config.tcp_rate_limit_per_conn);
+    let observer = config.packet_observer.as_deref();
+    let dest_addr = SocketAddr::V4(conn.dest_addr);
+    let on_upload = |len: usize| {
+        stats.upload_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        conn.upload_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(observer) = observer {
+            observer.on_packet(
+                conn.sender_addr,
+                dest_addr,
+                Protocol::Tcp,
+                len,
+                Direction::Upload,
+            );
+        }
+    };
+    let on_download = |len: usize| {
+        stats
+            .download_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+        conn.download_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(observer) = observer {
+            observer.on_packet(
+                dest_addr,
+                conn.sender_addr,
+                Protocol::Tcp,
+                len,
+                Direction::Download,
+            );
+        }
+    };
+    // 读到的数据写完才会继续读，读到EOF时没有未写出的数据，写端在这之后drop，
+    // 对端能收到完整数据和FIN，保证半关闭正常传递。
+    // 空闲超时或者一个方向写入停滞（受tcp_write_timeout限制）时通过closing关闭整个连接，
+    // 两个方向都不再读取，已经读到的数据最多再等CLOSE_FLUSH_TIMEOUT写完
+    let client_to_server = async {
+        let rs = copy(
+            &mut client_read,
+            &mut server_write,
+            buf_len,
+            &state,
+            &on_upload,
+            [rate_limiter, &conn_limiter],
+            config.tcp_write_timeout,
+        )
+        .await;
+        drop(server_write);
+        if direction_result(conn, "upload", rs).is_err() {
+            state.close();
+        }
+    };
+    let server_to_client = async {
+        let rs = copy(
+            &mut server_read,
+            &mut client_write,
+            buf_len,
+            &state,
+            &on_download,
+            [rate_limiter, &conn_limiter],
+            config.tcp_write_timeout,
+        )
+        .await;
+        drop(client_write);
+        if direction_result(conn, "download", rs).is_err() {
+            state.close();
+        }
+    };
+    let transfer = async { tokio::join!(client_to_server, server_to_client) };
+    tokio::pin!(transfer);
+    tokio::select! {
+        _ = &mut transfer => return,
+        _ = idle_timeout(&state.last_active, config.tcp_idle_timeout) => {
+            conn.set_reason(CloseReason::IdleTimeout);
+            log::warn!(
+                "tcp proxy error id={} src={} dst={} reason=idle_timeout",
+                conn.id,
+                conn.sender_addr,
+                conn.dest_addr
+            );
+            state.close();
+        }
+    }
+    transfer.await;
+}
+
+/// 一条连接两个方向共用的状态
+struct ConnState {
+    /// 最后一次读到数据的时间，用于空闲超时
+    last_active: AtomicCell<Instant>,
+    /// 变为true时两个方向都结束转发
+    closing: watch::Sender<bool>,
+}
+
+impl ConnState {
+    fn new() -> Self {
+        Self {
+            last_active: AtomicCell::new(Instant::now()),
+            closing: watch::channel(false).0,
+        }
+    }
+    fn close(&self) {
+        self.closing.send_replace(true);
+    }
+}
+
+/// 记录单向转发的结束，只有写入停滞需要关闭整个连接，返回Err，其他错误只结束这个方向
+fn direction_result(conn: &ConnGuard, direction: &str, rs: io::Result<u64>) -> Result<(), ()> {
+    match rs {
+        Ok(bytes) => {
+            // 读到EOF，已经把FIN传给另一端
+            log::debug!(
+                "tcp proxy shutdown id={} src={} dst={} direction={} bytes={}",
+                conn.id,
+                conn.sender_addr,
+                conn.dest_addr,
+                direction,
+                bytes
+            );
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            conn.set_reason(CloseReason::WriteTimeout);
+            log::warn!(
+                "tcp proxy error id={} src={} dst={} reason={}_write_timeout",
+                conn.id,
+                conn.sender_addr,
+                conn.dest_addr,
+                direction
+            );
+            Err(())
+        }
+        Err(e) => {
+            conn.set_reason(CloseReason::Error);
+            log::warn!(
+                "tcp proxy error id={} src={} dst={} reason={} error={:?}",
+                conn.id,
+                conn.sender_addr,
+                conn.dest_addr,
+                direction,
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+/// 两个方向都没有数据的时间超过idle_timeout时返回
+async fn idle_timeout(last_active: &AtomicCell<Instant>, idle_timeout: Duration) {
+    loop {
+        let idle = last_active.load().elapsed();
+        if idle >= idle_timeout {
+            return;
+        }
+        tokio::time::sleep(idle_timeout - idle).await;
+    }
+}
+
+/// 单向转发，缓冲区在堆上分配，整个连接只分配一次并重复使用，每次写入后累加到counters(总计数和单个连接的计数)。
+/// 写不进去时不会继续读取，对端缓冲区满的背压通过tcp窗口传回来源，
+/// 限速时令牌不足也一样，写完后等待令牌补齐再读取，rate_limiters是所有连接合计和这个连接的限速。
+/// write_timeout不为0时，一次写入等待超过这个时间都没写进数据就返回TimedOut。
+/// 连接关闭(state.close)后不再读取，正在写的数据继续写，最多等待CLOSE_FLUSH_TIMEOUT，写完后flush并返回
+async fn copy<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    buf_len: usize,
+    state: &ConnState,
+    on_data: &F,
+    rate_limiters: [&RateLimiter; 2],
+    write_timeout: Duration,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(usize),
+{
+    let mut closing = state.closing.subscribe();
+    let mut buf = vec![0u8; buf_len];
+    let mut total = 0u64;
+    loop {
+        // 已经关闭时即使还有数据可读也不再读取
+        let len = tokio::select! {
+            biased;
+            Ok(_) = closing.wait_for(|closing| *closing) => 0,
+            rs = reader.read(&mut buf) => rs?,
+        };
+        if len == 0 {
+            writer.flush().await?;
+            return Ok(total);
+        }
+        state.last_active.store(Instant::now());
+        let write_all = async {
+            let mut pos = 0;
+            while pos < len {
+                pos += write_some(writer, &buf[pos..len], write_timeout).await?;
+            }
+            io::Result::Ok(())
+        };
+        tokio::pin!(write_all);
+        let closed = tokio::select! {
+            rs = &mut write_all => {
+                rs?;
+                false
+            }
+            Ok(_) = closing.wait_for(|closing| *closing) => true,
+        };
+        if closed {
+            tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, write_all)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "close flush timeout"))??;
+        }
+        on_data(len);
+        total += len as u64;
+        for rate_limiter in rate_limiters {
+            tokio::select! {
+                _ = rate_limiter.acquire(len) => {}
+                Ok(_) = closing.wait_for(|closing| *closing) => break,
+            }
+        }
+    }
+}
+
+/// 写入一次，write_timeout为0时一直等待
+async fn write_some<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    buf: &[u8],
+    write_timeout: Duration,
+) -> io::Result<usize> {
+    let len = if write_timeout.is_zero() {
+        writer.write(buf).await?
+    } else {
+        tokio::time::timeout(write_timeout, writer.write(buf))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "write stalled"))??
+    };
+    if len == 0 {
+        return Err(io::ErrorKind::WriteZero.into());
+    }
+    Ok(len)
+}
+
+/// 记录写入次数的writer
+#[cfg(test)]
+#[derive(Default)]
+struct CountingWriter {
+    data: Vec<u8>,
+    writes: usize,
+    /// 每次最多写入的字节数，为0则不限制
+    max_write: usize,
+}
+
+#[cfg(test)]
+impl AsyncWrite for CountingWriter {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.writes += 1;
+        let len = match self.max_write {
+            0 => buf.len(),
+            max_write => buf.len().min(max_write),
+        };
+        self.data.extend_from_slice(&buf[..len]);
+        std::task::Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_copy_write_calls() {
+    // 每次读到的数据在一个连续的缓冲区里，写得进去时一次写完，没有需要合并的分段
+    let data: Vec<u8> = (0..100_000u32).map(|v| v as u8).collect();
+    let mut reader = &data[..];
+    let mut writer = CountingWriter::default();
+    let counter = AtomicU64::new(0);
+    let limiter = RateLimiter::new(0);
+    let total = copy(
+        &mut reader,
+        &mut writer,
+        DEFAULT_BUF_LEN,
+        &ConnState::new(),
+        &|len| {
+            counter.fetch_add(len as u64, Ordering::Relaxed);
+        },
+        [&limiter, &limiter],
+        Duration::ZERO,
+    )
+    .await
+    .unwrap();
+    assert_eq!(total, data.len() as u64);
+    assert_eq!(counter.load(Ordering::Relaxed), total);
+    assert_eq!(writer.data, data);
+    assert_eq!(writer.writes, data.len().div_ceil(DEFAULT_BUF_LEN));
+}
+
+#[tokio::test]
+async fn test_copy_partial() {
+    // 缓冲区只分配一次，数据远大于缓冲区，每次读到的数据不满缓冲区，每次也只写得进一部分
+    let data: Vec<u8> = (0..200_000u32).map(|v| (v % 251) as u8).collect();
+    let (mut reader, mut source) = tokio::io::duplex(700);
+    let upload = {
+        let data = data.clone();
+        tokio::spawn(async move {
+            for chunk in data.chunks(333) {
+                source.write_all(chunk).await.unwrap();
+            }
+        })
+    };
+    let mut writer = CountingWriter {
+        max_write: 1000,
+        ..CountingWriter::default()
+    };
+    let limiter = RateLimiter::new(0);
+    let total = copy(
+        &mut reader,
+        &mut writer,
+        MIN_BUF_LEN,
+        &ConnState::new(),
+        &|_| {},
+        [&limiter, &limiter],
+        Duration::ZERO,
+    )
+    .await
+    .unwrap();
+    upload.await.unwrap();
+    assert_eq!(total, data.len() as u64);
+    assert_eq!(writer.data, data);
+    assert!(writer.writes >= data.len().div_ceil(1000));
+}
+
+#[tokio::test]
+async fn test_copy_close_flush() {
+    // 关闭时最后一次写入还没完成，已经读到的数据要写完再结束，不再读取新数据
+    let data = vec![5u8; 1000];
+    let (mut reader, mut source) = tokio::io::duplex(64 * 1024);
+    source.write_all(&data).await.unwrap();
+    let (mut writer, mut target) = tokio::io::duplex(100);
+    let state = ConnState::new();
+    let limiter = RateLimiter::new(0);
+    let transfer = copy(
+        &mut reader,
+        &mut writer,
+        DEFAULT_BUF_LEN,
+        &state,
+        &|_| {},
+        [&limiter, &limiter],
+        Duration::ZERO,
+    );
+    let close = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        state.close();
+        source.write_all(&[6u8; 100]).await.unwrap();
+        let mut buf = vec![0u8; data.len()];
+        target.read_exact(&mut buf).await.unwrap();
+        buf
+    };
+    let (total, buf) = tokio::join!(transfer, close);
+    assert_eq!(total.unwrap(), data.len() as u64);
+    assert_eq!(buf, data);
+
+    // 对端一直不读时最多等待CLOSE_FLUSH_TIMEOUT
+    let (mut reader, mut source) = tokio::io::duplex(64 * 1024);
+    source.write_all(&data).await.unwrap();
+    let (mut writer, _target) = tokio::io::duplex(100);
+    let state = ConnState::new();
+    let transfer = copy(
+        &mut reader,
+        &mut writer,
+        DEFAULT_BUF_LEN,
+        &state,
+        &|_| {},
+        [&limiter, &limiter],
+        Duration::ZERO,
+    );
+    let start = Instant::now();
+    let close = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        state.close();
+    };
+    let (rs, _) = tokio::join!(transfer, close);
+    assert_eq!(rs.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= CLOSE_FLUSH_TIMEOUT);
+    assert!(start.elapsed() < CLOSE_FLUSH_TIMEOUT * 2);
+}
+
+#[tokio::test]
+async fn test_tcp_connect_ipv6() {
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ProxyConfig::default();
+    let (stream, accept) = tokio::join!(tcp_connect(0, 0, addr, None, &config), listener.accept());
+    let stream = stream.unwrap();
+    let (_, peer_addr) = accept.unwrap();
+    assert!(stream.local_addr().unwrap().is_ipv6());
+    assert_eq!(stream.local_addr().unwrap(), peer_addr);
+}
+
+#[tokio::test]
+async fn test_nat64() {
+    let config = ProxyConfig {
+        tcp_nat64: vec!["0.0.0.1/32,::".parse().unwrap()],
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // 0.0.0.1映射为::1
+    let mut client =
+        connect_via_proxy(&proxy, SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 1), port)).await;
+    let (mut server, peer_addr) = listener.accept().await.unwrap();
+    assert!(peer_addr.is_ipv6());
+    client.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[tokio::test]
+async fn test_proxy_protocol() {
+    let (target, target_addr) = local_listener().await;
+    let config = ProxyConfig {
+        tcp_proxy_protocol: vec![format!("v1 {} {}", target_addr.ip(), target_addr.port())
+            .parse()
+            .unwrap()],
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let client_addr = client.local_addr().unwrap();
+    client.write_all(b"hello").await.unwrap();
+    let (mut stream, _) = target.accept().await.unwrap();
+    let expected = format!(
+        "PROXY TCP4 {} {} {} {}\r\nhello",
+        client_addr.ip(),
+        target_addr.ip(),
+        client_addr.port(),
+        target_addr.port()
+    );
+    let mut buf = vec![0u8; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected.as_bytes());
+    // 下行的数据不带头部
+    stream.write_all(b"world").await.unwrap();
+    let mut buf = [0u8; 5];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"world");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_target() {
+    let path = std::env::temp_dir().join(format!("vnt-proxy-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut read, mut write) = stream.split();
+        let _ = tokio::io::copy(&mut read, &mut write).await;
+    });
+    let target: SocketAddrV4 = "192.0.2.1:80".parse().unwrap();
+    let config = ProxyConfig {
+        tcp_unix_targets: HashMap::from([(target, path.clone())]),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let mut client = connect_via_proxy(&proxy, target).await;
+    client.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    drop(client);
+    wait_closed(&proxy, 1).await;
+    assert_eq!(proxy.stats().upload_bytes, 5);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_tls_target() {
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/src/ip_proxy/testdata/tls");
+    let certs = CertificateDer::pem_file_iter(format!("{}/server.pem", testdata))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_file(format!("{}/server.key", testdata)).unwrap();
+    let server_config = tokio_rustls::rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let (target, target_addr) = local_listener().await;
     tokio::spawn(async move {
-        if let Err(e) = tokio::io::copy(&mut client_read, &mut server_write).await {
-            log::warn!("client tcp proxy {}->{},{:?}", sender_addr, dest_addr, e);
+        loop {
+            let (stream, _) = target.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    let (mut read, mut write) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                }
+            });
+        }
+    });
+    let ca_file = format!("{}/ca.pem", testdata);
+    let tls_config = |rule: &str| ProxyConfig {
+        tcp_tls: Some(
+            crate::ip_proxy::tls::TlsOrigin::new(
+                vec![rule.parse().unwrap()],
+                Some(std::path::Path::new(&ca_file)),
+            )
+            .unwrap(),
+        ),
+        ..ProxyConfig::default()
+    };
+    // 证书包含127.0.0.1，不指定域名时使用目标ip校验
+    let proxy = TcpProxy::new(&tls_config(&target_addr.ip().to_string()))
+        .await
+        .unwrap();
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    client.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    drop(client);
+    wait_closed(&proxy, 1).await;
+    assert_eq!(proxy.stats().upload_bytes, 5);
+
+    // 域名和证书不匹配时握手失败，来源的连接被关闭
+    let rule = format!("{} {} other.test", target_addr.ip(), target_addr.port());
+    let proxy = TcpProxy::new(&tls_config(&rule)).await.unwrap();
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let mut buf = [0u8; 1];
+    assert!(!matches!(client.read(&mut buf).await, Ok(1)));
+    wait_closed(&proxy, 1).await;
+    assert_eq!(proxy.stats().connect_other, 1);
+}
+
+#[tokio::test]
+async fn test_tcp_connect_require_src_port() {
+    let (_target, target_addr) = local_listener().await;
+    // 占用一个端口作为来源端口
+    let (_used, used_addr) = local_listener().await;
+    let config = ProxyConfig {
+        tcp_require_src_port: true,
+        ..ProxyConfig::default()
+    };
+    let e = tcp_connect(0, used_addr.port(), target_addr.into(), None, &config)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<io::Error>().unwrap().kind(),
+        io::ErrorKind::AddrInUse
+    );
+    // 尽量保留时改用其他端口
+    let stream = tcp_connect(
+        0,
+        used_addr.port(),
+        target_addr.into(),
+        None,
+        &ProxyConfig::default(),
+    )
+    .await
+    .unwrap();
+    assert_ne!(stream.local_addr().unwrap().port(), used_addr.port());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tcp_connect_fwmark() {
+    let (_target, target_addr) = local_listener().await;
+    let config = ProxyConfig {
+        tcp_fwmark: Some(100),
+        ..ProxyConfig::default()
+    };
+    let stream = match tcp_connect(0, 0, target_addr.into(), None, &config).await {
+        Ok(stream) => stream,
+        // 设置SO_MARK需要CAP_NET_ADMIN
+        Err(e)
+            if e.downcast_ref::<io::Error>().map(|e| e.kind())
+                == Some(io::ErrorKind::PermissionDenied) =>
+        {
+            return;
+        }
+        Err(e) => panic!("{:?}", e),
+    };
+    assert_eq!(socket2::SockRef::from(&stream).mark().unwrap(), 100);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tcp_connect_egress_bind() {
+    // linux上整个127.0.0.0/8都在lo上，可以用来模拟多个出口地址
+    let (_target, target_addr) = local_listener().await;
+    let egress = Ipv4Addr::new(127, 0, 0, 2);
+    let config = ProxyConfig {
+        tcp_egress_bind: Some(egress),
+        ..ProxyConfig::default()
+    };
+    let stream = tcp_connect(0, 0, target_addr.into(), None, &config)
+        .await
+        .unwrap();
+    assert_eq!(stream.local_addr().unwrap().ip(), IpAddr::V4(egress));
+}
+
+#[tokio::test]
+async fn test_tcp_connect_timeout() {
+    // 不可达的地址，要么立即失败，要么在超时时间内失败
+    let timeout = Duration::from_millis(300);
+    let start = std::time::Instant::now();
+    let config = ProxyConfig {
+        tcp_connect_timeout: timeout,
+        ..ProxyConfig::default()
+    };
+    let rs = tcp_connect(0, 0, "192.0.2.1:80".parse().unwrap(), None, &config).await;
+    assert!(rs.is_err());
+    assert!(start.elapsed() < timeout + Duration::from_millis(500));
+}
+
+/// 模拟recv_handle写入映射，再通过代理连接到target
+#[cfg(test)]
+async fn connect_via_proxy(proxy: &TcpProxy, target: SocketAddrV4) -> TcpStream {
+    // 先绑定来源端口，映射写入后才能连接代理
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let client_addr = match socket.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    proxy
+        .nat_map
+        .lock()
+        .insert(client_addr, target, Instant::now());
+    let proxy_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), proxy.local_port());
+    socket.connect(proxy_addr).await.unwrap()
+}
+
+#[cfg(test)]
+async fn local_listener() -> (TcpListener, SocketAddrV4) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => (listener, addr),
+        SocketAddr::V6(_) => unreachable!(),
+    }
+}
+
+/// 等待代理关闭的连接数达到closed，连接结束后计数是异步更新的
+#[cfg(test)]
+async fn wait_closed(proxy: &TcpProxy, closed: u64) {
+    for _ in 0..100 {
+        if proxy.stats().closed >= closed {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// 回显服务
+#[cfg(test)]
+async fn echo_server() -> SocketAddrV4 {
+    let (target, target_addr) = local_listener().await;
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = target.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    target_addr
+}
+
+#[tokio::test]
+async fn test_stats() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let (target, target_addr) = local_listener().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let (mut server, _) = target.accept().await.unwrap();
+    client.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).await.unwrap();
+    server.write_all(b"hi").await.unwrap();
+    client.read_exact(&mut buf[..2]).await.unwrap();
+    let stats = proxy.stats();
+    assert_eq!(stats.accepted, 1);
+    assert_eq!(stats.active_connections, 1);
+    assert_eq!(stats.upload_bytes, 5);
+    assert_eq!(stats.download_bytes, 2);
+    drop(client);
+    drop(server);
+    wait_closed(&proxy, 1).await;
+    let stats = proxy.stats();
+    assert_eq!(stats.closed, 1);
+    assert_eq!(stats.active_connections, 0);
+}
+
+#[tokio::test]
+async fn test_no_cross_talk() {
+    // 快速打开关闭大量连接，每条连接只能收到自己的数据
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let target_addr = echo_server().await;
+    for i in 0..200u32 {
+        let mut client = connect_via_proxy(&proxy, target_addr).await;
+        client.write_all(&i.to_be_bytes()).await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(u32::from_be_bytes(buf), i);
+    }
+}
+
+#[tokio::test]
+async fn test_max_connections() {
+    let config = ProxyConfig {
+        tcp_max_connections: 2,
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let target_addr = echo_server().await;
+    let mut buf = [0u8; 1];
+    let mut first = connect_via_proxy(&proxy, target_addr).await;
+    let mut second = connect_via_proxy(&proxy, target_addr).await;
+    // 确保前两个连接都已经建立
+    for client in [&mut first, &mut second] {
+        client.write_all(b"a").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+    }
+    let mut third = connect_via_proxy(&proxy, target_addr).await;
+    let _ = third.write_all(b"c").await;
+    assert!(matches!(third.read(&mut buf).await, Ok(0) | Err(_)));
+    for client in [&mut first, &mut second] {
+        client.write_all(b"b").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"b");
+    }
+    assert_eq!(proxy.stats().active_connections, 2);
+}
+
+#[tokio::test]
+async fn test_accept_rate() {
+    let config = ProxyConfig {
+        tcp_accept_rate: 10,
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let target_addr = echo_server().await;
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for _ in 0..20 {
+        let mut client = connect_via_proxy(&proxy, target_addr).await;
+        tasks.push(tokio::spawn(async move {
+            let mut buf = [0u8; 1];
+            client.write_all(b"a").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            start.elapsed()
+        }));
+    }
+    let mut elapsed = Vec::new();
+    for task in tasks {
+        elapsed.push(task.await.unwrap());
+    }
+    elapsed.sort();
+    // 开始时有1秒的令牌，前10个立即接收，之后每100毫秒接收一个
+    assert!(elapsed[9] < Duration::from_millis(500), "{:?}", elapsed);
+    assert!(elapsed[19] >= Duration::from_millis(800), "{:?}", elapsed);
+    assert_eq!(proxy.stats().accepted, 20);
+}
+
+#[tokio::test]
+async fn test_max_connections_per_dest() {
+    let config = ProxyConfig {
+        tcp_max_connections_per_dest: 1,
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let target_addr = echo_server().await;
+    let mut buf = [0u8; 1];
+    let mut first = connect_via_proxy(&proxy, target_addr).await;
+    first.write_all(b"a").await.unwrap();
+    first.read_exact(&mut buf).await.unwrap();
+    let mut second = connect_via_proxy(&proxy, target_addr).await;
+    let _ = second.write_all(b"b").await;
+    assert!(matches!(second.read(&mut buf).await, Ok(0) | Err(_)));
+    assert_eq!(proxy.stats().rejected, 1);
+    // 第一个连接关闭后可以再次连接
+    drop(first);
+    wait_closed(&proxy, 1).await;
+    let mut third = connect_via_proxy(&proxy, target_addr).await;
+    third.write_all(b"c").await.unwrap();
+    third.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"c");
+}
+
+#[tokio::test]
+async fn test_idle_timeout() {
+    let config = ProxyConfig {
+        tcp_idle_timeout: Duration::from_millis(200),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let target_addr = echo_server().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let mut buf = [0u8; 1];
+    client.write_all(b"a").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    // 不再发送数据，代理应该在空闲超时后关闭连接
+    let start = Instant::now();
+    let rs = tokio::time::timeout(Duration::from_secs(2), client.read(&mut buf)).await;
+    assert!(matches!(rs, Ok(Ok(0)) | Ok(Err(_))));
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    wait_closed(&proxy, 1).await;
+    assert_eq!(proxy.stats().active_connections, 0);
+}
+
+#[tokio::test]
+async fn test_write_timeout() {
+    let config = ProxyConfig {
+        tcp_write_timeout: Duration::from_millis(300),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let (target, target_addr) = local_listener().await;
+    let client = connect_via_proxy(&proxy, target_addr).await;
+    let (_server, _) = target.accept().await.unwrap();
+    let (mut client_read, mut client_write) = client.into_split();
+    // 目标不读取，缓冲区写满后代理的写入停滞
+    let writer = tokio::spawn(async move {
+        let chunk = vec![1u8; 64 * 1024];
+        while client_write.write(&chunk).await.is_ok() {}
+    });
+    let mut buf = [0u8; 1];
+    let rs = tokio::time::timeout(Duration::from_secs(10), client_read.read(&mut buf)).await;
+    assert!(matches!(rs, Ok(Ok(0)) | Ok(Err(_))));
+    wait_closed(&proxy, 1).await;
+    assert_eq!(proxy.stats().active_connections, 0);
+    writer.abort();
+}
+
+#[tokio::test]
+async fn test_connect_refused() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    // 绑定后立即释放，得到一个没有监听的端口
+    let (listener, target_addr) = local_listener().await;
+    drop(listener);
+    let e = tcp_connect(0, 0, target_addr.into(), None, &ProxyConfig::default())
+        .await
+        .unwrap_err();
+    assert_eq!(ConnectFailure::classify(&e), ConnectFailure::Refused);
+
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let client_addr = match client.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let mut buf = [0u8; 1];
+    // 来源收到RST
+    let rs = client.read(&mut buf).await;
+    assert_eq!(rs.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    assert_eq!(proxy.stats().connect_refused, 1);
+    // 等待删除映射期间已经不占用连接数
+    assert_eq!(proxy.stats().active_connections, 0);
+    assert!(proxy.nat_map.lock().map.contains_key(&client_addr));
+    tokio::time::sleep(RST_FLUSH_DELAY + Duration::from_millis(100)).await;
+    assert!(!proxy.nat_map.lock().map.contains_key(&client_addr));
+}
+
+#[tokio::test]
+async fn test_slow_connect_not_block_accept() {
+    let config = ProxyConfig {
+        tcp_connect_timeout: Duration::from_secs(3),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    // 不可达的目标会一直连接到超时
+    let _slow = connect_via_proxy(&proxy, "192.0.2.1:80".parse().unwrap()).await;
+    let target_addr = echo_server().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let mut buf = [0u8; 1];
+    let rs = tokio::time::timeout(Duration::from_secs(1), async {
+        client.write_all(b"a").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+    })
+    .await;
+    assert!(rs.is_ok());
+}
+
+#[tokio::test]
+async fn test_half_close() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let (target, target_addr) = local_listener().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let (mut server, _) = target.accept().await.unwrap();
+    let mut buf = Vec::new();
+
+    // 来源关闭写端，目标读完数据后收到FIN，但仍然可以继续发送
+    client.write_all(b"request").await.unwrap();
+    client.shutdown().await.unwrap();
+    server.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"request");
+    for _ in 0..3 {
+        server.write_all(b"response").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    server.shutdown().await.unwrap();
+    buf.clear();
+    client.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"responseresponseresponse");
+    wait_closed(&proxy, 1).await;
+    assert_eq!(proxy.stats().active_connections, 0);
+
+    // 反方向：目标先关闭写端，来源仍然可以发送
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let (mut server, _) = target.accept().await.unwrap();
+    server.write_all(b"banner").await.unwrap();
+    server.shutdown().await.unwrap();
+    buf.clear();
+    client.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"banner");
+    client.write_all(b"after").await.unwrap();
+    client.shutdown().await.unwrap();
+    buf.clear();
+    server.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"after");
+    wait_closed(&proxy, 2).await;
+    assert_eq!(proxy.stats().active_connections, 0);
+}
+
+#[tokio::test]
+async fn test_one_way_transfer() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let (target, target_addr) = local_listener().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let (mut server, _) = target.accept().await.unwrap();
+
+    // 上行方向一直没有数据，不能因此断开下行的长时间传输
+    let chunk = vec![7u8; 64 * 1024];
+    let download = tokio::spawn(async move {
+        for _ in 0..16 {
+            server.write_all(&chunk).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        server
     });
-    if let Err(e) = tokio::io::copy(&mut server_read, &mut client_write).await {
-        log::warn!("server tcp proxy {}->{},{:?}", sender_addr, dest_addr, e);
+    let mut received = 0;
+    let mut buf = vec![0u8; 8192];
+    while received < 16 * 64 * 1024 {
+        let len = client.read(&mut buf).await.unwrap();
+        assert_ne!(len, 0);
+        received += len;
+        assert_eq!(proxy.stats().active_connections, 1);
     }
+    let mut server = download.await.unwrap();
+
+    // 下行结束后上行仍然可用
+    client.write_all(b"ack").await.unwrap();
+    let mut ack = [0u8; 3];
+    server.read_exact(&mut ack).await.unwrap();
+    assert_eq!(&ack, b"ack");
+    drop(client);
+    drop(server);
+    wait_closed(&proxy, 1).await;
+    assert_eq!(proxy.stats().active_connections, 0);
+}
+
+#[tokio::test]
+async fn test_backpressure() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let (target, target_addr) = local_listener().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let (mut server, _) = target.accept().await.unwrap();
+
+    // 目标不读取时写入最终会阻塞，代理不会无限缓存
+    const TOTAL: usize = 64 * 1024 * 1024;
+    let written = Arc::new(AtomicU64::new(0));
+    let writer = {
+        let written = written.clone();
+        tokio::spawn(async move {
+            let chunk = vec![1u8; 64 * 1024];
+            let mut total = 0;
+            while total < TOTAL {
+                let len = client
+                    .write(&chunk[..chunk.len().min(TOTAL - total)])
+                    .await
+                    .unwrap();
+                total += len;
+                written.store(total as u64, Ordering::Relaxed);
+            }
+            client.shutdown().await.unwrap();
+        })
+    };
+    let mut last = 0;
+    loop {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let now = written.load(Ordering::Relaxed);
+        if now == last {
+            break;
+        }
+        last = now;
+    }
+    assert!((last as usize) < TOTAL);
+
+    // 目标开始读取后转发恢复，数据完整到达
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut received = 0;
+    loop {
+        let len = server.read(&mut buf).await.unwrap();
+        if len == 0 {
+            break;
+        }
+        received += len;
+    }
+    assert_eq!(received, TOTAL);
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_close_after_large_write() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let (target, target_addr) = local_listener().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let (mut server, _) = target.accept().await.unwrap();
+
+    // 目标写完大量数据后立即关闭，来源稍后才开始读，代理里积压的数据也要完整送达
+    const TOTAL: usize = 8 * 1024 * 1024;
+    let upload = tokio::spawn(async move {
+        server.write_all(&vec![3u8; TOTAL]).await.unwrap();
+        drop(server);
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf.len(), TOTAL);
+    assert!(buf.iter().all(|v| *v == 3));
+    upload.await.unwrap();
+    drop(client);
+    wait_closed(&proxy, 1).await;
+}
+
+#[tokio::test]
+async fn test_rate_limit_per_conn() {
+    let rate = 512 * 1024;
+    let config = ProxyConfig {
+        tcp_rate_limit_per_conn: rate,
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let (listener, target_addr) = local_listener().await;
+    // 两个连接同时传输，各自按自己的速率限制，互不影响
+    let len = 2 * rate as usize;
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for _ in 0..2 {
+        let mut client = connect_via_proxy(&proxy, target_addr).await;
+        let (mut server, _) = listener.accept().await.unwrap();
+        tasks.push(tokio::spawn(async move {
+            let data = vec![1u8; len];
+            let mut buf = vec![0u8; len];
+            let (read, write) = tokio::join!(server.read_exact(&mut buf), client.write_all(&data));
+            read.unwrap();
+            write.unwrap();
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(2500), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn test_socks5_upstream() {
+    // 不需要认证的socks5服务端，把连接转到回显服务
+    let (socks, socks_addr) = local_listener().await;
+    tokio::spawn(async move {
+        let (mut stream, _) = socks.accept().await.unwrap();
+        let mut buf = [0u8; 10];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+        let port = u16::from_be_bytes([buf[8], buf[9]]);
+        let mut target = TcpStream::connect((ip, port)).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut stream, &mut target).await;
+    });
+    let config = ProxyConfig {
+        tcp_upstream: UpstreamProxy::Socks5 {
+            addr: socks_addr.into(),
+            auth: None,
+        },
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let target_addr = echo_server().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let mut buf = [0u8; 5];
+    client.write_all(b"hello").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[tokio::test]
+async fn test_drain() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let (target, target_addr) = local_listener().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let (mut server, _) = target.accept().await.unwrap();
+    let drain = {
+        let proxy = proxy.clone();
+        tokio::spawn(async move { proxy.drain(Duration::from_secs(5)).await })
+    };
+    // 排空期间已有连接继续转发
+    let data = vec![7u8; 256 * 1024];
+    let mut buf = vec![0u8; data.len()];
+    let (write, read) = tokio::join!(client.write_all(&data), server.read_exact(&mut buf));
+    write.unwrap();
+    read.unwrap();
+    assert_eq!(buf, data);
+    // 不再接收新连接
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let proxy_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), proxy.local_port());
+    assert!(TcpStream::connect(proxy_addr).await.is_err());
+    drop(client);
+    drop(server);
+    assert_eq!(drain.await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_drain_timeout() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let target_addr = echo_server().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let mut buf = [0u8; 1];
+    client.write_all(b"a").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    // 连接一直不关闭，超时后返回剩余的连接数
+    assert_eq!(proxy.drain(Duration::from_millis(200)).await, 1);
+}
+
+#[tokio::test]
+async fn test_set_nodelay() {
+    let (listener, addr) = local_listener().await;
+    let (src_stream, accept) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    let src_stream = src_stream.unwrap();
+    let (dest_stream, _) = accept.unwrap();
+    for nodelay in [true, false] {
+        set_nodelay(&src_stream, &dest_stream, nodelay);
+        assert_eq!(src_stream.nodelay().unwrap(), nodelay);
+        assert_eq!(dest_stream.nodelay().unwrap(), nodelay);
+    }
+}
+
+#[tokio::test]
+async fn test_set_keepalive() {
+    let (listener, addr) = local_listener().await;
+    let (src_stream, accept) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    let src_stream = src_stream.unwrap();
+    let (dest_stream, _) = accept.unwrap();
+    // 默认不开启
+    set_keepalive(&src_stream, &dest_stream, &ProxyConfig::default());
+    assert!(!socket2::SockRef::from(&src_stream).keepalive().unwrap());
+    let config = ProxyConfig {
+        tcp_keepalive_idle: Duration::from_secs(30),
+        tcp_keepalive_interval: Duration::from_secs(5),
+        tcp_keepalive_count: 3,
+        ..ProxyConfig::default()
+    };
+    set_keepalive(&src_stream, &dest_stream, &config);
+    for stream in [&src_stream, &dest_stream] {
+        let sock_ref = socket2::SockRef::from(stream);
+        assert!(sock_ref.keepalive().unwrap());
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            assert_eq!(sock_ref.keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(
+                sock_ref.keepalive_interval().unwrap(),
+                Duration::from_secs(5)
+            );
+            assert_eq!(sock_ref.keepalive_retries().unwrap(), 3);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_tos_passthrough() {
+    let guest: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let virtual_ip = Ipv4Addr::new(10, 26, 0, 3);
+    // DSCP=46(EF)，ECN=1
+    let mut buf = tcp_ipv4_packet(guest, "192.168.1.2:80".parse().unwrap());
+    buf[1] = 0xb9;
+    buf[33] = packet::tcp::SYN;
+    // 默认不记录
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let mut packet = buf.clone();
+    let mut ipv4 = IpV4Packet::new(&mut packet[..]).unwrap();
+    proxy
+        .recv_handle(&mut ipv4, *guest.ip(), virtual_ip)
+        .unwrap();
+    assert_eq!(proxy.nat_map.lock().tos(&guest), None);
+    let config = ProxyConfig {
+        tcp_tos_passthrough: true,
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let mut packet = buf.clone();
+    let mut ipv4 = IpV4Packet::new(&mut packet[..]).unwrap();
+    proxy
+        .recv_handle(&mut ipv4, *guest.ip(), virtual_ip)
+        .unwrap();
+    // 只保留DSCP
+    let tos = proxy.nat_map.lock().tos(&guest);
+    assert_eq!(tos, Some(0xb8));
+    // 连接目标的socket带上TOS
+    let (listener, addr) = local_listener().await;
+    let (stream, accept) = tokio::join!(
+        tcp_connect(0, 0, addr.into(), tos, &config),
+        listener.accept()
+    );
+    accept.unwrap();
+    let stream = stream.unwrap();
+    assert_eq!(socket2::SockRef::from(&stream).tos().unwrap(), 0xb8);
+    // 映射删除时一起删除
+    proxy
+        .nat_map
+        .lock()
+        .remove(&guest, "192.168.1.2:80".parse().unwrap());
+    assert_eq!(proxy.nat_map.lock().tos(&guest), None);
+}
+
+/// 构造一个ipv4 tcp包（只有头部）
+#[cfg(test)]
+pub(super) fn tcp_ipv4_packet(source: SocketAddrV4, destination: SocketAddrV4) -> Vec<u8> {
+    let mut tcp_header = [0u8; 20];
+    tcp_header[12] = 5 << 4;
+    let mut buf = super::ipv4_packet(Protocol::Tcp, *source.ip(), *destination.ip(), &tcp_header);
+    let mut ipv4 = IpV4Packet::unchecked(&mut buf[..]);
+    let mut tcp_packet = TcpPacket::unchecked(*source.ip(), *destination.ip(), ipv4.payload_mut());
+    tcp_packet.set_source_port(source.port());
+    tcp_packet.set_destination_port(destination.port());
+    tcp_packet.update_checksum();
+    buf
+}
+
+#[tokio::test]
+async fn test_bind_addr() {
+    let config = ProxyConfig {
+        tcp_bind_addr: Ipv4Addr::LOCALHOST.into(),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    // 进入代理的包目标改成绑定的地址
+    let guest: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let mut buf = tcp_ipv4_packet(guest, "192.168.1.2:80".parse().unwrap());
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    let virtual_ip = Ipv4Addr::new(10, 26, 0, 3);
+    assert_eq!(
+        proxy
+            .recv_handle(&mut ipv4, *guest.ip(), virtual_ip)
+            .unwrap(),
+        ProxyAction::PassThrough
+    );
+    assert_eq!(ipv4.destination_ip(), Ipv4Addr::LOCALHOST);
+
+    let target_addr = echo_server().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let mut buf = [0u8; 1];
+    client.write_all(b"a").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    // 其他地址连不上代理
+    #[cfg(target_os = "linux")]
+    assert!(
+        TcpStream::connect((Ipv4Addr::new(127, 0, 0, 2), proxy.local_port()))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_port_filter() {
+    let config = ProxyConfig {
+        tcp_port_filter: PortFilter::Allow(vec![80..=80, 443..=443]),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let guest: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let virtual_ip = Ipv4Addr::new(10, 26, 0, 3);
+    // 不在允许列表的端口原样返回
+    let packet = tcp_ipv4_packet(guest, "192.168.1.2:22".parse().unwrap());
+    let mut buf = packet.clone();
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        proxy
+            .recv_handle(&mut ipv4, *guest.ip(), virtual_ip)
+            .unwrap(),
+        ProxyAction::PassThrough
+    );
+    assert_eq!(buf, packet);
+    assert!(proxy.nat_map.lock().map.is_empty());
+    // 允许的端口走代理
+    let mut buf = tcp_ipv4_packet(guest, "192.168.1.2:443".parse().unwrap());
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        proxy
+            .recv_handle(&mut ipv4, *guest.ip(), virtual_ip)
+            .unwrap(),
+        ProxyAction::PassThrough
+    );
+    assert_eq!(ipv4.destination_ip(), virtual_ip);
+    assert!(proxy.nat_map.lock().map.contains_key(&guest));
+
+    // 运行时改成禁止443，已经映射的连接不受影响，新连接不再代理
+    proxy.set_port_filter(PortFilter::Deny(vec![443..=443]));
+    let mut buf = tcp_ipv4_packet(guest, "192.168.1.2:443".parse().unwrap());
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        proxy
+            .recv_handle(&mut ipv4, *guest.ip(), virtual_ip)
+            .unwrap(),
+        ProxyAction::PassThrough
+    );
+    assert_eq!(ipv4.destination_ip(), virtual_ip);
+    let new_guest: SocketAddrV4 = "10.26.0.2:40001".parse().unwrap();
+    let packet = tcp_ipv4_packet(new_guest, "192.168.1.2:443".parse().unwrap());
+    let mut buf = packet.clone();
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        proxy
+            .recv_handle(&mut ipv4, *new_guest.ip(), virtual_ip)
+            .unwrap(),
+        ProxyAction::PassThrough
+    );
+    assert_eq!(buf, packet);
+    assert!(!proxy.nat_map.lock().map.contains_key(&new_guest));
+}
+
+/// 检查校验和，返回包的(来源地址,目标地址)
+#[cfg(test)]
+fn tcp_packet_addrs(buf: &mut [u8]) -> (SocketAddrV4, SocketAddrV4) {
+    let mut ipv4 = IpV4Packet::new(buf).unwrap();
+    assert!(ipv4.is_valid());
+    let (source_ip, dest_ip) = (ipv4.source_ip(), ipv4.destination_ip());
+    let tcp_packet = TcpPacket::new(source_ip, dest_ip, ipv4.payload_mut()).unwrap();
+    assert!(tcp_packet.is_valid());
+    (
+        SocketAddrV4::new(source_ip, tcp_packet.source_port()),
+        SocketAddrV4::new(dest_ip, tcp_packet.destination_port()),
+    )
+}
+
+#[tokio::test]
+async fn test_mss_clamp() {
+    let config = ProxyConfig {
+        tcp_mss: 1360,
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let local_ip = Ipv4Addr::new(10, 26, 0, 1);
+    let client: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let dest: SocketAddrV4 = "192.168.1.2:80".parse().unwrap();
+    // 带MSS=1460选项的SYN包
+    let syn = |mss: u16| {
+        let mut buf = tcp_ipv4_packet(client, dest);
+        buf[2..4].copy_from_slice(&44u16.to_be_bytes());
+        buf[32] = 0x60;
+        buf[33] = packet::tcp::SYN;
+        buf.extend_from_slice(&[2, 4]);
+        buf.extend_from_slice(&mss.to_be_bytes());
+        buf
+    };
+    let recv = |mut buf: Vec<u8>| {
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        ipv4.update_checksum();
+        assert_eq!(
+            proxy
+                .recv_handle(&mut ipv4, *client.ip(), local_ip)
+                .unwrap(),
+            ProxyAction::PassThrough
+        );
+        tcp_packet_addrs(&mut buf);
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        let (source_ip, dest_ip) = (ipv4.source_ip(), ipv4.destination_ip());
+        let tcp_packet = TcpPacket::new(source_ip, dest_ip, ipv4.payload_mut()).unwrap();
+        tcp_packet.mss()
+    };
+    assert_eq!(recv(syn(1460)), Some(1360));
+    // 已经比较小的不修改
+    assert_eq!(recv(syn(1200)), Some(1200));
+    // 不是SYN包不修改
+    let mut buf = syn(1460);
+    buf[33] = packet::tcp::ACK;
+    assert_eq!(recv(buf), Some(1460));
+}
+
+#[tokio::test]
+async fn test_ip_options() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let local_ip = Ipv4Addr::new(10, 26, 0, 1);
+    let client: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let dest: SocketAddrV4 = "192.168.1.2:80".parse().unwrap();
+    // 在ip头后面插入Router Alert选项，tcp头从24字节开始
+    let mut buf = tcp_ipv4_packet(client, dest);
+    buf.splice(20..20, [148, 4, 0, 0]);
+    buf[0] = 0x46;
+    buf[2..4].copy_from_slice(&44u16.to_be_bytes());
+    IpV4Packet::new(&mut buf[..]).unwrap().update_checksum();
+    let origin = buf.clone();
+
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        proxy
+            .recv_handle(&mut ipv4, *client.ip(), local_ip)
+            .unwrap(),
+        ProxyAction::PassThrough
+    );
+    // 选项保持不变，端口改写在tcp头上，校验和都正确
+    assert_eq!(buf[20..24], [148, 4, 0, 0]);
+    let (source, to) = tcp_packet_addrs(&mut buf);
+    assert_eq!(source, client);
+    assert_eq!(to, SocketAddrV4::new(local_ip, proxy.local_port()));
+
+    // 代理的回复还原成原来的地址
+    let mut reply = buf.clone();
+    reply[12..16].copy_from_slice(&buf[16..20]);
+    reply[16..20].copy_from_slice(&buf[12..16]);
+    reply[24..26].copy_from_slice(&buf[26..28]);
+    reply[26..28].copy_from_slice(&buf[24..26]);
+    let mut ipv4 = IpV4Packet::new(&mut reply[..]).unwrap();
+    ipv4.update_checksum();
+    let (source_ip, dest_ip) = (ipv4.source_ip(), ipv4.destination_ip());
+    TcpPacket::new(source_ip, dest_ip, ipv4.payload_mut())
+        .unwrap()
+        .update_checksum();
+    proxy.send_handle(&mut ipv4).unwrap();
+    assert_eq!(tcp_packet_addrs(&mut reply), (dest, client));
+    assert_eq!(reply[20..24], origin[20..24]);
+
+    // 头部长度小于5的包不能解析
+    let mut buf = origin;
+    buf[0] = 0x44;
+    assert!(IpV4Packet::new(&mut buf[..]).is_err());
+}
+
+#[test]
+fn test_nat_max() {
+    let mut nat = TcpNat {
+        max: 2,
+        ..TcpNat::default()
+    };
+    let now = Instant::now();
+    let client1: SocketAddrV4 = "10.26.0.2:40001".parse().unwrap();
+    let client2: SocketAddrV4 = "10.26.0.2:40002".parse().unwrap();
+    let client3: SocketAddrV4 = "10.26.0.2:40003".parse().unwrap();
+    let dest: SocketAddrV4 = "192.168.1.2:80".parse().unwrap();
+    nat.insert(client1, dest, now);
+    nat.insert(client2, dest, now + Duration::from_secs(1));
+    // client1后来又有数据，最久没有使用的变成client2
+    nat.insert(client1, dest, now + Duration::from_secs(2));
+    nat.insert(client3, dest, now + Duration::from_secs(3));
+    assert_eq!(nat.map.len(), 2);
+    assert!(nat.get(&client1).is_some());
+    assert!(nat.get(&client2).is_none());
+    assert!(nat.get(&client3).is_some());
+}
+
+#[tokio::test]
+async fn test_nat_evict_one_shot() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let local_ip = Ipv4Addr::new(10, 26, 0, 1);
+    let source_ip = Ipv4Addr::new(10, 26, 0, 2);
+    // 大量只有一个包的流，其中一半和前面的流来源端口相同、目标不同，需要换端口
+    for i in 0..2000u16 {
+        let source = SocketAddrV4::new(source_ip, 20000 + i % 1000);
+        let dest = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, (i / 1000) as u8 + 1), 80);
+        let mut buf = tcp_ipv4_packet(source, dest);
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        proxy.recv_handle(&mut ipv4, source_ip, local_ip).unwrap();
+    }
+    let start = Instant::now();
+    {
+        let nat = proxy.nat_map.lock();
+        assert_eq!(nat.map.len(), 2000);
+        assert_eq!(nat.remapped.len(), 1000);
+    }
+    // 还有数据的流不会被清理
+    let active = SocketAddrV4::new(source_ip, 20000);
+    let active_dest = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 80);
+    let later = start + DEFAULT_NAT_TTL;
+    proxy.nat_map.lock().find(active, active_dest, later);
+    proxy
+        .nat_map
+        .lock()
+        .evict(DEFAULT_NAT_TTL, later + Duration::from_secs(1));
+    let nat = proxy.nat_map.lock();
+    assert_eq!(nat.map.len(), 1);
+    assert!(nat.remapped.is_empty());
+    assert_eq!(nat.get(&active), Some((active_dest, active.port())));
+}
+
+#[tokio::test]
+async fn test_source_port_collision() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let local_ip = Ipv4Addr::new(10, 26, 0, 1);
+    let proxy_addr = SocketAddrV4::new(local_ip, proxy.local_port());
+    let client: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let other_client: SocketAddrV4 = "10.26.0.3:40000".parse().unwrap();
+    let dest1: SocketAddrV4 = "192.168.1.2:80".parse().unwrap();
+    let dest2: SocketAddrV4 = "192.168.1.3:80".parse().unwrap();
+    // 返回经过recv_handle后进入代理的来源地址
+    let recv = |source: SocketAddrV4, dest: SocketAddrV4| {
+        let mut buf = tcp_ipv4_packet(source, dest);
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        assert_eq!(
+            proxy
+                .recv_handle(&mut ipv4, *source.ip(), local_ip)
+                .unwrap(),
+            ProxyAction::PassThrough
+        );
+        let (mapped, to) = tcp_packet_addrs(&mut buf);
+        assert_eq!(to, proxy_addr);
+        mapped
+    };
+    // 返回代理的回复经过send_handle后的(来源地址,目标地址)
+    let send = |mapped: SocketAddrV4| {
+        let mut buf = tcp_ipv4_packet(proxy_addr, mapped);
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        proxy.send_handle(&mut ipv4).unwrap();
+        tcp_packet_addrs(&mut buf)
+    };
+    // 没有冲突时保持原来的端口
+    let mapped1 = recv(client, dest1);
+    assert_eq!(mapped1, client);
+    // 同一个来源地址连接另一个目标，换一个端口
+    let mapped2 = recv(client, dest2);
+    assert_eq!(mapped2.ip(), client.ip());
+    assert_ne!(mapped2.port(), client.port());
+    // 不同来源用相同的端口连接同一个目标，互不影响
+    assert_eq!(recv(other_client, dest1), other_client);
+    // 后续的包继续使用各自的映射
+    assert_eq!(recv(client, dest1), mapped1);
+    assert_eq!(recv(client, dest2), mapped2);
+    // 回复还原成各自的目标地址和原来的端口
+    assert_eq!(send(mapped1), (dest1, client));
+    assert_eq!(send(mapped2), (dest2, client));
+    assert_eq!(send(other_client), (dest1, other_client));
+    let mut expect = vec![(client, dest1), (client, dest2), (other_client, dest1)];
+    expect.sort();
+    assert_eq!(proxy.mappings(), expect);
+    // 映射过期后换端口的记录一起删除
+    proxy.nat_map.lock().evict(Duration::ZERO, Instant::now());
+    assert!(proxy.nat_map.lock().map.is_empty());
+    assert!(proxy.nat_map.lock().remapped.is_empty());
+}
+
+#[tokio::test]
+async fn test_not_tcp() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let local_ip = Ipv4Addr::new(10, 26, 0, 1);
+    let source: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let dest: SocketAddrV4 = "192.168.1.2:53".parse().unwrap();
+    // 把协议改成udp，ip头部之后的数据不是tcp头部
+    let mut buf = tcp_ipv4_packet(source, dest);
+    buf[9] = 17;
+    buf.truncate(28);
+    buf[2..4].copy_from_slice(&28u16.to_be_bytes());
+    let origin = buf.clone();
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        proxy
+            .recv_handle(&mut ipv4, *source.ip(), local_ip)
+            .unwrap(),
+        ProxyAction::PassThrough
+    );
+    proxy.send_handle(&mut ipv4).unwrap();
+    assert_eq!(buf, origin);
+    assert!(proxy.mappings().is_empty());
+}
+
+#[tokio::test]
+async fn test_nat_miss() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let local_ip = Ipv4Addr::new(10, 26, 0, 1);
+    let source: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let proxy_addr = SocketAddrV4::new(local_ip, proxy.local_port());
+    // 代理发出的包没有映射时原样发送并计数
+    let mut buf = tcp_ipv4_packet(proxy_addr, source);
+    let origin = buf.clone();
+    proxy
+        .send_handle(&mut IpV4Packet::new(&mut buf[..]).unwrap())
+        .unwrap();
+    assert_eq!(buf, origin);
+    assert_eq!(proxy.stats().nat_miss, 1);
+    // 其他端口的包不是代理发出的，不计数
+    let mut buf = tcp_ipv4_packet(SocketAddrV4::new(local_ip, 22), source);
+    proxy
+        .send_handle(&mut IpV4Packet::new(&mut buf[..]).unwrap())
+        .unwrap();
+    assert_eq!(proxy.stats().nat_miss, 1);
+}
+
+#[tokio::test]
+async fn test_mappings() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    assert!(proxy.mappings().is_empty());
+    let virtual_ip = Ipv4Addr::new(10, 26, 0, 3);
+    let guest1: SocketAddrV4 = "10.26.0.2:40001".parse().unwrap();
+    let guest2: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let dest1: SocketAddrV4 = "192.168.1.2:80".parse().unwrap();
+    let dest2: SocketAddrV4 = "192.168.1.3:443".parse().unwrap();
+    for (guest, dest) in [(guest1, dest1), (guest2, dest2)] {
+        let mut buf = tcp_ipv4_packet(guest, dest);
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        proxy
+            .recv_handle(&mut ipv4, *guest.ip(), virtual_ip)
+            .unwrap();
+    }
+    let mappings = proxy.mappings();
+    assert_eq!(mappings, vec![(guest2, dest2), (guest1, dest1)]);
+    // 返回的是快照，之后的修改不影响，也不会持有锁
+    proxy.nat_map.lock().map.clear();
+    assert_eq!(mappings.len(), 2);
+    assert!(proxy.mappings().is_empty());
+}
+
+#[tokio::test]
+async fn test_rate_limit() {
+    let rate = 512 * 1024;
+    let config = ProxyConfig {
+        tcp_rate_limit: rate,
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let (listener, target_addr) = local_listener().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let (mut server, _) = listener.accept().await.unwrap();
+    // 第1秒的令牌是预先积累的，之后按速率转发
+    let len = 2 * rate as usize;
+    let start = Instant::now();
+    let writer = tokio::spawn(async move {
+        client.write_all(&vec![1u8; len]).await.unwrap();
+        client
+    });
+    let mut buf = vec![0u8; len];
+    server.read_exact(&mut buf).await.unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    let mut client = writer.await.unwrap();
+
+    // 运行时取消限速，已有的连接也生效
+    proxy.set_rate_limit(0);
+    let data = vec![2u8; len];
+    let start = Instant::now();
+    let (read, write) = tokio::join!(server.read_exact(&mut buf), client.write_all(&data));
+    read.unwrap();
+    write.unwrap();
+    assert!(buf.iter().all(|v| *v == 2));
+    assert!(start.elapsed() < Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn test_map_capacity() {
+    assert_eq!(map_capacity(0), MIN_MAP_CAPACITY);
+    assert_eq!(map_capacity(1000), 1000);
+    assert_eq!(map_capacity(100_000), MAX_MAP_CAPACITY);
+    let config = ProxyConfig {
+        tcp_max_connections: 1000,
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    assert!(proxy.nat_map.lock().map.capacity() >= 1000);
+    assert!(proxy.stats.connections.lock().capacity() >= 1000);
+}
+
+#[tokio::test]
+async fn test_connections() {
+    let proxy = TcpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let (target, target_addr) = local_listener().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let (mut server, _) = target.accept().await.unwrap();
+    let data = vec![1u8; 100_000];
+    client.write_all(&data).await.unwrap();
+    let mut buf = vec![0u8; data.len()];
+    server.read_exact(&mut buf).await.unwrap();
+    server.write_all(&data[..3000]).await.unwrap();
+    client.read_exact(&mut buf[..3000]).await.unwrap();
+    let connections = proxy.connections();
+    assert_eq!(connections.len(), 1);
+    let conn = connections[0];
+    assert_eq!(conn.id, 1);
+    assert_eq!(conn.src, client.local_addr().unwrap());
+    assert_eq!(conn.dst, target_addr);
+    assert_eq!((conn.upload_bytes, conn.download_bytes), (100_000, 3000));
+    // 关闭后不再出现在列表里
+    drop(client);
+    drop(server);
+    wait_closed(&proxy, 1).await;
+    assert!(proxy.connections().is_empty());
+}
+
+#[test]
+fn test_conn_guard() {
+    let config = ProxyConfig {
+        tcp_max_connections_per_dest: 1,
+        ..ProxyConfig::default()
+    };
+    let stats = Arc::new(ProxyStats::default());
+    let dest_counts: DestCounts = Arc::new(Mutex::new(HashMap::new()));
+    let src: SocketAddr = "10.26.0.2:40000".parse().unwrap();
+    let dest1: SocketAddrV4 = "192.168.1.2:80".parse().unwrap();
+    let dest2: SocketAddrV4 = "192.168.1.3:80".parse().unwrap();
+    // 连接id递增，被拒绝的连接不占用id
+    let first = ConnGuard::acquire(&config, &stats, &dest_counts, &None, src, dest1).unwrap();
+    assert!(ConnGuard::acquire(&config, &stats, &dest_counts, &None, src, dest1).is_none());
+    let second = ConnGuard::acquire(&config, &stats, &dest_counts, &None, src, dest2).unwrap();
+    assert_eq!((first.id, second.id), (1, 2));
+    drop(first);
+    assert!(!dest_counts.lock().contains_key(dest1.ip()));
+    let third = ConnGuard::acquire(&config, &stats, &dest_counts, &None, src, dest1).unwrap();
+    assert_eq!(third.id, 3);
+    assert_eq!(stats.snapshot().active_connections, 2);
+}
+
+#[test]
+fn test_conn_guard_concurrent() {
+    let config = ProxyConfig {
+        tcp_max_connections: 4,
+        tcp_max_connections_per_dest: 3,
+        ..ProxyConfig::default()
+    };
+    let stats = Arc::new(ProxyStats::default());
+    let dest_counts: DestCounts = Arc::new(Mutex::new(HashMap::new()));
+    let src: SocketAddr = "10.26.0.2:40000".parse().unwrap();
+    let barrier = std::sync::Barrier::new(16);
+    // 所有线程同时acquire，成功的数量不能超过上限
+    let guards: Vec<ConnGuard> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..16u8)
+            .map(|i| {
+                let (config, stats, dest_counts, barrier) =
+                    (&config, &stats, &dest_counts, &barrier);
+                scope.spawn(move || {
+                    let dest = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, i % 2), 80);
+                    barrier.wait();
+                    ConnGuard::acquire(config, stats, dest_counts, &None, src, dest)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    assert_eq!(guards.len(), 4);
+    assert_eq!(stats.snapshot().active_connections, 4);
+    for ip in [Ipv4Addr::new(192, 168, 1, 0), Ipv4Addr::new(192, 168, 1, 1)] {
+        let count = guards
+            .iter()
+            .filter(|guard| *guard.dest_addr.ip() == ip)
+            .count();
+        assert!(count <= 3);
+        assert_eq!(dest_counts.lock().get(&ip).copied().unwrap_or(0), count);
+    }
+    drop(guards);
+    assert_eq!(stats.snapshot().active_connections, 0);
+    assert!(dest_counts.lock().is_empty());
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct TestObserver {
+    opened: Mutex<Vec<SocketAddrV4>>,
+    closed: Mutex<Vec<(SocketAddrV4, u64, u64)>>,
+}
+
+#[cfg(test)]
+impl ProxyObserver for TestObserver {
+    fn on_open(&self, _src: SocketAddr, dest: SocketAddrV4) {
+        self.opened.lock().push(dest);
+    }
+    fn on_close(
+        &self,
+        _src: SocketAddr,
+        dest: SocketAddrV4,
+        bytes_up: u64,
+        bytes_down: u64,
+        _duration: Duration,
+    ) {
+        self.closed.lock().push((dest, bytes_up, bytes_down));
+    }
+}
+
+#[tokio::test]
+async fn test_observer() {
+    let observer = Arc::new(TestObserver::default());
+    let config = ProxyConfig {
+        tcp_observer: Some(observer.clone()),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    // 正常结束的连接
+    let target_addr = echo_server().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let mut buf = [0u8; 5];
+    client.write_all(b"hello").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    drop(client);
+    wait_closed(&proxy, 1).await;
+    // 连接目标失败的连接也只回调一次
+    let (listener, refused_addr) = local_listener().await;
+    drop(listener);
+    let mut client = connect_via_proxy(&proxy, refused_addr).await;
+    let _ = client.read(&mut buf).await;
+    wait_closed(&proxy, 2).await;
+    assert_eq!(*observer.opened.lock(), vec![target_addr, refused_addr]);
+    assert_eq!(
+        *observer.closed.lock(),
+        vec![(target_addr, 5, 5), (refused_addr, 0, 0)]
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_listen_reuse_addr() {
+    let (listener, addr) = local_listener().await;
+    // 监听端先关闭连接，端口上留下TIME_WAIT的连接
+    let (client, accept) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    let mut client = client.unwrap();
+    drop(accept.unwrap().0);
+    assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+    drop(client);
+    drop(listener);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let config = ProxyConfig {
+        tcp_reuse_addr: false,
+        ..ProxyConfig::default()
+    };
+    let e = listen(addr.into(), &config).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+    let config = ProxyConfig {
+        tcp_reuse_addr: true,
+        tcp_listen_backlog: 16,
+        ..ProxyConfig::default()
+    };
+    let listener = listen(addr.into(), &config).unwrap();
+    let (client, accept) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    client.unwrap();
+    accept.unwrap();
+}
+
+#[tokio::test]
+async fn test_access_log() {
+    let path = std::env::temp_dir().join(format!("vnt-proxy-access-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ProxyConfig {
+        tcp_access_log: Some(path.clone()),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let target_addr = echo_server().await;
+    let mut client = connect_via_proxy(&proxy, target_addr).await;
+    let mut buf = [0u8; 5];
+    client.write_all(b"hello").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    drop(client);
+    wait_closed(&proxy, 1).await;
+    let (listener, refused_addr) = local_listener().await;
+    drop(listener);
+    let mut client = connect_via_proxy(&proxy, refused_addr).await;
+    let _ = client.read(&mut buf).await;
+    wait_closed(&proxy, 2).await;
+    // 日志由单独的线程写入
+    let mut lines = Vec::new();
+    for _ in 0..100 {
+        lines = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+        if lines.len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(&format!("\"dst\":\"{}\",\"up\":5,\"down\":5,", target_addr)));
+    assert!(lines[0].ends_with("\"reason\":\"eof\"}"));
+    assert!(lines[1].contains(&format!("\"dst\":\"{}\"", refused_addr)));
+    assert!(lines[1].ends_with("\"reason\":\"connect_failed\"}"));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_policy() {
+    let rules = ["deny 0.0.0.0/0 25", "deny 192.168.2.0/24"];
+    let config = ProxyConfig {
+        tcp_policy: ProxyPolicy::new(rules.iter().map(|v| v.parse().unwrap()).collect()),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let guest: SocketAddrV4 = "10.26.0.2:40000".parse().unwrap();
+    let virtual_ip = Ipv4Addr::new(10, 26, 0, 3);
+    // 被拒绝的目标原样写入tun
+    for dest in ["192.168.1.2:25", "192.168.2.2:80"] {
+        let packet = tcp_ipv4_packet(guest, dest.parse().unwrap());
+        let mut buf = packet.clone();
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        assert_eq!(
+            proxy
+                .recv_handle(&mut ipv4, *guest.ip(), virtual_ip)
+                .unwrap(),
+            ProxyAction::PassThrough
+        );
+        assert_eq!(buf, packet);
+    }
+    assert!(proxy.mappings().is_empty());
+    let dest: SocketAddrV4 = "192.168.1.2:80".parse().unwrap();
+    let mut buf = tcp_ipv4_packet(guest, dest);
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    proxy
+        .recv_handle(&mut ipv4, *guest.ip(), virtual_ip)
+        .unwrap();
+    assert_eq!(proxy.mappings(), vec![(guest, dest)]);
+}
+
+#[tokio::test]
+async fn test_socks5_frontend() {
+    let auth = ("user".to_string(), "pass".to_string());
+    let config = ProxyConfig {
+        tcp_policy: ProxyPolicy::new(vec!["deny 0.0.0.0/0 25".parse().unwrap()]),
+        socks5: Some(Socks5Listen {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            auth: Some(auth.clone()),
+        }),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let socks5_addr = proxy.socks5_addr().unwrap();
+    let target_addr = echo_server().await;
+
+    let mut client = TcpStream::connect(socks5_addr).await.unwrap();
+    socks5::handshake(&mut client, target_addr.into(), Some(&auth))
+        .await
+        .unwrap();
+    let mut buf = [0u8; 5];
+    client.write_all(b"hello").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    assert_eq!(proxy.stats().accepted, 1);
+
+    // 认证失败
+    let mut client = TcpStream::connect(socks5_addr).await.unwrap();
+    let wrong = ("user".to_string(), "wrong".to_string());
+    let e = socks5::handshake(&mut client, target_addr.into(), Some(&wrong))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    // 过滤规则同样生效
+    let mut client = TcpStream::connect(socks5_addr).await.unwrap();
+    let denied = SocketAddrV4::new(*target_addr.ip(), 25);
+    let e = socks5::handshake(&mut client, denied.into(), Some(&auth))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    // 目标拒绝连接
+    let (listener, refused) = local_listener().await;
+    drop(listener);
+    let mut client = TcpStream::connect(socks5_addr).await.unwrap();
+    let e = socks5::handshake(&mut client, refused.into(), Some(&auth))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[tokio::test]
+async fn test_socks5_limit() {
+    // 没有认证时不能监听非回环地址
+    let config = ProxyConfig {
+        socks5: Some(Socks5Listen {
+            addr: "0.0.0.0:0".parse().unwrap(),
+            auth: None,
+        }),
+        ..ProxyConfig::default()
+    };
+    assert!(TcpProxy::new(&config).await.is_err());
+    let config = ProxyConfig {
+        tcp_max_connections: 1,
+        tcp_connect_timeout: Duration::from_secs(1),
+        socks5: Some(Socks5Listen {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            auth: None,
+        }),
+        ..ProxyConfig::default()
+    };
+    let proxy = TcpProxy::new(&config).await.unwrap();
+    let socks5_addr = proxy.socks5_addr().unwrap();
+    // 还没有握手的连接也占用连接数
+    let idle = TcpStream::connect(socks5_addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(proxy.stats().active_connections, 1);
+    let mut client = TcpStream::connect(socks5_addr).await.unwrap();
+    let target_addr = echo_server().await;
+    assert!(socks5::handshake(&mut client, target_addr.into(), None)
+        .await
+        .is_err());
+    assert_eq!(proxy.stats().rejected, 1);
+    // 握手超时后释放
+    let mut buf = [0u8; 1];
+    let mut idle = idle;
+    assert_eq!(idle.read(&mut buf).await.unwrap(), 0);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(proxy.stats().active_connections, 0);
+    let mut client = TcpStream::connect(socks5_addr).await.unwrap();
+    socks5::handshake(&mut client, target_addr.into(), None)
+        .await
+        .unwrap();
+    assert_eq!(proxy.stats().accepted, 1);
 }
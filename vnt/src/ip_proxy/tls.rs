@@ -0,0 +1,161 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::ip_proxy::port_filter::{parse_ports, ports_match, Cidr};
+
+/// 目标在网段内并且端口匹配时，代理连接目标后先发起TLS握手，
+/// 来源发来的明文经过TLS加密后再发给目标，用于访问只接受TLS的服务
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsRule {
+    pub cidr: Cidr,
+    /// 为空则匹配所有端口
+    pub ports: Vec<RangeInclusive<u16>>,
+    /// 握手时使用的SNI，并用来校验证书，为None则使用目标ip
+    pub server_name: Option<String>,
+}
+
+impl TlsRule {
+    fn matches(&self, dest: SocketAddrV4) -> bool {
+        self.cidr.contains(*dest.ip()) && ports_match(&self.ports, dest.port())
+    }
+}
+
+impl FromStr for TlsRule {
+    type Err = String;
+    /// 格式：192.168.1.0/24、192.168.1.10 443、192.168.1.10 443,8443 api.internal，
+    /// 省略端口时匹配所有端口，指定域名时必须指定端口
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let cidr = parts
+            .next()
+            .ok_or_else(|| format!("not match '{}', exp: 192.168.1.10 443 api.internal", s))?
+            .parse()?;
+        let ports = match parts.next() {
+            Some(ports) => parse_ports(ports)?,
+            None => Vec::new(),
+        };
+        let server_name = match parts.next() {
+            Some(name) => {
+                ServerName::try_from(name)
+                    .map_err(|e| format!("'{}' server name error:{}", s, e))?;
+                Some(name.to_string())
+            }
+            None => None,
+        };
+        if parts.next().is_some() {
+            return Err(format!("'{}' too many fields", s));
+        }
+        Ok(TlsRule {
+            cidr,
+            ports,
+            server_name,
+        })
+    }
+}
+
+/// tcp代理发起TLS的规则和共用的客户端配置，按配置顺序第一条匹配的规则生效
+#[derive(Clone)]
+pub struct TlsOrigin {
+    rules: Vec<TlsRule>,
+    ca_file: Option<PathBuf>,
+    connector: TlsConnector,
+}
+
+impl TlsOrigin {
+    /// ca_file是PEM格式的根证书，用来校验目标的证书，为None则使用内置的公共根证书
+    pub fn new(rules: Vec<TlsRule>, ca_file: Option<&Path>) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match ca_file {
+            Some(path) => {
+                let certs = CertificateDer::pem_file_iter(path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| anyhow!("read ca file {:?} failed:{}", path, e))?;
+                let (added, _) = roots.add_parsable_certificates(certs);
+                if added == 0 {
+                    return Err(anyhow!("ca file {:?} has no certificate", path));
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            rules,
+            ca_file: ca_file.map(Path::to_path_buf),
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+    pub fn rules(&self) -> &[TlsRule] {
+        &self.rules
+    }
+    /// 目标匹配规则时返回握手使用的域名，没有匹配时不发起TLS
+    pub(crate) fn find(&self, dest: SocketAddrV4) -> Option<ServerName<'static>> {
+        let rule = self.rules.iter().find(|rule| rule.matches(dest))?;
+        match &rule.server_name {
+            // 解析规则时已经校验过
+            Some(name) => ServerName::try_from(name.clone()).ok(),
+            None => Some(ServerName::from(IpAddr::V4(*dest.ip()))),
+        }
+    }
+    pub(crate) async fn connect(
+        &self,
+        server_name: ServerName<'static>,
+        stream: TcpStream,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        self.connector.connect(server_name, stream).await
+    }
+}
+
+/// 客户端配置由规则和ca_file决定，比较这两项就能判断配置是否变化
+impl PartialEq for TlsOrigin {
+    fn eq(&self, other: &Self) -> bool {
+        self.rules == other.rules && self.ca_file == other.ca_file
+    }
+}
+
+impl fmt::Debug for TlsOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsOrigin")
+            .field("rules", &self.rules)
+            .field("ca_file", &self.ca_file)
+            .finish()
+    }
+}
+
+#[test]
+fn test_tls_rule() {
+    let rules: Vec<TlsRule> = ["10.26.0.3 443 api.internal", "192.168.1.0/24"]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+    let origin = TlsOrigin::new(rules, None).unwrap();
+    let find = |dest: &str| origin.find(dest.parse().unwrap());
+    assert_eq!(
+        find("10.26.0.3:443"),
+        Some(ServerName::try_from("api.internal").unwrap())
+    );
+    assert_eq!(find("10.26.0.3:80"), None);
+    assert_eq!(
+        find("192.168.1.9:8443"),
+        Some(ServerName::from(IpAddr::V4([192, 168, 1, 9].into())))
+    );
+    assert_eq!(find("192.168.2.9:443"), None);
+    assert!(TlsRule::from_str("10.0.0.0/33").is_err());
+    assert!(TlsRule::from_str("10.0.0.1 443 bad_name!").is_err());
+    assert!(TlsRule::from_str("10.0.0.1 443 a.com 1").is_err());
+    assert!(TlsOrigin::new(Vec::new(), Some(Path::new("/nonexistent/ca.pem"))).is_err());
+}
@@ -11,30 +11,35 @@ use tokio::net::UdpSocket;
 use packet::ip::ipv4::packet::IpV4Packet;
 use packet::udp::udp::UdpPacket;
 
-use crate::ip_proxy::ProxyHandler;
+use crate::ip_proxy::{spawn_evict, NatMap, ProxyAction, ProxyConfig, ProxyHandler};
+
+/// 默认的udp空闲超时时间
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
 
 #[derive(Clone)]
 pub struct UdpProxy {
     port: u16,
-    nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
+    nat_map: NatMap,
 }
 
 impl UdpProxy {
-    pub async fn new() -> anyhow::Result<Self> {
-        let nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>> =
-            Arc::new(Mutex::new(HashMap::with_capacity(16)));
+    /// udp没有连接关闭的概念，映射和转发socket都靠udp_idle_timeout回收
+    pub async fn new(config: &ProxyConfig) -> anyhow::Result<Self> {
+        let idle_timeout = config.udp_idle_timeout;
+        let nat_map: NatMap = Arc::new(Mutex::new(HashMap::with_capacity(16)));
         let udp = UdpSocket::bind(format!("0.0.0.0:{}", 0))
             .await
             .context("UdpProxy bind failed")?;
         let port = udp.local_addr()?.port();
         {
             let nat_map = nat_map.clone();
-            tokio::spawn(async {
-                if let Err(e) = udp_proxy(udp, nat_map).await {
+            tokio::spawn(async move {
+                if let Err(e) = udp_proxy(udp, nat_map, idle_timeout).await {
                     log::warn!("udp_proxy:{:?}", e);
                 }
             });
         }
+        spawn_evict(nat_map.clone(), idle_timeout);
         Ok(Self { port, nat_map })
     }
 }
@@ -45,10 +50,12 @@ impl ProxyHandler for UdpProxy {
         ipv4: &mut IpV4Packet<&mut [u8]>,
         source: Ipv4Addr,
         destination: Ipv4Addr,
-    ) -> io::Result<bool> {
+    ) -> io::Result<ProxyAction> {
         let dest_ip = ipv4.destination_ip();
-        //转发到代理目标地址
-        let mut udp_packet = UdpPacket::new(source, destination, ipv4.payload_mut())?;
+        //转发到代理目标地址，udp头不完整的包直接丢弃
+        let Ok(mut udp_packet) = UdpPacket::new(source, destination, ipv4.payload_mut()) else {
+            return Ok(ProxyAction::Drop);
+        };
         let source_port = udp_packet.source_port();
         let dest_port = udp_packet.destination_port();
         udp_packet.set_destination_port(self.port);
@@ -58,18 +65,24 @@ impl ProxyHandler for UdpProxy {
         let key = SocketAddrV4::new(source, source_port);
         self.nat_map
             .lock()
-            .insert(key.into(), SocketAddrV4::new(dest_ip, dest_port).into());
-        Ok(false)
+            .insert(key, (SocketAddrV4::new(dest_ip, dest_port), Instant::now()));
+        Ok(ProxyAction::PassThrough)
     }
 
     fn send_handle(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
         let src_ip = ipv4.source_ip();
         let dest_ip = ipv4.destination_ip();
         let dest_addr = {
-            let udp_packet = UdpPacket::new(src_ip, dest_ip, ipv4.payload_mut())?;
+            let Ok(udp_packet) = UdpPacket::new(src_ip, dest_ip, ipv4.payload_mut()) else {
+                return Ok(());
+            };
             SocketAddrV4::new(dest_ip, udp_packet.destination_port())
         };
-        if let Some(source_addr) = self.nat_map.lock().get(&dest_addr) {
+        let source_addr = self.nat_map.lock().get_mut(&dest_addr).map(|(addr, time)| {
+            *time = Instant::now();
+            *addr
+        });
+        if let Some(source_addr) = source_addr {
             let source_ip = *source_addr.ip();
             let mut udp_packet = UdpPacket::new(source_ip, dest_ip, ipv4.payload_mut())?;
             udp_packet.set_source_port(source_addr.port());
@@ -81,10 +94,7 @@ impl ProxyHandler for UdpProxy {
     }
 }
 
-async fn udp_proxy(
-    udp: UdpSocket,
-    nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
-) -> io::Result<()> {
+async fn udp_proxy(udp: UdpSocket, nat_map: NatMap, idle_timeout: Duration) -> io::Result<()> {
     let mut buf = [0u8; 65536];
 
     let inner_map: Arc<Mutex<HashMap<SocketAddrV4, (Arc<UdpSocket>, Arc<AtomicCell<Instant>>)>>> =
@@ -94,9 +104,15 @@ async fn udp_proxy(
         match udp_socket.recv_from(&mut buf).await {
             Ok((len, sender_addr)) => match sender_addr {
                 SocketAddr::V4(sender_addr) => {
-                    if let Err(e) =
-                        udp_proxy0(&buf[..len], sender_addr, &inner_map, &nat_map, &udp_socket)
-                            .await
+                    if let Err(e) = udp_proxy0(
+                        &buf[..len],
+                        sender_addr,
+                        &inner_map,
+                        &nat_map,
+                        &udp_socket,
+                        idle_timeout,
+                    )
+                    .await
                     {
                         log::warn!("udp proxy {} {:?}", sender_addr, e);
                     }
@@ -114,15 +130,16 @@ async fn udp_proxy0(
     buf: &[u8],
     sender_addr: SocketAddrV4,
     inner_map: &Arc<Mutex<HashMap<SocketAddrV4, (Arc<UdpSocket>, Arc<AtomicCell<Instant>>)>>>,
-    map: &Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
+    map: &NatMap,
     udp_socket: &Arc<UdpSocket>,
+    idle_timeout: Duration,
 ) -> io::Result<()> {
     let option = inner_map.lock().get(&sender_addr).cloned();
     if let Some((udp, time)) = option {
         time.store(Instant::now());
         udp.send(buf).await?;
     } else {
-        let option = map.lock().get(&sender_addr).cloned();
+        let option = map.lock().get(&sender_addr).map(|(addr, _)| *addr);
         if let Some(dest_addr) = option {
             //先使用相同的端口，冲突了再随机端口
             let peer_udp_socket =
@@ -143,12 +160,7 @@ async fn udp_proxy0(
             tokio::spawn(async move {
                 let mut buf = [0u8; 65536];
                 loop {
-                    match tokio::time::timeout(
-                        Duration::from_secs(600),
-                        peer_udp_socket.recv(&mut buf),
-                    )
-                    .await
-                    {
+                    match tokio::time::timeout(idle_timeout, peer_udp_socket.recv(&mut buf)).await {
                         Ok(rs) => match rs {
                             Ok(len) => match udp_socket.send_to(&buf[..len], sender_addr).await {
                                 Ok(_) => {}
@@ -164,7 +176,7 @@ async fn udp_proxy0(
                             }
                         },
                         Err(_) => {
-                            if time.load().elapsed() > Duration::from_secs(580) {
+                            if time.load().elapsed() >= idle_timeout {
                                 //超时关闭
                                 log::warn!("udp proxy timeout {}->{}", sender_addr, dest_addr);
                                 break;
@@ -179,3 +191,83 @@ async fn udp_proxy0(
     }
     Ok(())
 }
+
+/// 构造一个ipv4 udp包
+#[cfg(test)]
+pub(crate) fn udp_ipv4_packet(
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let mut udp = vec![0u8; udp_len];
+    udp[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    udp[8..].copy_from_slice(payload);
+    let mut buf = super::ipv4_packet(
+        packet::ip::ipv4::protocol::Protocol::Udp,
+        *source.ip(),
+        *destination.ip(),
+        &udp,
+    );
+    let mut ipv4 = IpV4Packet::unchecked(&mut buf[..]);
+    let mut udp_packet = UdpPacket::unchecked(*source.ip(), *destination.ip(), ipv4.payload_mut());
+    udp_packet.set_source_port(source.port());
+    udp_packet.set_destination_port(destination.port());
+    udp_packet.update_checksum();
+    buf
+}
+
+#[tokio::test]
+async fn test_udp_proxy_echo() {
+    let proxy = UdpProxy::new(&ProxyConfig::default()).await.unwrap();
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = match echo.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        while let Ok((len, addr)) = echo.recv_from(&mut buf).await {
+            let _ = echo.send_to(&buf[..len], addr).await;
+        }
+    });
+    // 客户端的地址作为虚拟网络里的来源，recv_handle建立的映射和真实发出的数据对得上
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let guest = match client.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let local_ip = Ipv4Addr::LOCALHOST;
+    // 来自虚拟网络的包，目标改写成代理端口
+    let mut buf = udp_ipv4_packet(guest, echo_addr, b"ping");
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    assert_eq!(
+        proxy.recv_handle(&mut ipv4, *guest.ip(), local_ip).unwrap(),
+        ProxyAction::PassThrough
+    );
+    assert_eq!(ipv4.destination_ip(), local_ip);
+    let udp_packet = UdpPacket::new(*guest.ip(), local_ip, ipv4.payload_mut()).unwrap();
+    assert_eq!(udp_packet.destination_port(), proxy.port);
+
+    // 真实的数据经过代理转发到目标并收到回显
+    client
+        .send_to(b"ping", (local_ip, proxy.port))
+        .await
+        .unwrap();
+    let mut recv_buf = [0u8; 16];
+    let (len, from) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut recv_buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&recv_buf[..len], b"ping");
+    assert_eq!(from.port(), proxy.port);
+
+    // 回复的包源地址还原成原始目标
+    let mut buf = udp_ipv4_packet(SocketAddrV4::new(local_ip, proxy.port), guest, b"ping");
+    let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+    proxy.send_handle(&mut ipv4).unwrap();
+    assert_eq!(ipv4.source_ip(), *echo_addr.ip());
+    let udp_packet = UdpPacket::new(*echo_addr.ip(), *guest.ip(), ipv4.payload_mut()).unwrap();
+    assert_eq!(udp_packet.source_port(), echo_addr.port());
+    assert_eq!(udp_packet.destination_port(), guest.port());
+}
@@ -11,6 +11,7 @@ use parking_lot::Mutex;
 use rand::Rng;
 
 use crate::channel::punch::{NatInfo, NatType};
+use crate::channel::RouteKey;
 use crate::proto::message::PunchNatType;
 
 mod stun;
@@ -189,6 +190,14 @@ impl NatTest {
         let mut guard = self.info.lock();
         guard.update_addr(index, ip, port)
     }
+    /// 服务端看到的通道公网地址，tcp通道的index和udp通道无关，端口也不是udp的公网端口，只更新ip
+    pub fn update_route_addr(&self, route_key: &RouteKey, ip: Ipv4Addr, port: u16) {
+        if route_key.is_tcp() {
+            self.update_addr(route_key.index(), ip, 0)
+        } else {
+            self.update_addr(route_key.index(), ip, port)
+        }
+    }
     pub fn re_test(
         &self,
         local_ipv4: Option<Ipv4Addr>,
@@ -267,3 +276,17 @@ impl NatTest {
         return Ok(false);
     }
 }
+
+#[test]
+fn test_update_route_addr() {
+    let nat_test = NatTest::new(3, vec![], None, None, vec![1001, 1002, 1003], 1004);
+    let ip = Ipv4Addr::new(1, 2, 3, 4);
+    let addr: SocketAddr = "1.1.1.1:29872".parse().unwrap();
+    // tcp连接的index从2开始，不能覆盖第三个udp通道的公网端口
+    nat_test.update_route_addr(&RouteKey::new(true, 2, addr), ip, 40000);
+    let info = nat_test.nat_info();
+    assert_eq!(info.public_ports, vec![0, 0, 0]);
+    assert_eq!(info.public_ips, vec![ip]);
+    nat_test.update_route_addr(&RouteKey::new(false, 2, addr), ip, 50000);
+    assert_eq!(nat_test.nat_info().public_ports, vec![0, 0, 50000]);
+}
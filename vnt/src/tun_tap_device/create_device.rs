@@ -37,14 +37,7 @@ pub fn create_device(config: &crate::core::Config) -> io::Result<Arc<Device>> {
             .unwrap_or(default_name.to_string()),
         config.tap,
     )?);
-    let mtu = config.mtu.unwrap_or_else(|| {
-        if config.password.is_none() {
-            1450
-        } else {
-            1410
-        }
-    });
-    device.set_mtu(mtu)?;
+    device.set_mtu(config.device_mtu())?;
     Ok(device)
 }
 
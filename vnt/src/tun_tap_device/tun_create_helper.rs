@@ -80,6 +80,7 @@ struct TunDeviceHelperInner {
     up_counter: SingleU64Adder,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     compressor: Compressor,
+    compress_threshold: usize,
 }
 
 impl TunDeviceHelper {
@@ -95,6 +96,7 @@ impl TunDeviceHelper {
         up_counter: SingleU64Adder,
         device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
         compressor: Compressor,
+        compress_threshold: usize,
     ) -> Self {
         Self {
             inner: Arc::new(AtomicCell::new(Some(TunDeviceHelperInner {
@@ -110,6 +112,7 @@ impl TunDeviceHelper {
                 up_counter,
                 device_list,
                 compressor,
+                compress_threshold,
             }))),
         }
     }
@@ -129,6 +132,7 @@ impl TunDeviceHelper {
                 inner.up_counter,
                 inner.device_list,
                 inner.compressor,
+                inner.compress_threshold,
             )?;
             Ok(())
         } else {
@@ -0,0 +1,35 @@
+use std::io;
+
+/// windows上socket数量达到上限时的错误码
+#[cfg(windows)]
+const WSAEMFILE: i32 = 10024;
+
+/// accept是否因为文件描述符(socket)用完而失败。
+/// 这种错误不会因为重试而消失，监听socket一直可读，立即重试会空转占满cpu，
+/// 需要等其他连接关闭释放描述符后再accept
+pub fn is_fd_exhausted(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        #[cfg(unix)]
+        Some(code) => code == libc::EMFILE || code == libc::ENFILE,
+        #[cfg(windows)]
+        Some(code) => code == WSAEMFILE,
+        #[cfg(not(any(unix, windows)))]
+        Some(_) => false,
+        None => false,
+    }
+}
+
+#[test]
+fn test_is_fd_exhausted() {
+    #[cfg(unix)]
+    {
+        assert!(is_fd_exhausted(&io::Error::from_raw_os_error(libc::EMFILE)));
+        assert!(is_fd_exhausted(&io::Error::from_raw_os_error(libc::ENFILE)));
+        assert!(!is_fd_exhausted(&io::Error::from_raw_os_error(
+            libc::ECONNABORTED
+        )));
+    }
+    #[cfg(windows)]
+    assert!(is_fd_exhausted(&io::Error::from_raw_os_error(WSAEMFILE)));
+    assert!(!is_fd_exhausted(&io::ErrorKind::WouldBlock.into()));
+}
@@ -8,3 +8,6 @@ pub use counter::*;
 
 mod dns_query;
 pub use dns_query::*;
+
+mod fd_limit;
+pub use fd_limit::is_fd_exhausted;
@@ -2,7 +2,7 @@ use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::thread::Thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 use parking_lot::Mutex;
@@ -21,6 +21,10 @@ impl StopManager {
             inner: Arc::new(StopManagerInner::new(f)),
         }
     }
+    /// 注册停止时的回调，返回的Worker drop之前stop_and_wait等都认为它还在运行。
+    /// name不能为空且不能和还没触发的监听器重名，否则返回错误；
+    /// 已经停止时返回错误且不会调用f，调用方应该在创建线程、绑定端口之前注册或检查is_stop。
+    /// stop时按注册顺序调用所有回调，每个回调只调用一次
     pub fn add_listener<F>(&self, name: String, f: F) -> anyhow::Result<Worker>
     where
         F: FnOnce() + Send + 'static,
@@ -36,6 +40,13 @@ impl StopManager {
     pub fn wait_timeout(&self, dur: Duration) -> bool {
         self.inner.wait_timeout(dur)
     }
+    /// 停止所有监听器并等待所有worker退出，最多等待timeout，
+    /// 返回超时后还没有退出的worker名称，为空表示全部正常停止
+    pub fn stop_and_wait(&self, timeout: Duration) -> Vec<String> {
+        self.stop();
+        self.inner.wait_deadline(Instant::now() + timeout);
+        self.inner.workers.lock().clone()
+    }
     pub fn is_stop(&self) -> bool {
         self.inner.state.load(Ordering::Acquire)
     }
@@ -45,6 +56,8 @@ struct StopManagerInner {
     listeners: Mutex<(bool, Vec<(String, Box<dyn FnOnce() + Send>)>)>,
     park_threads: Mutex<Vec<Thread>>,
     worker_num: AtomicUsize,
+    // 还没有退出的worker名称
+    workers: Mutex<Vec<String>>,
     state: AtomicBool,
     stop_call: Mutex<Option<Box<dyn FnOnce() + Send>>>,
 }
@@ -58,6 +71,7 @@ impl StopManagerInner {
             listeners: Mutex::new((false, Vec::with_capacity(32))),
             park_threads: Mutex::new(Vec::with_capacity(4)),
             worker_num: AtomicUsize::new(0),
+            workers: Mutex::new(Vec::new()),
             state: AtomicBool::new(false),
             stop_call: Mutex::new(Some(Box::new(f))),
         }
@@ -71,7 +85,7 @@ impl StopManagerInner {
         }
         let mut guard = self.listeners.lock();
         if guard.0 {
-            return Err(anyhow!("stopped"));
+            return Err(anyhow!("stop add_listener {:?} already stopped", name));
         }
         for (n, _) in &guard.1 {
             if &name == n {
@@ -117,6 +131,19 @@ impl StopManagerInner {
         thread::park_timeout(dur);
         self.worker_num.load(Ordering::Acquire) == 0
     }
+    fn wait_deadline(&self, deadline: Instant) {
+        self.park_threads.lock().push(thread::current());
+        loop {
+            if self.worker_num.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
     fn stop_call(&self) {
         if let Some(call) = self.stop_call.lock().take() {
             call();
@@ -132,10 +159,17 @@ pub struct Worker {
 impl Worker {
     fn new(name: String, inner: Arc<StopManagerInner>) -> Self {
         let _ = inner.worker_num.fetch_add(1, Ordering::AcqRel);
+        inner.workers.lock().push(name.clone());
         Self { name, inner }
     }
     fn release0(&self) {
         let inner = &self.inner;
+        {
+            let mut workers = inner.workers.lock();
+            if let Some(index) = workers.iter().position(|n| n == &self.name) {
+                workers.remove(index);
+            }
+        }
         let count = inner.worker_num.fetch_sub(1, Ordering::AcqRel);
         if count == 1 {
             for x in inner.park_threads.lock().drain(..) {
@@ -155,3 +189,74 @@ impl Drop for Worker {
         log::info!("stop {}", self.name);
     }
 }
+
+#[test]
+fn test_stop_and_wait() {
+    let stop_manager = StopManager::new(|| {});
+    let (sender, receiver) = std::sync::mpsc::channel::<()>();
+    let fast = stop_manager
+        .add_listener("fast".to_string(), move || drop(sender))
+        .unwrap();
+    let slow_release = Arc::new(AtomicBool::new(false));
+    let slow = stop_manager
+        .add_listener("slow".to_string(), || {})
+        .unwrap();
+    thread::spawn(move || {
+        let _ = receiver.recv();
+        drop(fast);
+    });
+    {
+        let slow_release = slow_release.clone();
+        thread::spawn(move || {
+            while !slow_release.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            drop(slow);
+        });
+    }
+    // 监听器触发后fast退出，slow一直不退出
+    let not_stopped = stop_manager.stop_and_wait(Duration::from_millis(200));
+    assert_eq!(not_stopped, vec!["slow".to_string()]);
+    assert!(stop_manager.is_stop());
+
+    slow_release.store(true, Ordering::Release);
+    assert!(stop_manager
+        .stop_and_wait(Duration::from_secs(5))
+        .is_empty());
+}
+
+#[test]
+fn test_add_listener() {
+    let stop_manager = StopManager::new(|| {});
+    let called = Arc::new(AtomicUsize::new(0));
+    let listener = |called: &Arc<AtomicUsize>| {
+        let called = called.clone();
+        move || {
+            called.fetch_add(1, Ordering::AcqRel);
+        }
+    };
+    let worker = stop_manager
+        .add_listener("tcp_proxy".to_string(), listener(&called))
+        .unwrap();
+    // 重名和空名称都注册失败，已经注册的不受影响
+    let err = stop_manager
+        .add_listener("tcp_proxy".to_string(), listener(&called))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("already exists"));
+    assert!(stop_manager
+        .add_listener(String::new(), listener(&called))
+        .is_err());
+
+    stop_manager.stop();
+    assert_eq!(called.load(Ordering::Acquire), 1);
+    // 停止后注册失败，回调不会被调用
+    let err = stop_manager
+        .add_listener("udp_proxy".to_string(), listener(&called))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("already stopped"));
+    drop(worker);
+    assert!(stop_manager.wait_timeout(Duration::from_secs(1)));
+    assert_eq!(called.load(Ordering::Acquire), 1);
+}